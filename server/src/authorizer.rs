@@ -0,0 +1,437 @@
+use std::collections::HashSet;
+use crate::channel;
+use crate::filter::{evaluate, Caller};
+use crate::policy::{AllowStmt, DenyStmt, MsgType, Span};
+use crate::resolver::{broadcast_stmts, Policy};
+use crate::validator::{BroadcastMessage, Message, MessageError, Params, UnauthorizedError};
+
+/// The rule's name has to match the channel name, and each of its bindings the instance value at
+/// its position; values it doesn't bind can be anything.
+fn matches_channel(msg_name: &str, bindings: &[(String, Span)], caller: &Caller, channel: &str) -> bool {
+    let (name, values) = channel::split_instance(channel);
+    channel::matches(msg_name, name)
+        && bindings.len() <= values.len()
+        && bindings
+            .iter()
+            .zip(values)
+            .all(|((claim, _), value)| caller.claim(claim).map(channel::encode).as_deref() == Some(value))
+}
+
+/// Rules without a `when` filter, and messages without a payload, match on type and channel alone.
+fn allows(stmt: &AllowStmt, caller: &Caller, msg_type: MsgType, channel: &str, payload: Option<&Params>) -> bool {
+    stmt.msg_type == msg_type
+        && matches_channel(&stmt.msg_name, &stmt.bindings, caller, channel)
+        && match (&stmt.filter, payload) {
+            (Some(filter), Some(payload)) => evaluate(filter, payload, caller),
+            _ => true,
+        }
+}
+
+/// A deny rule with a `when` filter only applies to messages that have a payload to test.
+fn denies(stmt: &DenyStmt, caller: &Caller, msg_type: MsgType, channel: &str, payload: Option<&Params>) -> bool {
+    stmt.msg_type == msg_type
+        && matches_channel(&stmt.msg_name, &stmt.bindings, caller, channel)
+        && match (&stmt.filter, payload) {
+            (Some(filter), Some(payload)) => evaluate(filter, payload, caller),
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+}
+
+/// Walks the chain from the role itself outwards. The first role with a matching rule decides, and
+/// its deny rules beat its allow rules, so a role can carve exceptions out of what it inherits.
+fn role_allows(policy: &Policy, caller: &Caller, msg_type: MsgType, channel: &str, payload: Option<&Params>) -> bool {
+    let role = match policy.roles.get(caller.role()) {
+        Some(role) => role,
+        None => return false,
+    };
+    for role_name in &role.chain {
+        if role.denials.iter().any(|deny_stmt| {
+            deny_stmt.role_name == *role_name && denies(deny_stmt, caller, msg_type, channel, payload)
+        }) {
+            return false;
+        }
+        if role.permissions.iter().any(|allow_stmt| {
+            allow_stmt.role_name == *role_name && allows(allow_stmt, caller, msg_type, channel, payload)
+        }) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Checks the message against the rules of the role and of every role it extends. Listen frames carry
+/// no payload, so their `when` filters are left to `authorize_delivery`, and a wildcard subscription
+/// is accepted when the role may listen to at least one of the channels it covers.
+pub fn authorize_message(policy: &Policy, caller: &Caller, message: &Message) -> Option<MessageError> {
+    let allowed = match message {
+        Message::Listen(listen_message) => {
+            let (name, values) = channel::split_instance(&listen_message.channel);
+            broadcast_stmts(&policy.statements)
+                .filter(|broadcast_stmt| channel::matches(name, &broadcast_stmt.msg_name))
+                .map(|broadcast_stmt| {
+                    let values = values.iter().map(|value| format!("/{}", value)).collect::<String>();
+                    format!("{}{}", broadcast_stmt.msg_name, values)
+                })
+                .any(|channel| role_allows(policy, caller, MsgType::Listen, &channel, None))
+        }
+        _ => role_allows(policy, caller, message.msg_type(), message.channel(), message.payload()),
+    };
+    if allowed {
+        return None;
+    }
+
+    Some(MessageError::Unauthorized(UnauthorizedError {
+        role_name: caller.role().to_string(),
+        msg_type: message.msg_type(),
+        channel: message.channel().to_string(),
+    }))
+}
+
+/// The payload as the caller may see it: `None` when no rule lets it through, otherwise only the
+/// fields listed by the matching rules of the role that decides, as in `role_allows`, or all of
+/// them when one of those rules lists none.
+pub fn project(policy: &Policy, caller: &Caller, msg_type: MsgType, channel: &str, payload: &Params) -> Option<Params> {
+    if !role_allows(policy, caller, msg_type, channel, Some(payload)) {
+        return None;
+    }
+    let role = policy.roles.get(caller.role())?;
+    let matching: Vec<&AllowStmt> = role
+        .chain
+        .iter()
+        .map(|role_name| {
+            role.permissions
+                .iter()
+                .filter(|allow_stmt| allow_stmt.role_name == *role_name && allows(allow_stmt, caller, msg_type, channel, Some(payload)))
+                .collect::<Vec<&AllowStmt>>()
+        })
+        .find(|matching| !matching.is_empty())?;
+    let mut visible: HashSet<&str> = HashSet::new();
+    for allow_stmt in matching {
+        match &allow_stmt.fields {
+            Some(fields) => visible.extend(fields.iter().map(|(field, _)| field.as_str())),
+            None => return Some(payload.clone()),
+        }
+    }
+    Some(
+        payload
+            .iter()
+            .filter(|(field, _)| visible.contains(field.as_str()))
+            .map(|(field, value)| (field.clone(), value.clone()))
+            .collect(),
+    )
+}
+
+/// A broadcast as a listener receives it, after the `when` filters and field lists of its listen
+/// rules; `None` if the listener should not receive it at all.
+pub fn authorize_delivery(policy: &Policy, caller: &Caller, message: &BroadcastMessage) -> Option<BroadcastMessage> {
+    let payload = project(policy, caller, MsgType::Listen, &message.channel, &message.payload)?;
+    Some(BroadcastMessage {
+        payload,
+        channel: message.channel.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::authorize_delivery;
+    use crate::filter::Caller;
+    use crate::policy::MsgType;
+    use crate::resolver::load;
+    use crate::validator::{message_from_str, BroadcastMessage, Message};
+
+    fn authorize_io(policy: &str, role_name: &str, message: &str, expected_error: bool) {
+        let policy = load(policy).unwrap();
+        let message = serde_json::from_str::<Message>(message).unwrap();
+        let caller = Caller::new(role_name, &Default::default());
+        let result = crate::authorizer::authorize_message(&policy, &caller, &message);
+        assert_eq!(!expected_error, result.is_none());
+    }
+
+    const POLICY: &str = "
+        role frontend
+        role backend
+
+        request new-user {
+            name: string
+        }
+
+        allow frontend request new-user
+    ";
+
+    #[test]
+    fn test_authorize_allowed_role() {
+        authorize_io(
+            POLICY,
+            "frontend",
+            r#"{"type": "request", "payload": {"name": "a"}, "channel": "new-user"}"#,
+            false,
+        );
+    }
+
+    #[test]
+    fn test_authorize_other_role() {
+        authorize_io(
+            POLICY,
+            "backend",
+            r#"{"type": "request", "payload": {"name": "a"}, "channel": "new-user"}"#,
+            true,
+        );
+    }
+
+    #[test]
+    fn test_authorize_other_msg_type() {
+        authorize_io(
+            POLICY,
+            "frontend",
+            r#"{"type": "response", "payload": {}, "channel": "new-user"}"#,
+            true,
+        );
+    }
+
+    #[test]
+    fn test_authorize_other_channel() {
+        authorize_io(
+            POLICY,
+            "frontend",
+            r#"{"type": "request", "payload": {}, "channel": "other"}"#,
+            true,
+        );
+    }
+
+    const ROLES_POLICY: &str = "
+        role backend
+        role admin extends backend
+        role root extends admin
+
+        request new-user {
+            name: string
+        }
+
+        allow backend request new-user
+    ";
+
+    #[test]
+    fn test_authorize_inherited_role() {
+        let message = r#"{"type": "request", "payload": {"name": "a"}, "channel": "new-user"}"#;
+        authorize_io(ROLES_POLICY, "root", message, false);
+        authorize_io(ROLES_POLICY, "admin", message, false);
+        authorize_io(ROLES_POLICY, "frontend", message, true);
+    }
+
+    #[test]
+    fn test_authorize_filter() {
+        let policy = load("
+            request transfer {
+                tenant_id: string
+            }
+            allow backend request transfer when payload.tenant_id == caller.tenant_id
+        ").unwrap();
+        let claims = serde_json::json!({ "tenant_id": "acme" });
+        let caller = Caller::new("backend", claims.as_object().unwrap());
+        let message = |tenant_id: &str| {
+            let message = format!(r#"{{"type": "request", "payload": {{"tenant_id": "{}"}}, "channel": "transfer"}}"#, tenant_id);
+            serde_json::from_str::<Message>(&message).unwrap()
+        };
+        assert!(crate::authorizer::authorize_message(&policy, &caller, &message("acme")).is_none());
+        assert_eq!(crate::authorizer::authorize_message(&policy, &caller, &message("other")).unwrap().code(), "unauthorized");
+    }
+
+    #[test]
+    fn test_authorize_delivery() {
+        let policy = load("
+            broadcast order-created {
+                tenant_id: string
+            }
+            allow service listen order-created when payload.tenant_id == caller.tenant_id
+            allow auditor listen order-created
+        ").unwrap();
+        let event = |tenant_id: &str| BroadcastMessage {
+            payload: serde_json::json!({ "tenant_id": tenant_id }).as_object().unwrap().clone(),
+            channel: "order-created".to_string(),
+        };
+        let claims = serde_json::json!({ "tenant_id": "acme" });
+        let service = Caller::new("service", claims.as_object().unwrap());
+        let auditor = Caller::new("auditor", &Default::default());
+        let listen = serde_json::from_str::<Message>(r#"{"type": "listen", "channel": "order-created"}"#).unwrap();
+
+        assert!(crate::authorizer::authorize_message(&policy, &service, &listen).is_none());
+        assert!(authorize_delivery(&policy, &service, &event("acme")).is_some());
+        assert!(authorize_delivery(&policy, &service, &event("other")).is_none());
+        assert!(authorize_delivery(&policy, &auditor, &event("other")).is_some());
+        assert!(authorize_delivery(&policy, &Caller::new("frontend", &Default::default()), &event("acme")).is_none());
+    }
+
+    #[test]
+    fn test_project_fields() {
+        let policy = load("
+            role reader
+            role analytics extends reader
+
+            broadcast user-created {
+                id: uuid
+                email: email
+                created: datetime
+            }
+            allow reader listen user-created { id }
+            allow analytics listen user-created { created }
+            allow admin listen user-created
+            role auditor extends admin
+            allow auditor listen user-created { id }
+        ").unwrap();
+        let event = BroadcastMessage {
+            payload: serde_json::json!({ "id": "1", "email": "a@b.c", "created": "now" }).as_object().unwrap().clone(),
+            channel: "user-created".to_string(),
+        };
+        let fields = |role_name: &str| {
+            let delivered = authorize_delivery(&policy, &Caller::new(role_name, &Default::default()), &event).unwrap();
+            delivered.payload.keys().cloned().collect::<Vec<String>>()
+        };
+        assert_eq!(fields("reader"), vec!["id"]);
+        assert_eq!(fields("analytics"), vec!["created"]);
+        assert_eq!(fields("auditor"), vec!["id"]);
+        assert_eq!(fields("admin"), vec!["id", "email", "created"]);
+    }
+
+    #[test]
+    fn test_project_response() {
+        let policy = load("
+            request new-user {
+                name: string
+            }
+            response new-user {
+                id: uuid
+                email: email
+            }
+            allow frontend request new-user
+            allow frontend response new-user { id }
+            allow backend response new-user
+            allow intern request new-user
+        ").unwrap();
+        let payload = serde_json::json!({ "id": "1", "email": "a@b.c" }).as_object().unwrap().clone();
+        let received = |role_name: &str| {
+            let caller = Caller::new(role_name, &Default::default());
+            crate::authorizer::project(&policy, &caller, MsgType::Response, "new-user", &payload)
+                .map(|payload| payload.keys().cloned().collect::<Vec<String>>())
+        };
+        assert_eq!(received("frontend"), Some(vec!["id".to_string()]));
+        assert_eq!(received("intern"), None);
+    }
+
+    #[test]
+    fn test_authorize_deny() {
+        let policy = "
+            role reader
+            role writer extends reader
+            role admin extends writer
+
+            request delete-user {
+                id: string
+                protected: bool
+            }
+
+            allow reader request delete-user
+            deny writer request delete-user when payload.protected == true
+            deny admin request delete-user when payload.id == \"root\"
+            allow admin request delete-user when payload.protected == true
+            deny intern request delete-user
+        ";
+        let message = |id: &str, protected: bool| {
+            format!(r#"{{"type": "request", "payload": {{"id": "{}", "protected": {}}}, "channel": "delete-user"}}"#, id, protected)
+        };
+        authorize_io(policy, "reader", &message("a", true), false);
+        authorize_io(policy, "writer", &message("a", false), false);
+        authorize_io(policy, "writer", &message("a", true), true);
+        authorize_io(policy, "admin", &message("a", true), false);
+        authorize_io(policy, "admin", &message("root", true), true);
+        authorize_io(policy, "intern", &message("a", false), true);
+    }
+
+    #[test]
+    fn test_authorize_handle() {
+        let policy = "
+            role backend
+            role intern
+
+            request new-user {
+                name: string
+            }
+            response new-user {
+                tenant_id: string
+            }
+
+            allow backend response new-user when payload.tenant_id == \"acme\"
+            deny backend response new-user when payload.tenant_id == \"root\"
+            allow intern response new-user
+            deny intern response new-user
+        ";
+        let handle = r#"{"type": "handle", "channel": "new-user"}"#;
+        let response = |tenant_id: &str| {
+            format!(r#"{{"type": "response", "id": "1", "payload": {{"tenant_id": "{}"}}, "channel": "new-user"}}"#, tenant_id)
+        };
+        authorize_io(policy, "backend", handle, false);
+        authorize_io(policy, "backend", &response("acme"), false);
+        authorize_io(policy, "backend", &response("other"), true);
+        authorize_io(policy, "intern", handle, true);
+    }
+
+    #[test]
+    fn test_authorize_wildcards() {
+        let policy = "
+            broadcast orders.created {
+                id: string
+            }
+            broadcast orders.shipped.eu {
+                id: string
+            }
+            broadcast users.created {
+                id: string
+            }
+            allow backend broadcast orders.>
+            allow analytics listen *.created
+            deny analytics listen users.*
+        ";
+        let broadcast = |channel: &str| format!(r#"{{"type": "broadcast", "payload": {{"id": "1"}}, "channel": "{}"}}"#, channel);
+        let listen = |channel: &str| format!(r#"{{"type": "listen", "channel": "{}"}}"#, channel);
+        authorize_io(policy, "backend", &broadcast("orders.created"), false);
+        authorize_io(policy, "backend", &broadcast("orders.shipped.eu"), false);
+        authorize_io(policy, "backend", &broadcast("users.created"), true);
+        authorize_io(policy, "analytics", &listen("orders.created"), false);
+        authorize_io(policy, "analytics", &listen("orders.*"), false);
+        authorize_io(policy, "analytics", &listen("users.*"), true);
+        authorize_io(policy, "analytics", &listen("orders.shipped.*"), true);
+    }
+
+    #[test]
+    fn test_authorize_channel_instances() {
+        let policy = load("
+            broadcast user-updated/{user_id} {
+                user_id: string
+                name: string
+            }
+            allow backend broadcast user-updated
+            allow user listen user-updated/{caller.user_id}
+            allow admin listen user-updated
+        ").unwrap();
+        let claims = serde_json::json!({ "user_id": "42" });
+        let user = Caller::new("user", claims.as_object().unwrap());
+        let admin = Caller::new("admin", &Default::default());
+        let listen = |channel: &str| message_from_str(&policy, &format!(r#"{{"type": "listen", "channel": "{}"}}"#, channel)).unwrap();
+        assert!(crate::authorizer::authorize_message(&policy, &user, &listen("user-updated/42")).is_none());
+        assert!(crate::authorizer::authorize_message(&policy, &user, &listen("user-updated/7")).is_some());
+        assert!(crate::authorizer::authorize_message(&policy, &user, &listen("user-updated")).is_some());
+        assert!(crate::authorizer::authorize_message(&policy, &admin, &listen("user-updated")).is_none());
+
+        let broadcast = |user_id: &str| {
+            let frame = format!(r#"{{"type": "broadcast", "channel": "user-updated", "payload": {{"user_id": "{}", "name": "a"}}}}"#, user_id);
+            match message_from_str(&policy, &frame).unwrap() {
+                Message::Broadcast(broadcast_message) => broadcast_message,
+                _ => panic!("Expected broadcast message"),
+            }
+        };
+        assert_eq!(broadcast("42").channel, "user-updated/42");
+        assert!(authorize_delivery(&policy, &user, &broadcast("42")).is_some());
+        assert!(authorize_delivery(&policy, &user, &broadcast("7")).is_none());
+        assert!(authorize_delivery(&policy, &admin, &broadcast("7")).is_some());
+    }
+}
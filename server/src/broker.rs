@@ -1,9 +1,13 @@
 use std::collections::{HashSet, HashMap};
 use std::sync::{Arc, Mutex};
 use uuid::Uuid;
-use crate::validator::{RequestMessage, ResponseMessage, ListenMessage, BroadcastMessage};
+use crate::channel;
+use crate::validator::{RequestMessage, ResponseMessage, ListenMessage, BroadcastMessage, HandleMessage};
+use crate::validator::{ChannelMismatchError, MessageError, TimeoutError, UnavailableError, UnknownRequestError};
 use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::time::Duration;
+use tokio::task::AbortHandle;
 
 #[derive(Clone)]
 struct Listener {
@@ -36,7 +40,7 @@ impl fmt::Debug for Listener {
 #[derive(Clone)]
 struct Responder {
     connection_id: Uuid,
-    callback: Arc<dyn Fn(RequestMessage) -> ResponseMessage + Send + Sync>,
+    callback: Arc<dyn Fn(RequestMessage) + Send + Sync>,
 }
 
 impl PartialEq for Responder {
@@ -64,81 +68,445 @@ impl fmt::Debug for Responder {
 #[derive(Clone)]
 struct Requester {
     connection_id: Uuid,
-    message: RequestMessage,
-    callback: Arc<dyn Fn(ResponseMessage) + Send + Sync>,
+    channel: String,
+    responder_id: Uuid,
+    request_id: Option<String>,
+    callback: Arc<dyn Fn(Result<ResponseMessage, MessageError>) + Send + Sync>,
+    /// The task that answers with `MessageError::Timeout`, stopped once the request is settled.
+    timer: Arc<AbortHandle>,
+}
+
+type Requests = Arc<Mutex<HashMap<Uuid, Requester>>>;
+
+/// Listeners indexed by channel segment, so a broadcast only visits the subscriptions that can
+/// match it. `*` and `>` are stored as ordinary segments and followed alongside the exact one, and
+/// the values of a parameterized channel hang below its name in `instances`.
+#[derive(Default)]
+struct ListenerTrie {
+    listeners: HashSet<Listener>,
+    children: HashMap<String, ListenerTrie>,
+    instances: HashMap<String, ListenerTrie>,
+}
+
+impl ListenerTrie {
+    fn insert(&mut self, segments: &[&str], values: &[&str], listener: Listener) {
+        match (segments.split_first(), values.split_first()) {
+            (Some((segment, rest)), _) => {
+                self.children.entry(segment.to_string()).or_default().insert(rest, values, listener)
+            }
+            (None, Some((value, rest))) => self.instances.entry(value.to_string()).or_default().insert(&[], rest, listener),
+            (None, None) => {
+                self.listeners.insert(listener);
+            }
+        }
+    }
+
+    /// Listeners of a channel without values receive every instance of it.
+    fn collect<'a>(&'a self, segments: &[&str], values: &[&str], found: &mut HashMap<Uuid, &'a Listener>) {
+        let (segment, rest) = match segments.split_first() {
+            Some(split) => split,
+            None => {
+                found.extend(self.listeners.iter().map(|listener| (listener.connection_id, listener)));
+                if let Some((value, rest)) = values.split_first() {
+                    if let Some(instance) = self.instances.get(*value) {
+                        instance.collect(&[], rest, found);
+                    }
+                }
+                return;
+            }
+        };
+        if let Some(child) = self.children.get(*segment) {
+            child.collect(rest, values, found);
+        }
+        if let Some(child) = self.children.get("*") {
+            child.collect(rest, values, found);
+        }
+        if let Some(child) = self.children.get(">") {
+            child.collect(&[], values, found);
+        }
+    }
+
+    fn remove(&mut self, connection_id: Uuid) {
+        self.listeners.retain(|listener| listener.connection_id != connection_id);
+        for child in self.children.values_mut().chain(self.instances.values_mut()) {
+            child.remove(connection_id);
+        }
+        self.children.retain(|_, child| !child.is_empty());
+        self.instances.retain(|_, instance| !instance.is_empty());
+    }
+
+    fn is_empty(&self) -> bool {
+        self.listeners.is_empty() && self.children.is_empty() && self.instances.is_empty()
+    }
 }
 
 pub struct Broker {
-    listeners: Arc<Mutex<HashMap<String, Arc<Mutex<HashSet<Listener>>>>>>,
-    responders: Arc<Mutex<HashMap<String, Responder>>>,
-    requests: Arc<HashMap<String, Mutex<Vec<Requester>>>>,
+    listeners: Arc<Mutex<ListenerTrie>>,
+    responders: Arc<Mutex<HashMap<String, Vec<Responder>>>>,
+    requests: Requests,
 }
 
 impl Broker {
     pub fn new() -> Broker {
         Broker {
-            listeners: Arc::new(Mutex::new(HashMap::new())),
+            listeners: Arc::new(Mutex::new(ListenerTrie::default())),
             responders: Arc::new(Mutex::new(HashMap::new())),
-            requests: Arc::new(HashMap::new()),
+            requests: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    /// Subscribes to a channel, or to every channel matched by a `*`/`>` pattern. Without values, a
+    /// parameterized channel is subscribed to in all of its instances.
     pub fn listen(&self, connection_id: Uuid, message: ListenMessage, callback: Arc<dyn Fn(BroadcastMessage) + Send + Sync>) {
-        let mut listeners_map = self.listeners.lock().unwrap();
-        let listeners = listeners_map.entry(message.channel.clone()).or_insert(Arc::new(Mutex::new(HashSet::new())));
-        let mut listeners = listeners.lock().unwrap();
+        let (name, values) = channel::split_instance(&message.channel);
+        let segments: Vec<&str> = name.split('.').collect();
         let listener = Listener {
             connection_id,
             callback,
         };
-        listeners.insert(listener);
+        self.listeners.lock().unwrap().insert(&segments, &values, listener);
     }
 
+    /// A connection whose subscriptions overlap still receives each broadcast once.
     pub fn broadcast(&self, message: BroadcastMessage) {
-        let listeners_map = self.listeners.lock().unwrap();
-        if let Some(listeners) = listeners_map.get(&message.channel) {
-            let listeners = listeners.lock().unwrap();
-            for listener in listeners.iter() {
-                (listener.callback)(message.clone());
-            }
+        let listeners = self.listeners.lock().unwrap();
+        let (name, values) = channel::split_instance(&message.channel);
+        let segments: Vec<&str> = name.split('.').collect();
+        let mut found = HashMap::new();
+        listeners.collect(&segments, &values, &mut found);
+        for listener in found.values() {
+            (listener.callback)(message.clone());
         }
     }
 
-    pub fn respond(&self, connection_id: Uuid, message: ResponseMessage, callback: Arc<dyn Fn(RequestMessage) -> ResponseMessage + Send + Sync>) {
-        let mut responders = self.responders.lock().unwrap();
+    /// Registers a responder for the channel; requests are spread across responders round-robin.
+    pub fn handle(&self, connection_id: Uuid, message: HandleMessage, callback: Arc<dyn Fn(RequestMessage) + Send + Sync>) {
+        let mut responders_map = self.responders.lock().unwrap();
+        let responders = responders_map.entry(message.channel).or_default();
         let responder = Responder {
             connection_id,
             callback,
         };
-        responders.insert(message.channel, responder);
+        if !responders.contains(&responder) {
+            responders.push(responder);
+        }
     }
 
-    pub fn request(&self, connection_id: Uuid, message: RequestMessage, callback: Box<dyn Fn(ResponseMessage) + Send + Sync>) {
-        let channel = message.channel.clone();
-        let mut requests = self.requests.get(&channel);
-        if requests.is_none(){return};
-        let mut requests = requests.unwrap().lock().unwrap();
+    /// Forwards the request under a broker-assigned id so ids from different requesters never collide.
+    /// If no response arrives within `timeout`, the requester is answered with `MessageError::Timeout`.
+    pub fn request(&self, connection_id: Uuid, message: RequestMessage, timeout: Duration, callback: Arc<dyn Fn(Result<ResponseMessage, MessageError>) + Send + Sync>) {
+        let responder = {
+            let mut responders_map = self.responders.lock().unwrap();
+            match responders_map.get_mut(&message.channel) {
+                Some(responders) if !responders.is_empty() => {
+                    responders.rotate_left(1);
+                    responders.last().cloned()
+                }
+                _ => None,
+            }
+        };
+        let responder = match responder {
+            Some(responder) => responder,
+            None => {
+                return callback(Err(MessageError::Unavailable(UnavailableError {
+                    channel: message.channel,
+                })));
+            }
+        };
+
+        let id = Uuid::new_v4();
+        let mut requests = self.requests.lock().unwrap();
+        let pending = Arc::clone(&self.requests);
+        let timer = tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            let requester = pending.lock().unwrap().remove(&id);
+            if let Some(requester) = requester {
+                (requester.callback)(Err(MessageError::Timeout(TimeoutError)));
+            }
+        });
         let requester = Requester {
             connection_id,
-            message,
-            callback: Arc::new(move |response| {
-                callback(response);
-            }),
+            channel: message.channel.clone(),
+            responder_id: responder.connection_id,
+            request_id: message.id.clone(),
+            callback,
+            timer: Arc::new(timer.abort_handle()),
         };
-        requests.push(requester);
+        requests.insert(id, requester);
+        drop(requests);
+
+        (responder.callback)(RequestMessage {
+            id: Some(id.to_string()),
+            ..message
+        });
     }
 
-    fn consume_request(&self, channel: String) {
-        let mut requests = self.requests.get(&channel).unwrap().lock().unwrap();
-        let request = requests.pop();
-        match request {
-            Some(request) => {
-                let responders = self.responders.lock().unwrap();
-                if let Some(responder) = responders.get(&channel) {
-                    (responder.callback)(request.message);
+    /// Only the responder the request was forwarded to may answer it, only once, and on the channel
+    /// of the request, since the response was checked against the schema of the channel it names.
+    pub fn respond(&self, connection_id: Uuid, message: ResponseMessage) -> Option<MessageError> {
+        let requester = message.id.as_deref()
+            .and_then(|id| Uuid::parse_str(id).ok())
+            .and_then(|id| {
+                let mut requests = self.requests.lock().unwrap();
+                match requests.get(&id) {
+                    Some(requester) if requester.responder_id == connection_id => {
+                        if requester.channel != message.channel {
+                            return Some(Err(requester.channel.clone()));
+                        }
+                        requests.remove(&id).map(Ok)
+                    }
+                    _ => None,
                 }
+            });
+        let requester = match requester {
+            Some(Ok(requester)) => requester,
+            Some(Err(expected)) => {
+                return Some(MessageError::ChannelMismatch(ChannelMismatchError {
+                    channel: message.channel,
+                    expected,
+                }));
+            }
+            None => {
+                return Some(MessageError::UnknownRequest(UnknownRequestError {
+                    id: message.id,
+                }));
+            }
+        };
+        requester.timer.abort();
+        (requester.callback)(Ok(ResponseMessage {
+            id: requester.request_id,
+            ..message
+        }));
+        None
+    }
+
+    /// Drops every listener, responder and pending request owned by the connection. Requests that
+    /// were forwarded to it fail with `MessageError::Unavailable`.
+    pub fn disconnect(&self, connection_id: Uuid) {
+        self.listeners.lock().unwrap().remove(connection_id);
+        for responders in self.responders.lock().unwrap().values_mut() {
+            responders.retain(|responder| responder.connection_id != connection_id);
+        }
+        let mut orphaned = Vec::new();
+        self.requests.lock().unwrap().retain(|_, requester| {
+            if requester.connection_id != connection_id && requester.responder_id != connection_id {
+                return true;
+            }
+            requester.timer.abort();
+            if requester.connection_id != connection_id {
+                orphaned.push(requester.clone());
+            }
+            false
+        });
+        for requester in orphaned {
+            (requester.callback)(Err(MessageError::Unavailable(UnavailableError {
+                channel: requester.channel,
+            })));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Broker;
+    use crate::validator::{BroadcastMessage, HandleMessage, ListenMessage, MessageError, Params, RequestMessage, ResponseMessage};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    type Responses = Arc<Mutex<Vec<Result<ResponseMessage, MessageError>>>>;
+
+    fn setup() -> (Broker, Uuid, Arc<Mutex<Vec<RequestMessage>>>) {
+        let broker = Broker::new();
+        let responder_id = Uuid::new_v4();
+        let forwarded = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&forwarded);
+        broker.handle(
+            responder_id,
+            HandleMessage { channel: "new-user".to_string() },
+            Arc::new(move |request| sink.lock().unwrap().push(request)),
+        );
+        (broker, responder_id, forwarded)
+    }
+
+    fn request(broker: &Broker, channel: &str, id: &str) -> Responses {
+        let responses = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&responses);
+        broker.request(
+            Uuid::new_v4(),
+            RequestMessage {
+                id: Some(id.to_string()),
+                payload: Params::new(),
+                channel: channel.to_string(),
+                timeout_ms: None,
             },
-            None => {}
+            Duration::from_millis(50),
+            Arc::new(move |response| sink.lock().unwrap().push(response)),
+        );
+        responses
+    }
+
+    fn response(id: &Option<String>) -> ResponseMessage {
+        ResponseMessage {
+            id: id.clone(),
+            payload: Params::new(),
+            channel: "new-user".to_string(),
         }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_response_routed_to_requester() {
+        let (broker, responder_id, forwarded) = setup();
+        let first = request(&broker, "new-user", "1");
+        let second = request(&broker, "new-user", "1");
+
+        let forwarded = forwarded.lock().unwrap().clone();
+        assert_eq!(forwarded.len(), 2);
+        assert_ne!(forwarded[0].id, forwarded[1].id);
+
+        assert!(broker.respond(responder_id, response(&forwarded[1].id)).is_none());
+        assert!(first.lock().unwrap().is_empty());
+        assert_eq!(second.lock().unwrap()[0].as_ref().unwrap().id.as_deref(), Some("1"));
+
+        assert!(broker.respond(responder_id, response(&forwarded[1].id)).is_some());
+        assert_eq!(second.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_response_from_other_connection_ignored() {
+        let (broker, _, forwarded) = setup();
+        let responses = request(&broker, "new-user", "1");
+        let forwarded = forwarded.lock().unwrap().clone();
+
+        assert!(broker.respond(Uuid::new_v4(), response(&forwarded[0].id)).is_some());
+        assert!(responses.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_response_on_other_channel_rejected() {
+        let (broker, responder_id, forwarded) = setup();
+        let responses = request(&broker, "new-user", "1");
+        let forwarded = forwarded.lock().unwrap().clone();
+
+        let mut mislabelled = response(&forwarded[0].id);
+        mislabelled.channel = "delete-user".to_string();
+        assert!(matches!(broker.respond(responder_id, mislabelled), Some(MessageError::ChannelMismatch(_))));
+        assert!(responses.lock().unwrap().is_empty());
+
+        assert!(broker.respond(responder_id, response(&forwarded[0].id)).is_none());
+        assert_eq!(responses.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_request_without_responder() {
+        let (broker, _, _) = setup();
+        let responses = request(&broker, "other", "1");
+        assert!(matches!(responses.lock().unwrap()[0], Err(MessageError::Unavailable(_))));
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let (broker, responder_id, forwarded) = setup();
+        let responses = request(&broker, "new-user", "1");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(matches!(responses.lock().unwrap()[0], Err(MessageError::Timeout(_))));
+
+        let forwarded = forwarded.lock().unwrap().clone();
+        assert!(broker.respond(responder_id, response(&forwarded[0].id)).is_some());
+        assert_eq!(responses.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_settled_request_stops_its_timer() {
+        let (broker, responder_id, forwarded) = setup();
+        let timers = |broker: &Broker| broker.requests.lock().unwrap().values().map(|requester| requester.timer.clone()).collect::<Vec<_>>();
+        request(&broker, "new-user", "1");
+        let answered = timers(&broker);
+        let forwarded = forwarded.lock().unwrap().clone();
+        assert!(broker.respond(responder_id, response(&forwarded[0].id)).is_none());
+
+        request(&broker, "new-user", "2");
+        let orphaned = timers(&broker);
+        broker.disconnect(responder_id);
+
+        tokio::task::yield_now().await;
+        assert!(answered.iter().chain(&orphaned).all(|timer| timer.is_finished()));
+    }
+
+    #[tokio::test]
+    async fn test_responder_disconnect() {
+        let (broker, responder_id, _) = setup();
+        let responses = request(&broker, "new-user", "1");
+        broker.disconnect(responder_id);
+        assert!(matches!(responses.lock().unwrap()[0], Err(MessageError::Unavailable(_))));
+    }
+
+    #[test]
+    fn test_broadcast_wildcards() {
+        let broker = Broker::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let listen = |channel: &str, name: &'static str| {
+            let sink = Arc::clone(&received);
+            broker.listen(
+                Uuid::new_v4(),
+                ListenMessage { channel: channel.to_string() },
+                Arc::new(move |_| sink.lock().unwrap().push(name)),
+            );
+        };
+        listen("orders.created", "exact");
+        listen("orders.*", "star");
+        listen("orders.>", "tail");
+        listen("*.created", "prefix");
+
+        let broadcast = |channel: &str| {
+            received.lock().unwrap().clear();
+            broker.broadcast(BroadcastMessage { payload: Params::new(), channel: channel.to_string() });
+            let mut names = received.lock().unwrap().clone();
+            names.sort();
+            names
+        };
+        assert_eq!(broadcast("orders.created"), vec!["exact", "prefix", "star", "tail"]);
+        assert_eq!(broadcast("orders.shipped.eu"), vec!["tail"]);
+        assert_eq!(broadcast("users.created"), vec!["prefix"]);
+        assert!(broadcast("orders").is_empty());
+    }
+
+    #[test]
+    fn test_overlapping_listens_deliver_once() {
+        let broker = Broker::new();
+        let connection_id = Uuid::new_v4();
+        let count = Arc::new(Mutex::new(0));
+        for channel in ["orders.created", "orders.*"] {
+            let count = Arc::clone(&count);
+            broker.listen(
+                connection_id,
+                ListenMessage { channel: channel.to_string() },
+                Arc::new(move |_| *count.lock().unwrap() += 1),
+            );
+        }
+        broker.broadcast(BroadcastMessage { payload: Params::new(), channel: "orders.created".to_string() });
+        assert_eq!(*count.lock().unwrap(), 1);
+
+        broker.disconnect(connection_id);
+        broker.broadcast(BroadcastMessage { payload: Params::new(), channel: "orders.created".to_string() });
+        assert_eq!(*count.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_broadcast_instances() {
+        let broker = Broker::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        for (channel, name) in [("user-updated/1", "one"), ("user-updated/2", "two"), ("user-updated", "all"), ("*/1", "star")] {
+            let sink = Arc::clone(&received);
+            broker.listen(
+                Uuid::new_v4(),
+                ListenMessage { channel: channel.to_string() },
+                Arc::new(move |_| sink.lock().unwrap().push(name)),
+            );
+        }
+        broker.broadcast(BroadcastMessage { payload: Params::new(), channel: "user-updated/1".to_string() });
+        let mut names = received.lock().unwrap().clone();
+        names.sort();
+        assert_eq!(names, vec!["all", "one", "star"]);
+    }
+}
@@ -0,0 +1,66 @@
+use serde_json::Value;
+
+/// Channel names are made of `.`-separated segments, e.g. `orders.created`. In rules and listen
+/// subscriptions a `*` segment matches any one segment, and a trailing `>` matches one or more.
+/// The values of a parameterized channel follow its name after `/`, as in `user-updated/42`. A `/`
+/// or `%` inside a value is percent-encoded, so each value stays a single segment.
+pub fn encode(value: &Value) -> String {
+    let text = match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    };
+    text.replace('%', "%25").replace('/', "%2F")
+}
+
+/// Splits `user-updated/42` into its name and encoded values.
+pub fn split_instance(channel: &str) -> (&str, Vec<&str>) {
+    let mut parts = channel.split('/');
+    let name = parts.next().unwrap_or_default();
+    (name, parts.collect())
+}
+
+pub fn is_pattern(channel: &str) -> bool {
+    channel.split('.').any(|segment| segment == "*" || segment == ">")
+}
+
+pub fn matches(pattern: &str, channel: &str) -> bool {
+    let mut patterns = pattern.split('.');
+    let mut segments = channel.split('.');
+    loop {
+        match (patterns.next(), segments.next()) {
+            (Some(">"), Some(_)) => return true,
+            (Some("*"), Some(_)) => {}
+            (Some(expected), Some(segment)) if expected == segment => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, is_pattern, matches, split_instance};
+    use serde_json::json;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("orders.created", "orders.created"));
+        assert!(matches("orders.*", "orders.created"));
+        assert!(!matches("orders.*", "orders.created.eu"));
+        assert!(matches("orders.>", "orders.created.eu"));
+        assert!(!matches("orders.>", "orders"));
+        assert!(matches("*.created", "users.created"));
+        assert!(!matches("orders.created", "orders"));
+        assert!(matches(">", "user-created"));
+        assert!(is_pattern("orders.>"));
+        assert!(!is_pattern("orders.created"));
+    }
+
+    #[test]
+    fn test_instance() {
+        assert_eq!(encode(&json!("a/b%c")), "a%2Fb%25c");
+        assert_eq!(encode(&json!(42)), "42");
+        assert_eq!(split_instance("user-updated/42/a%2Fb"), ("user-updated", vec!["42", "a%2Fb"]));
+        assert_eq!(split_instance("orders.created"), ("orders.created", vec![]));
+    }
+}
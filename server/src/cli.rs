@@ -0,0 +1,172 @@
+use crate::policy::parse_duration;
+use crate::tokens::DEFAULT_TOKENS_PATH;
+use crate::typegen::Language;
+use crate::validator::Params;
+use serde_json::Value;
+use std::time::Duration;
+
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub const USAGE: &str = "Usage:
+    bus serve --policy <path to policy file or directory> [--tokens <path to token store>] [--request-timeout <duration>] [--rate-limit <messages per second>]
+    bus generate-token --role <role name> [--claim <key>=<value>]... [--tokens <path to token store>]
+    bus generate-types --policy <path to policy file or directory> --language <typescript|json-schema>
+    bus check --policy <path to policy file or directory> [--deny-warnings]
+    bus fmt --policy <path to policy file or directory> [--check]
+    bus lsp [--policy <path to policy file or directory>]
+    bus diff <old policy> <new policy> [--json]";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ServeOptions {
+    pub request_timeout: Duration,
+    /// Messages per second accepted from each connection, zero for no limit.
+    pub rate_limit: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Serve { policy: String, tokens: String, options: ServeOptions },
+    GenerateToken { role: String, tokens: String, claims: Params },
+    GenerateTypes { policy: String, language: Language },
+    Check { policy: String, deny_warnings: bool },
+    Format { policy: String, check: bool },
+    Lsp { policy: Option<String> },
+    Diff { old: String, new: String, json: bool },
+}
+
+fn get_flag(args: &[String], flag: &str) -> Result<Option<String>, String> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(index) => match args.get(index + 1) {
+            Some(value) => Ok(Some(value.clone())),
+            None => Err(format!("Missing value for {}", flag)),
+        },
+        None => Ok(None),
+    }
+}
+
+fn get_flags(args: &[String], flag: &str) -> Result<Vec<String>, String> {
+    let mut values = Vec::new();
+    for (index, arg) in args.iter().enumerate() {
+        if arg == flag {
+            match args.get(index + 1) {
+                Some(value) => values.push(value.clone()),
+                None => return Err(format!("Missing value for {}", flag)),
+            }
+        }
+    }
+    Ok(values)
+}
+
+/// `tenant_id=acme`; values that are valid JSON such as `1000` or `true` keep their type.
+fn parse_claim(claim: &str) -> Result<(String, Value), String> {
+    match claim.split_once('=') {
+        Some((key, value)) if !key.is_empty() => {
+            let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+            Ok((key.to_string(), value))
+        }
+        _ => Err(format!("Invalid claim {}, expected <key>=<value>", claim)),
+    }
+}
+
+fn require_flag(args: &[String], flag: &str) -> Result<String, String> {
+    get_flag(args, flag)?.ok_or_else(|| format!("Missing required flag {}", flag))
+}
+
+/// Removes a flag that takes no value, so the remaining arguments still come in pairs.
+fn take_switch(args: &[String], flag: &str) -> (Vec<String>, bool) {
+    let rest: Vec<String> = args.iter().filter(|arg| *arg != flag).cloned().collect();
+    let present = rest.len() < args.len();
+    (rest, present)
+}
+
+fn check_flags(args: &[String], known_flags: &[&str]) -> Result<(), String> {
+    let mut index = 0;
+    while index < args.len() {
+        if !known_flags.contains(&args[index].as_str()) {
+            return Err(format!("Unexpected argument {}", args[index]));
+        }
+        index += 2;
+    }
+    Ok(())
+}
+
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => return Err("Missing command".to_string()),
+    };
+    let tokens = get_flag(rest, "--tokens")?.unwrap_or_else(|| DEFAULT_TOKENS_PATH.to_string());
+    match command {
+        "serve" => {
+            check_flags(rest, &["--policy", "--tokens", "--request-timeout", "--rate-limit"])?;
+            let request_timeout = match get_flag(rest, "--request-timeout")? {
+                Some(value) => parse_duration(&value).ok_or_else(|| format!("Invalid duration {}", value))?,
+                None => DEFAULT_REQUEST_TIMEOUT,
+            };
+            let rate_limit = match get_flag(rest, "--rate-limit")? {
+                Some(value) => value.parse().map_err(|_| format!("Invalid rate limit {}", value))?,
+                None => 0,
+            };
+            Ok(Command::Serve {
+                policy: require_flag(rest, "--policy")?,
+                tokens,
+                options: ServeOptions {
+                    request_timeout,
+                    rate_limit,
+                },
+            })
+        }
+        "generate-token" => {
+            check_flags(rest, &["--role", "--tokens", "--claim"])?;
+            let claims = get_flags(rest, "--claim")?
+                .iter()
+                .map(|claim| parse_claim(claim))
+                .collect::<Result<Params, String>>()?;
+            Ok(Command::GenerateToken {
+                role: require_flag(rest, "--role")?,
+                tokens,
+                claims,
+            })
+        }
+        "generate-types" => {
+            check_flags(rest, &["--policy", "--language"])?;
+            let language = require_flag(rest, "--language")?;
+            Ok(Command::GenerateTypes {
+                policy: require_flag(rest, "--policy")?,
+                language: Language::from_name(&language).ok_or_else(|| format!("Unknown language {}", language))?,
+            })
+        }
+        "check" => {
+            let (rest, deny_warnings) = take_switch(rest, "--deny-warnings");
+            check_flags(&rest, &["--policy"])?;
+            Ok(Command::Check {
+                policy: require_flag(&rest, "--policy")?,
+                deny_warnings,
+            })
+        }
+        "fmt" => {
+            let (rest, check) = take_switch(rest, "--check");
+            check_flags(&rest, &["--policy"])?;
+            Ok(Command::Format {
+                policy: require_flag(&rest, "--policy")?,
+                check,
+            })
+        }
+        "lsp" => {
+            check_flags(rest, &["--policy"])?;
+            Ok(Command::Lsp { policy: get_flag(rest, "--policy")? })
+        }
+        "diff" => {
+            let (rest, json) = take_switch(rest, "--json");
+            match rest.as_slice() {
+                [old, new] if !old.starts_with("--") && !new.starts_with("--") => Ok(Command::Diff {
+                    old: old.clone(),
+                    new: new.clone(),
+                    json,
+                }),
+                _ => Err("Expected the paths of the old and new policies".to_string()),
+            }
+        }
+        _ => Err(format!("Unknown command {}", command)),
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::{BTreeMap, BTreeSet};
+use serde_json::{json, Value};
+use crate::policy::{AllowStmt, DenyStmt, MsgParam, MsgStmt, Statement, TypeExpr};
+use crate::resolver::{is_optional, Policy, Types};
+
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    /// Whether clients written against the old policy may stop working.
+    pub breaking: bool,
+    /// What changed, such as `request new-user` or `role admin`.
+    pub subject: String,
+    pub description: String,
+}
+
+/// The parts of a declaration that clients depend on.
+enum Shape<'a> {
+    Fields(&'a [MsgParam]),
+    Variants(Vec<&'a str>),
+    Alias(&'a TypeExpr),
+    Role(Option<&'a str>),
+}
+
+fn shapes(policy: &Policy) -> BTreeMap<String, Shape<'_>> {
+    let mut shapes = BTreeMap::new();
+    for stmt in &policy.statements {
+        let (subject, shape) = match stmt {
+            Statement::Msg(MsgStmt::Broadcast(broadcast_stmt))
+            | Statement::Allow(AllowStmt { schema: Some(broadcast_stmt), .. }) => {
+                (format!("broadcast {}", broadcast_stmt.msg_name), Shape::Fields(&broadcast_stmt.msg_params))
+            }
+            Statement::Msg(MsgStmt::Request(req_stmt)) => (format!("request {}", req_stmt.msg_name), Shape::Fields(&req_stmt.msg_params)),
+            Statement::Msg(MsgStmt::Response(res_stmt)) => (format!("response {}", res_stmt.msg_name), Shape::Fields(&res_stmt.msg_params)),
+            Statement::Type(type_stmt) => (format!("type {}", type_stmt.type_name), Shape::Fields(&type_stmt.msg_params)),
+            Statement::Alias(alias_stmt) => (format!("alias {}", alias_stmt.alias_name), Shape::Alias(&alias_stmt.alias_type)),
+            Statement::Enum(enum_stmt) => {
+                let variants = enum_stmt.variants.iter().map(String::as_str).collect();
+                (format!("enum {}", enum_stmt.enum_name), Shape::Variants(variants))
+            }
+            Statement::Union(union_stmt) => {
+                let variants = union_stmt.variants.iter().map(|variant| variant.param_name.as_str()).collect();
+                (format!("union {}", union_stmt.union_name), Shape::Variants(variants))
+            }
+            Statement::Role(role_stmt) => (format!("role {}", role_stmt.role_name), Shape::Role(role_stmt.extends_role.as_deref())),
+            _ => continue,
+        };
+        shapes.insert(subject, shape);
+    }
+    shapes
+}
+
+/// Rules written back in policy syntax, so that a rule whose condition or field list changed reads
+/// as one rule revoked and another granted.
+fn rules(policy: &Policy) -> BTreeSet<String> {
+    let mut rules = BTreeSet::new();
+    for stmt in &policy.statements {
+        let (keyword, role_name, msg_type, msg_name, bindings, filter, fields) = match stmt {
+            Statement::Allow(AllowStmt { role_name, msg_type, msg_name, bindings, filter, fields, .. }) => {
+                ("allow", role_name, msg_type, msg_name, bindings, filter, fields.as_ref())
+            }
+            Statement::Deny(DenyStmt { role_name, msg_type, msg_name, bindings, filter, .. }) => {
+                ("deny", role_name, msg_type, msg_name, bindings, filter, None)
+            }
+            _ => continue,
+        };
+        let mut rule = format!("{} {} {} {}", keyword, role_name, msg_type, msg_name);
+        for (claim, _) in bindings {
+            rule.push_str(&format!("/{{caller.{}}}", claim));
+        }
+        if let Some(filter) = filter {
+            rule.push_str(&format!(" when {}", filter));
+        }
+        if let Some(fields) = fields {
+            let fields: Vec<&str> = fields.iter().map(|(field, _)| field.as_str()).collect();
+            rule.push_str(&format!(" {{ {} }}", fields.join(" ")));
+        }
+        rules.insert(rule);
+    }
+    rules
+}
+
+/// `types` are the declarations of the new policy, through which added fields are looked up.
+fn diff_fields(types: &Types, subject: &str, path: &str, old: &[MsgParam], new: &[MsgParam], changes: &mut Vec<Change>) {
+    let change = |breaking: bool, description: String| Change { breaking, subject: subject.to_string(), description };
+    for old_param in old {
+        let field = format!("{}{}", path, old_param.param_name);
+        match new.iter().find(|new_param| new_param.param_name == old_param.param_name) {
+            Some(new_param) => diff_types(types, subject, &field, &old_param.param_type, &new_param.param_type, changes),
+            None => changes.push(change(true, format!("Removed field `{}`", field))),
+        }
+    }
+    for new_param in new.iter().filter(|new_param| old.iter().all(|old_param| old_param.param_name != new_param.param_name)) {
+        let field = format!("{}{}", path, new_param.param_name);
+        if is_optional(types, &new_param.param_type) {
+            changes.push(change(false, format!("Added optional field `{}`", field)));
+        } else {
+            changes.push(change(true, format!("Added required field `{}`", field)));
+        }
+    }
+}
+
+/// Inline objects are compared field by field, so that adding an optional field to one is still safe.
+fn diff_types(types: &Types, subject: &str, field: &str, old: &TypeExpr, new: &TypeExpr, changes: &mut Vec<Change>) {
+    match (old, new) {
+        (TypeExpr::Object(old_params), TypeExpr::Object(new_params)) => {
+            diff_fields(types, subject, &format!("{}.", field), old_params, new_params, changes)
+        }
+        (TypeExpr::List(old_item), TypeExpr::List(new_item)) => diff_types(types, subject, &format!("{}[]", field), old_item, new_item, changes),
+        (TypeExpr::Optional(old_inner), TypeExpr::Optional(new_inner)) => diff_types(types, subject, field, old_inner, new_inner, changes),
+        _ if old.to_string() != new.to_string() => changes.push(Change {
+            breaking: true,
+            subject: subject.to_string(),
+            description: format!("Changed field `{}` from `{}` to `{}`", field, old, new),
+        }),
+        _ => {}
+    }
+}
+
+fn diff_shapes(types: &Types, subject: &str, old: &Shape, new: &Shape, changes: &mut Vec<Change>) {
+    let change = |breaking: bool, description: String| Change { breaking, subject: subject.to_string(), description };
+    match (old, new) {
+        (Shape::Fields(old_params), Shape::Fields(new_params)) => diff_fields(types, subject, "", old_params, new_params, changes),
+        (Shape::Variants(old_variants), Shape::Variants(new_variants)) => {
+            for variant in old_variants.iter().filter(|variant| !new_variants.contains(variant)) {
+                changes.push(change(true, format!("Removed variant `{}`", variant)));
+            }
+            for variant in new_variants.iter().filter(|variant| !old_variants.contains(variant)) {
+                changes.push(change(false, format!("Added variant `{}`", variant)));
+            }
+        }
+        (Shape::Alias(old_type), Shape::Alias(new_type)) if old_type.to_string() != new_type.to_string() => {
+            changes.push(change(true, format!("Changed from `{}` to `{}`", old_type, new_type)));
+        }
+        (Shape::Role(old_parent), Shape::Role(new_parent)) if old_parent != new_parent => {
+            let description = match new_parent {
+                Some(parent) => format!("Now extends `{}`", parent),
+                None => "No longer extends another role".to_string(),
+            };
+            changes.push(change(old_parent.is_some(), description));
+        }
+        _ => {}
+    }
+}
+
+/// Compares two policies from the point of view of the clients of the old one. Removing anything,
+/// making a message stricter or revoking a permission is breaking; adding optional fields,
+/// messages, types or permissions is not.
+pub fn diff(old: &Policy, new: &Policy) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let (old_shapes, new_shapes) = (shapes(old), shapes(new));
+    for (subject, old_shape) in &old_shapes {
+        match new_shapes.get(subject) {
+            Some(new_shape) => diff_shapes(&new.types, subject, old_shape, new_shape, &mut changes),
+            None => changes.push(Change { breaking: true, subject: subject.clone(), description: "Removed".to_string() }),
+        }
+    }
+    for subject in new_shapes.keys().filter(|subject| !old_shapes.contains_key(*subject)) {
+        changes.push(Change { breaking: false, subject: subject.clone(), description: "Added".to_string() });
+    }
+
+    let (old_rules, new_rules) = (rules(old), rules(new));
+    for rule in old_rules.difference(&new_rules) {
+        let breaking = rule.starts_with("allow ");
+        changes.push(Change { breaking, subject: rule.clone(), description: "Removed rule".to_string() });
+    }
+    for rule in new_rules.difference(&old_rules) {
+        let breaking = rule.starts_with("deny ");
+        changes.push(Change { breaking, subject: rule.clone(), description: "Added rule".to_string() });
+    }
+    changes
+}
+
+pub fn to_json(changes: &[Change]) -> Value {
+    let changes: Vec<Value> = changes
+        .iter()
+        .map(|change| json!({
+            "kind": if change.breaking { "breaking" } else { "safe" },
+            "subject": change.subject,
+            "description": change.description,
+        }))
+        .collect();
+    json!({
+        "breaking": changes.iter().any(|change| change["kind"] == "breaking"),
+        "changes": changes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, Change};
+    use crate::resolver::load;
+
+    fn changes(old: &str, new: &str) -> Vec<(bool, String)> {
+        diff(&load(old).unwrap(), &load(new).unwrap())
+            .into_iter()
+            .map(|Change { breaking, subject, description }| (breaking, format!("{}: {}", subject, description)))
+            .collect()
+    }
+
+    #[test]
+    fn test_diff_fields() {
+        let old = "
+            request new-user {
+                name: string
+                email: string
+                address: { street: string }
+                tags: list<string>
+            }
+        ";
+        let new = "
+            alias Note = string?
+            request new-user {
+                name: string
+                nickname: string?
+                note: Note
+                address: { street: string zip: string }
+                tags: list<int>
+            }
+        ";
+        assert_eq!(changes(old, new), vec![
+            (true, "request new-user: Removed field `email`".to_string()),
+            (true, "request new-user: Added required field `address.zip`".to_string()),
+            (true, "request new-user: Changed field `tags[]` from `string` to `int`".to_string()),
+            (false, "request new-user: Added optional field `nickname`".to_string()),
+            (false, "request new-user: Added optional field `note`".to_string()),
+            (false, "alias Note: Added".to_string()),
+        ]);
+        assert!(changes(old, old).is_empty());
+    }
+
+    #[test]
+    fn test_diff_declarations_and_rules() {
+        let old = "
+            role backend
+            role admin extends backend
+            enum Status { pending paid }
+            response new-user { id: uuid }
+            allow backend response new-user
+            allow admin request delete-user when payload.id != null
+        ";
+        let new = "
+            role backend
+            role admin
+            enum Status { pending paid shipped }
+            broadcast user-created { id: uuid }
+            allow backend response new-user
+            allow admin request delete-user when payload.id != null and caller.tier > 1
+            deny backend response new-user
+        ";
+        assert_eq!(changes(old, new), vec![
+            (false, "enum Status: Added variant `shipped`".to_string()),
+            (true, "response new-user: Removed".to_string()),
+            (true, "role admin: No longer extends another role".to_string()),
+            (false, "broadcast user-created: Added".to_string()),
+            (true, "allow admin request delete-user when payload.id != null: Removed rule".to_string()),
+            (false, "allow admin request delete-user when payload.id != null and caller.tier > 1: Added rule".to_string()),
+            (true, "deny backend response new-user: Added rule".to_string()),
+        ]);
+    }
+}
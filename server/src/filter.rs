@@ -0,0 +1,338 @@
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::fmt;
+use crate::policy::{CompareOp, Comparison, Diagnostic, Expr, MsgParam, Span, TypeExpr};
+use crate::resolver::Types;
+use crate::scalars::{scalar_type, Decimal, ScalarKind};
+use crate::validator::Params;
+
+/// Identity of a connection, seen by filters as `caller.role` and `caller.<claim>`.
+#[derive(Debug, Clone, Default)]
+pub struct Caller {
+    fields: Params,
+}
+
+impl Caller {
+    pub fn new(role: &str, claims: &Params) -> Caller {
+        let mut fields = claims.clone();
+        fields.insert("role".to_string(), Value::String(role.to_string()));
+        Caller { fields }
+    }
+
+    pub fn role(&self) -> &str {
+        self.fields.get("role").and_then(Value::as_str).unwrap_or("")
+    }
+
+    pub fn claim(&self, name: &str) -> Option<&Value> {
+        self.fields.get(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    String,
+    Number,
+    Bool,
+    Null,
+    Composite,
+    /// Token claims are free-form, so `caller.*` is only known at runtime.
+    Any,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::String => write!(f, "string"),
+            Kind::Number => write!(f, "number"),
+            Kind::Bool => write!(f, "bool"),
+            Kind::Null => write!(f, "null"),
+            Kind::Composite => write!(f, "list, map or object"),
+            Kind::Any => write!(f, "any value"),
+        }
+    }
+}
+
+/// Looks through aliases, optionals and constraints.
+fn resolve<'a>(types: &'a Types, param_type: &'a TypeExpr) -> &'a TypeExpr {
+    match param_type {
+        TypeExpr::Named(name) if scalar_type(name).is_none() => match types.get(name) {
+            Some(declared) => resolve(types, declared),
+            None => param_type,
+        },
+        TypeExpr::Optional(inner) | TypeExpr::Constrained(inner, _) => resolve(types, inner),
+        _ => param_type,
+    }
+}
+
+fn kind_of_type(types: &Types, param_type: &TypeExpr) -> Kind {
+    match resolve(types, param_type) {
+        TypeExpr::Named(name) => match scalar_type(name).map(|scalar| scalar.kind) {
+            Some(ScalarKind::Text) => Kind::String,
+            Some(ScalarKind::Number) => Kind::Number,
+            Some(ScalarKind::Bool) => Kind::Bool,
+            None => Kind::Any,
+        },
+        TypeExpr::Enum(_) => Kind::String,
+        _ => Kind::Composite,
+    }
+}
+
+fn kind_of_value(value: &Value) -> Kind {
+    match value {
+        Value::String(_) => Kind::String,
+        Value::Number(_) => Kind::Number,
+        Value::Bool(_) => Kind::Bool,
+        Value::Null => Kind::Null,
+        _ => Kind::Composite,
+    }
+}
+
+fn kind_of_payload_path(types: &Types, params: &[MsgParam], path: &[String], span: Span, diagnostics: &mut Vec<Diagnostic>) -> Kind {
+    let mut current = TypeExpr::Object(params.to_vec());
+    for (index, segment) in path.iter().enumerate() {
+        let next = match resolve(types, &current) {
+            TypeExpr::Object(params) | TypeExpr::Union(params) => params
+                .iter()
+                .find(|param| param.param_name == *segment)
+                .map(|param| param.param_type.clone()),
+            TypeExpr::Map(_, value) => Some(*value.clone()),
+            _ => None,
+        };
+        match next {
+            Some(next) => current = next,
+            None => {
+                diagnostics.push(Diagnostic {
+                    message: format!("Unknown field `payload.{}`", path[..=index].join(".")),
+                    span,
+                });
+                return Kind::Any;
+            }
+        }
+    }
+    kind_of_type(types, &current)
+}
+
+fn check_kind(types: &Types, schema: Option<&[MsgParam]>, expr: &mut Expr, diagnostics: &mut Vec<Diagnostic>) -> Kind {
+    let span = expr.span();
+    match expr {
+        Expr::Path(path, _) => match (path[0].as_str(), schema) {
+            ("payload", Some(params)) => kind_of_payload_path(types, params, &path[1..], span, diagnostics),
+            ("payload", None) => Kind::Any,
+            ("caller", _) if path.len() == 2 && path[1] == "role" => Kind::String,
+            ("caller", _) => Kind::Any,
+            (name, _) => {
+                diagnostics.push(Diagnostic {
+                    message: format!("Unknown name `{}`, expected `payload` or `caller`", name),
+                    span,
+                });
+                Kind::Any
+            }
+        },
+        Expr::Literal(value, _) => kind_of_value(value),
+        Expr::Not(inner) => {
+            check_condition(types, schema, inner, diagnostics);
+            Kind::Bool
+        }
+        Expr::And(left, right) | Expr::Or(left, right) => {
+            check_condition(types, schema, left, diagnostics);
+            check_condition(types, schema, right, diagnostics);
+            Kind::Bool
+        }
+        Expr::Compare(op, left, right, comparison) => {
+            let left_kind = check_kind(types, schema, left, diagnostics);
+            let right_kind = check_kind(types, schema, right, diagnostics);
+            if left_kind == Kind::Number || right_kind == Kind::Number {
+                *comparison = Comparison::Numeric;
+            }
+            let comparable = match op {
+                _ if left_kind == Kind::Any || right_kind == Kind::Any => true,
+                CompareOp::Eq | CompareOp::Ne => {
+                    left_kind == right_kind || left_kind == Kind::Null || right_kind == Kind::Null
+                }
+                _ => left_kind == right_kind && matches!(left_kind, Kind::Number | Kind::String),
+            };
+            if !comparable {
+                diagnostics.push(Diagnostic {
+                    message: format!("Cannot compare {} with {}", left_kind, right_kind),
+                    span,
+                });
+            }
+            Kind::Bool
+        }
+    }
+}
+
+fn check_condition(types: &Types, schema: Option<&[MsgParam]>, expr: &mut Expr, diagnostics: &mut Vec<Diagnostic>) {
+    let kind = check_kind(types, schema, expr, diagnostics);
+    if !matches!(kind, Kind::Bool | Kind::Any) {
+        diagnostics.push(Diagnostic {
+            message: format!("Expected a condition, found {}", kind),
+            span: expr.span(),
+        });
+    }
+}
+
+/// Type-checks a `when` filter against the payload schema of the message it guards, if one is
+/// declared, and settles how each of its comparisons matches values.
+pub fn check(types: &Types, schema: Option<&[MsgParam]>, expr: &mut Expr, diagnostics: &mut Vec<Diagnostic>) {
+    check_condition(types, schema, expr, diagnostics);
+}
+
+fn lookup(path: &[String], payload: &Params, caller: &Caller) -> Value {
+    let root = if path[0] == "payload" { payload } else { &caller.fields };
+    let mut current = match path.get(1) {
+        Some(first) => root.get(first),
+        None => return Value::Object(root.clone()),
+    };
+    for field in &path[2..] {
+        current = current.and_then(|value| value.get(field));
+    }
+    current.cloned().unwrap_or(Value::Null)
+}
+
+/// Next to a number field, `decimal` strings such as `"12.50"` are read as the number they hold.
+/// Numbers keep every digit they were sent with, so large integers and long decimals compare exactly.
+fn number(value: &Value) -> Option<Decimal> {
+    match value {
+        Value::Number(number) => Decimal::parse(&number.to_string()),
+        Value::String(text) => Decimal::parse(text),
+        _ => None,
+    }
+}
+
+fn ordering(comparison: Comparison, left: &Value, right: &Value) -> Option<Ordering> {
+    match (comparison, left, right) {
+        (Comparison::Numeric, _, _) => Some(number(left)?.cmp(&number(right)?)),
+        (Comparison::Exact, Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+        (Comparison::Exact, Value::Number(_), Value::Number(_)) => Some(number(left)?.cmp(&number(right)?)),
+        _ => None,
+    }
+}
+
+fn compare(op: CompareOp, comparison: Comparison, left: &Value, right: &Value) -> bool {
+    let ordering = ordering(comparison, left, right);
+    match op {
+        CompareOp::Eq => ordering.map(|ordering| ordering == Ordering::Equal).unwrap_or(left == right),
+        CompareOp::Ne => !compare(CompareOp::Eq, comparison, left, right),
+        CompareOp::Lt => ordering == Some(Ordering::Less),
+        CompareOp::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        CompareOp::Gt => ordering == Some(Ordering::Greater),
+        CompareOp::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+    }
+}
+
+fn value(expr: &Expr, payload: &Params, caller: &Caller) -> Value {
+    match expr {
+        Expr::Path(path, _) => lookup(path, payload, caller),
+        Expr::Literal(value, _) => value.clone(),
+        Expr::Not(inner) => Value::Bool(!evaluate(inner, payload, caller)),
+        Expr::And(left, right) => Value::Bool(evaluate(left, payload, caller) && evaluate(right, payload, caller)),
+        Expr::Or(left, right) => Value::Bool(evaluate(left, payload, caller) || evaluate(right, payload, caller)),
+        Expr::Compare(op, left, right, comparison) => {
+            Value::Bool(compare(*op, *comparison, &value(left, payload, caller), &value(right, payload, caller)))
+        }
+    }
+}
+
+/// Missing fields and claims read as `null`, so a filter on them fails rather than erroring.
+pub fn evaluate(expr: &Expr, payload: &Params, caller: &Caller) -> bool {
+    value(expr, payload, caller) == Value::Bool(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{evaluate, Caller};
+    use crate::policy::Statement;
+    use crate::resolver::load;
+    use serde_json::json;
+
+    const POLICY: &str = "
+        request transfer {
+            tenant_id: string
+            amount: decimal
+            memo: { urgent: bool }?
+        }
+        allow backend request transfer when payload.tenant_id == caller.tenant_id and payload.amount < 1000
+        allow admin request transfer when not (payload.memo.urgent == true or caller.role != \"admin\")
+        allow auditor request transfer when payload.amount > caller.limit
+    ";
+
+    fn filter_allows(rule: usize, payload: serde_json::Value, caller: &Caller) -> bool {
+        let policy = load(POLICY).unwrap();
+        let filter = policy
+            .statements
+            .iter()
+            .filter_map(|stmt| match stmt {
+                Statement::Allow(allow_stmt) => allow_stmt.filter.as_ref(),
+                _ => None,
+            })
+            .nth(rule)
+            .unwrap();
+        evaluate(filter, payload.as_object().unwrap(), caller)
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let claims = json!({ "tenant_id": "acme" });
+        let caller = Caller::new("backend", claims.as_object().unwrap());
+        assert!(filter_allows(0, json!({ "tenant_id": "acme", "amount": "999.99" }), &caller));
+        assert!(!filter_allows(0, json!({ "tenant_id": "acme", "amount": 1000 }), &caller));
+        assert!(!filter_allows(0, json!({ "tenant_id": "other", "amount": 1 }), &caller));
+        assert!(!filter_allows(0, json!({ "tenant_id": "acme", "amount": 1 }), &Caller::default()));
+
+        let admin = Caller::new("admin", &Default::default());
+        assert!(filter_allows(1, json!({ "memo": { "urgent": false } }), &admin));
+        assert!(!filter_allows(1, json!({ "memo": { "urgent": true } }), &admin));
+        assert!(filter_allows(1, json!({}), &admin));
+    }
+
+    #[test]
+    fn test_evaluate_decimal_strings_by_value() {
+        let claims = json!({ "limit": "1000" });
+        let auditor = Caller::new("auditor", claims.as_object().unwrap());
+        assert!(!filter_allows(2, json!({ "amount": "999.99" }), &auditor));
+        assert!(filter_allows(2, json!({ "amount": "1000.5" }), &auditor));
+        assert!(filter_allows(2, json!({ "amount": 1001 }), &auditor));
+
+        let claims = json!({ "limit": "0.30000000000000000001" });
+        let auditor = Caller::new("auditor", claims.as_object().unwrap());
+        assert!(filter_allows(2, json!({ "amount": "0.30000000000000000002" }), &auditor));
+        assert!(!filter_allows(2, json!({ "amount": "0.3" }), &auditor));
+    }
+
+    #[test]
+    fn test_evaluate_equality_is_strict_about_type() {
+        let claims = json!({ "tenant_id": 1 });
+        let caller = Caller::new("backend", claims.as_object().unwrap());
+        assert!(!filter_allows(0, json!({ "tenant_id": "1", "amount": 1 }), &caller));
+
+        let claims = json!({ "tenant_id": "1" });
+        let caller = Caller::new("backend", claims.as_object().unwrap());
+        assert!(filter_allows(0, json!({ "tenant_id": "1", "amount": 1 }), &caller));
+
+        let claims = json!({ "tenant_id": 9007199254740993u64 });
+        let caller = Caller::new("backend", claims.as_object().unwrap());
+        assert!(!filter_allows(0, json!({ "tenant_id": 9007199254740992u64, "amount": 1 }), &caller));
+        assert!(filter_allows(0, json!({ "tenant_id": 9007199254740993u64, "amount": 1 }), &caller));
+    }
+
+    #[test]
+    fn test_check() {
+        let err = load("
+            request transfer {
+                amount: int
+                tags: list<string>
+            }
+            allow backend request transfer when payload.amout > 1
+            allow backend request transfer when payload.amount < \"1\"
+            allow backend request transfer when payload.tags or account.id == 1
+        ").unwrap_err();
+        let messages: Vec<&str> = err.diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "Unknown field `payload.amout`",
+            "Cannot compare number with string",
+            "Expected a condition, found list, map or object",
+            "Unknown name `account`, expected `payload` or `caller`",
+        ]);
+    }
+}
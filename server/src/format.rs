@@ -0,0 +1,729 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use crate::policy::{
+    lex, parse_at, quote, AllowStmt, Constraint, DenyStmt, Expr, MsgParam, MsgStmt, PolicyError, Span, SpannedToken, Statement,
+    Token, TypeExpr,
+};
+
+struct Comment<'a> {
+    span: Span,
+    text: &'a str,
+    /// Written after code on the same line, rather than on a line of its own.
+    trailing: bool,
+}
+
+fn is_line_comment(text: &str) -> bool {
+    text.starts_with('#') || text.starts_with("//")
+}
+
+/// `500ms`, `5s` or `1m`, whichever unit is the largest that reads back the same duration.
+fn duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    if millis > 0 && millis.is_multiple_of(60_000) {
+        format!("{}m", millis / 60_000)
+    } else if millis > 0 && millis.is_multiple_of(1000) {
+        format!("{}s", millis / 1000)
+    } else {
+        format!("{}ms", millis)
+    }
+}
+
+fn is_comment(token: &Token) -> bool {
+    matches!(token, Token::Comment(_) | Token::DocComment(_))
+}
+
+/// Prints statements from the parsed tree. The source is only looked at for what the tree leaves
+/// out: comments, and where blank lines separate statements or fields.
+struct Printer<'a> {
+    input: &'a str,
+    /// The tokens of the source, without whitespace.
+    tokens: Vec<SpannedToken>,
+    /// The `}` closing each `{`, both by offset.
+    closing: HashMap<usize, usize>,
+    comments: Vec<Comment<'a>>,
+    next_comment: usize,
+    output: String,
+    line: String,
+    /// Whether the last line printed opens a block, which is never followed by a blank line.
+    block_start: bool,
+    /// Whether the last line printed ends with a comment running to the end of the line.
+    open_comment: bool,
+    /// The indentation of the statement or field being printed.
+    depth: usize,
+    /// The next token to print, as an index into `tokens`.
+    cursor: usize,
+    /// Where the statement being printed ends, so that its pieces are only looked for inside it.
+    limit: usize,
+}
+
+impl<'a> Printer<'a> {
+    fn new(input: &'a str) -> Printer<'a> {
+        let tokens: Vec<SpannedToken> = lex(input, &mut Vec::new())
+            .into_iter()
+            .filter(|token| token.token != Token::Whitespace)
+            .collect();
+        let mut closing = HashMap::new();
+        let mut open = Vec::new();
+        for token in &tokens {
+            match token.token {
+                Token::LBrace => open.push(token.span.start),
+                Token::RBrace => {
+                    if let Some(start) = open.pop() {
+                        closing.insert(start, token.span.start);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let comments = tokens
+            .iter()
+            .filter(|token| is_comment(&token.token))
+            .map(|token| {
+                let line_start = input[..token.span.start].rfind('\n').map(|index| index + 1).unwrap_or(0);
+                Comment {
+                    span: token.span,
+                    text: input[token.span.start..token.span.end].trim_end(),
+                    trailing: !input[line_start..token.span.start].trim().is_empty(),
+                }
+            })
+            .collect();
+        Printer {
+            input,
+            tokens,
+            closing,
+            comments,
+            next_comment: 0,
+            output: String::new(),
+            line: String::new(),
+            block_start: false,
+            open_comment: false,
+            depth: 0,
+            cursor: 0,
+            limit: input.len(),
+        }
+    }
+
+    /// The code token right before an offset, such as the keyword before a declared name.
+    fn token_before(&self, offset: usize) -> Option<&SpannedToken> {
+        self.tokens
+            .iter()
+            .rev()
+            .skip_while(|token| token.span.start >= offset)
+            .find(|token| !is_comment(&token.token))
+    }
+
+    /// Where a field starts: its name, which is right before the `:` preceding its type.
+    fn field_start(&self, param: &MsgParam) -> usize {
+        let colon = self.token_before(param.type_span.start).map(|token| token.span.start).unwrap_or(param.type_span.start);
+        self.token_before(colon).map(|token| token.span.start).unwrap_or(colon)
+    }
+
+    /// The `}` of the first block opening at or after an offset, leaving out channel parameters.
+    fn block_end(&self, from: usize) -> usize {
+        let mut previous: Option<&Token> = None;
+        for token in &self.tokens {
+            if token.span.start >= from && token.token == Token::LBrace && previous != Some(&Token::Slash) {
+                return self.closing.get(&token.span.start).copied().unwrap_or(self.input.len());
+            }
+            previous = Some(&token.token);
+        }
+        self.input.len()
+    }
+
+    fn blank_before(&self, offset: usize) -> bool {
+        let previous = self.tokens.iter().rev().find(|token| token.span.end <= offset);
+        previous.is_some_and(|previous| self.input[previous.span.end..offset].matches('\n').count() > 1)
+    }
+
+    fn push(&mut self, text: &str) {
+        self.line.push_str(text);
+    }
+
+    fn end_line(&mut self) {
+        self.output.push_str(self.line.trim_end());
+        self.output.push('\n');
+        self.block_start = self.line.ends_with('{');
+        self.open_comment = false;
+        self.line.clear();
+    }
+
+    fn start_line(&mut self, offset: usize, depth: usize) {
+        if !self.output.is_empty() && !self.block_start && self.blank_before(offset) {
+            self.output.push('\n');
+        }
+        self.depth = depth;
+        self.line.push_str(&"    ".repeat(depth));
+    }
+
+    /// Moves on to the first token at or after an offset.
+    fn seek(&mut self, offset: usize) {
+        self.cursor = self.tokens.partition_point(|token| token.span.start < offset);
+    }
+
+    /// Where the next token to print starts in the source.
+    fn offset(&self) -> usize {
+        self.tokens.get(self.cursor).map(|token| token.span.start).unwrap_or(self.input.len())
+    }
+
+    /// Prints the comments written before an offset, each on its own line at `depth` unless it
+    /// trails the line above.
+    fn comments_before(&mut self, offset: usize, depth: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment).filter(|comment| comment.span.start < offset) {
+            let (span, text, trailing) = (comment.span, comment.text, comment.trailing);
+            self.next_comment += 1;
+            if trailing && !self.output.is_empty() && !self.open_comment {
+                self.output.pop();
+                self.output.push(' ');
+                self.output.push_str(text);
+                self.output.push('\n');
+            } else {
+                self.start_line(span.start, depth);
+                self.push(text);
+                let block_start = self.block_start;
+                self.end_line();
+                self.block_start = block_start && trailing;
+            }
+            self.open_comment = is_line_comment(text);
+        }
+    }
+
+    /// Prints the comments written before an offset in the middle of a line, where they were
+    /// written. A comment running to the end of the line continues the statement on the next one.
+    fn inline_comments_before(&mut self, offset: usize) {
+        if self.line.trim().is_empty() {
+            let indentation = std::mem::take(&mut self.line);
+            let depth = self.depth;
+            self.comments_before(offset, depth + 1);
+            self.depth = depth;
+            self.line = indentation;
+            return;
+        }
+        while let Some(comment) = self.comments.get(self.next_comment).filter(|comment| comment.span.start < offset) {
+            let text = comment.text;
+            self.next_comment += 1;
+            if !self.line.ends_with(' ') {
+                self.push(" ");
+            }
+            self.push(text);
+            if is_line_comment(text) {
+                let depth = self.depth;
+                self.end_line();
+                self.line.push_str(&"    ".repeat(depth + 1));
+            } else {
+                self.push(" ");
+            }
+        }
+    }
+
+    /// Prints a piece of a statement after the comments written before the tokens it stands for,
+    /// found further on in the statement. Pieces the source writes differently, such as `5s` for
+    /// `5000`, are printed all the same.
+    fn token(&mut self, text: &str) {
+        let found = (self.cursor..self.tokens.len())
+            .take_while(|index| self.tokens[*index].span.start < self.limit)
+            .find_map(|index| {
+                let start = self.tokens[index].span.start;
+                if is_comment(&self.tokens[index].token) || !self.input[start..].starts_with(text) {
+                    return None;
+                }
+                let end = start + text.len();
+                let last = self.tokens[index..].iter().take_while(|token| token.span.start < end).position(|token| token.span.end == end)?;
+                Some((start, index + last + 1))
+            });
+        if let Some((start, next)) = found {
+            self.inline_comments_before(start);
+            self.cursor = next;
+        }
+        self.push(text);
+    }
+
+    /// Prints a piece the source may have left out, such as the parentheses around a condition,
+    /// matching it only against the very next token.
+    fn optional_token(&mut self, text: &str) {
+        let next = self.tokens[self.cursor..].iter().find(|token| !is_comment(&token.token));
+        match next {
+            Some(next) if self.input[next.span.start..].starts_with(text) => self.token(text),
+            _ => self.push(text),
+        }
+    }
+
+    /// Prints a name or literal whose source is known to be at `span`.
+    fn token_at(&mut self, text: &str, span: Span) {
+        self.seek(span.start);
+        self.inline_comments_before(span.start);
+        self.push(text);
+        self.seek(span.end);
+    }
+
+    /// Starts the line of a statement or field that begins at `offset` in the source.
+    fn start_item(&mut self, offset: usize, depth: usize) {
+        self.comments_before(offset, depth);
+        self.start_line(offset, depth);
+        self.seek(offset);
+    }
+
+    /// Prints the fields of a block closed by the `}` at `end`, one per line.
+    fn fields(&mut self, params: &[MsgParam], end: usize, depth: usize) {
+        let has_comments = self.comments.get(self.next_comment).is_some_and(|comment| comment.span.start < end);
+        if params.is_empty() && !has_comments {
+            self.push("{}");
+            self.seek(end + 1);
+            return;
+        }
+        self.push("{");
+        self.end_line();
+        for param in params {
+            self.start_item(self.field_start(param), depth + 1);
+            self.token(&param.param_name);
+            self.token(":");
+            self.push(" ");
+            self.type_expr(&param.param_type, depth + 1);
+            self.end_line();
+        }
+        self.comments_before(end, depth + 1);
+        self.depth = depth;
+        self.line.push_str(&"    ".repeat(depth));
+        self.push("}");
+        self.seek(end + 1);
+    }
+
+    /// Prints a type, with inline objects as blocks.
+    fn type_expr(&mut self, type_expr: &TypeExpr, depth: usize) {
+        match type_expr {
+            TypeExpr::Named(name) => self.token(name),
+            TypeExpr::List(item) => {
+                self.token("list");
+                self.token("<");
+                self.type_expr(item, depth);
+                self.token(">");
+            }
+            TypeExpr::Map(key, value) => {
+                self.token("map");
+                self.token("<");
+                self.type_expr(key, depth);
+                self.token(",");
+                self.push(" ");
+                self.type_expr(value, depth);
+                self.token(">");
+            }
+            TypeExpr::Object(params) => {
+                let end = self.block_end(self.offset());
+                self.fields(params, end, depth);
+            }
+            TypeExpr::Optional(inner) => {
+                self.type_expr(inner, depth);
+                self.token("?");
+            }
+            TypeExpr::Constrained(inner, constraints) => {
+                self.type_expr(inner, depth);
+                for constraint in constraints {
+                    match constraint {
+                        Constraint::Range { .. } => {
+                            self.token("(");
+                            self.token(&constraint.to_string());
+                            self.token(")");
+                        }
+                        Constraint::Pattern(_) => {
+                            self.push(" ");
+                            self.token(&constraint.to_string());
+                        }
+                    }
+                }
+            }
+            TypeExpr::Enum(_) | TypeExpr::Union(_) => self.push(&type_expr.to_string()),
+        }
+    }
+
+    /// Prints a condition with the parentheses its precedence needs, as `Expr` displays it.
+    fn expr(&mut self, expr: &Expr) {
+        let compound = |expr: &Expr| !matches!(expr, Expr::Path(..) | Expr::Literal(..));
+        match expr {
+            Expr::Path(_, span) | Expr::Literal(_, span) => self.token_at(&expr.to_string(), *span),
+            Expr::Not(inner) => {
+                self.token("not");
+                self.push(" ");
+                self.operand(inner, compound(inner) && !matches!(**inner, Expr::Not(_)));
+            }
+            Expr::And(left, right) => {
+                self.operand(left, matches!(**left, Expr::Or(..)));
+                self.push(" ");
+                self.token("and");
+                self.push(" ");
+                self.operand(right, matches!(**right, Expr::Or(..)));
+            }
+            Expr::Or(left, right) => {
+                self.expr(left);
+                self.push(" ");
+                self.token("or");
+                self.push(" ");
+                self.expr(right);
+            }
+            Expr::Compare(op, left, right, _) => {
+                self.operand(left, compound(left));
+                self.push(" ");
+                self.token(&op.to_string());
+                self.push(" ");
+                self.operand(right, compound(right));
+            }
+        }
+    }
+
+    fn operand(&mut self, expr: &Expr, parenthesized: bool) {
+        if parenthesized {
+            self.optional_token("(");
+            self.expr(expr);
+            self.optional_token(")");
+        } else {
+            self.expr(expr);
+        }
+    }
+
+    /// Channel parameters, as in `user-updated/{caller.user_id}`.
+    fn instance<'b>(&mut self, params: impl IntoIterator<Item = &'b String>, prefix: &str) {
+        for param in params {
+            self.token("/");
+            self.token("{");
+            self.token(&format!("{}{}", prefix, param));
+            self.token("}");
+        }
+    }
+
+    /// The names between `{ }` on one line, as in enum variants and the field lists of rules.
+    fn names<'b>(&mut self, names: impl IntoIterator<Item = &'b String>) {
+        self.token("{");
+        let mut empty = true;
+        for name in names {
+            self.push(" ");
+            self.token(name);
+            empty = false;
+        }
+        if !empty {
+            self.push(" ");
+        }
+        self.token("}");
+    }
+
+    fn when(&mut self, filter: &Option<Expr>) {
+        if let Some(filter) = filter {
+            self.push(" ");
+            self.token("when");
+            self.push(" ");
+            self.expr(filter);
+        }
+    }
+
+    /// Where a statement starts: the keyword before the name its span covers, or the rule itself.
+    fn statement_start(&self, stmt: &Statement) -> usize {
+        let name = match stmt {
+            Statement::Allow(AllowStmt { span, .. }) | Statement::Deny(DenyStmt { span, .. }) => return span.start,
+            Statement::Import(import_stmt) => return import_stmt.span.start,
+            Statement::Msg(MsgStmt::Broadcast(broadcast_stmt)) => broadcast_stmt.span.start,
+            Statement::Msg(MsgStmt::Request(req_stmt)) => req_stmt.span.start,
+            Statement::Msg(MsgStmt::Response(res_stmt)) => res_stmt.span.start,
+            Statement::Role(role_stmt) => role_stmt.span.start,
+            Statement::Type(type_stmt) => type_stmt.span.start,
+            Statement::Alias(alias_stmt) => alias_stmt.span.start,
+            Statement::Enum(enum_stmt) => enum_stmt.span.start,
+            Statement::Union(union_stmt) => union_stmt.span.start,
+        };
+        self.token_before(name).map(|token| token.span.start).unwrap_or(name)
+    }
+
+    /// Prints a statement, whose source ends before `limit`.
+    fn statement(&mut self, stmt: &Statement, limit: usize) {
+        self.limit = limit;
+        self.start_item(self.statement_start(stmt), 0);
+        match stmt {
+            Statement::Allow(allow_stmt) => {
+                self.token("allow");
+                self.push(" ");
+                self.token(&allow_stmt.role_name);
+                self.push(" ");
+                self.token(&allow_stmt.msg_type.to_string());
+                self.push(" ");
+                self.token(&allow_stmt.msg_name);
+                match &allow_stmt.schema {
+                    Some(schema) => self.instance(schema.bound_fields.iter().map(|(field, _)| field), ""),
+                    None => self.instance(allow_stmt.bindings.iter().map(|(claim, _)| claim), "caller."),
+                }
+                self.when(&allow_stmt.filter);
+                if let Some(schema) = &allow_stmt.schema {
+                    self.push(" ");
+                    self.fields(&schema.msg_params, self.block_end(self.offset()), 0);
+                }
+                if let Some(fields) = &allow_stmt.fields {
+                    self.push(" ");
+                    self.names(fields.iter().map(|(field, _)| field));
+                }
+            }
+            Statement::Deny(deny_stmt) => {
+                self.token("deny");
+                self.push(" ");
+                self.token(&deny_stmt.role_name);
+                self.push(" ");
+                self.token(&deny_stmt.msg_type.to_string());
+                self.push(" ");
+                self.token(&deny_stmt.msg_name);
+                self.instance(deny_stmt.bindings.iter().map(|(claim, _)| claim), "caller.");
+                self.when(&deny_stmt.filter);
+            }
+            Statement::Import(import_stmt) => {
+                self.token("import");
+                self.push(" ");
+                self.token(&quote(&import_stmt.path));
+            }
+            Statement::Msg(MsgStmt::Broadcast(broadcast_stmt)) => {
+                self.token("broadcast");
+                self.push(" ");
+                self.token(&broadcast_stmt.msg_name);
+                self.instance(broadcast_stmt.bound_fields.iter().map(|(field, _)| field), "");
+                self.push(" ");
+                self.fields(&broadcast_stmt.msg_params, self.block_end(self.offset()), 0);
+            }
+            Statement::Msg(MsgStmt::Request(req_stmt)) => {
+                self.token("request");
+                self.push(" ");
+                self.token(&req_stmt.msg_name);
+                if let Some(timeout) = req_stmt.timeout {
+                    self.push(" ");
+                    self.token("timeout");
+                    self.push(" ");
+                    self.token(&duration(timeout));
+                }
+                self.push(" ");
+                self.fields(&req_stmt.msg_params, self.block_end(req_stmt.span.end), 0);
+            }
+            Statement::Msg(MsgStmt::Response(res_stmt)) => {
+                self.token("response");
+                self.push(" ");
+                self.token(&res_stmt.msg_name);
+                self.push(" ");
+                self.fields(&res_stmt.msg_params, self.block_end(res_stmt.span.end), 0);
+            }
+            Statement::Role(role_stmt) => {
+                self.token("role");
+                self.push(" ");
+                self.token(&role_stmt.role_name);
+                if let Some(extends_role) = &role_stmt.extends_role {
+                    self.push(" ");
+                    self.token("extends");
+                    self.push(" ");
+                    self.token(extends_role);
+                }
+            }
+            Statement::Type(type_stmt) => {
+                self.token("type");
+                self.push(" ");
+                self.token(&type_stmt.type_name);
+                self.push(" ");
+                self.fields(&type_stmt.msg_params, self.block_end(type_stmt.span.end), 0);
+            }
+            Statement::Alias(alias_stmt) => {
+                self.token("alias");
+                self.push(" ");
+                self.token(&alias_stmt.alias_name);
+                self.push(" ");
+                self.token("=");
+                self.push(" ");
+                self.type_expr(&alias_stmt.alias_type, 0);
+            }
+            Statement::Enum(enum_stmt) => {
+                self.token("enum");
+                self.push(" ");
+                self.token(&enum_stmt.enum_name);
+                self.push(" ");
+                self.names(&enum_stmt.variants);
+            }
+            Statement::Union(union_stmt) => {
+                self.token("union");
+                self.push(" ");
+                self.token(&union_stmt.union_name);
+                self.push(" ");
+                self.fields(&union_stmt.variants, self.block_end(union_stmt.span.end), 0);
+            }
+        }
+        self.end_line();
+    }
+}
+
+/// Prints a policy file in the canonical layout: one statement per line, blocks of fields with one
+/// field per line indented by four spaces, enum variants and field lists of rules on one line, and
+/// single spaces around operators. Comments are kept where they were written, and so are single
+/// blank lines between statements and fields.
+pub fn format(input: &str) -> Result<String, PolicyError> {
+    let statements = parse_at(input, 0)?;
+    let mut printer = Printer::new(input);
+    let starts: Vec<usize> = statements.iter().map(|stmt| printer.statement_start(stmt)).collect();
+    for (index, stmt) in statements.iter().enumerate() {
+        printer.statement(stmt, starts.get(index + 1).copied().unwrap_or(input.len()));
+    }
+    printer.comments_before(input.len(), 0);
+    Ok(printer.output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+    use crate::policy::{lex, Token};
+
+    #[test]
+    fn test_format() {
+        let source = "
+
+role   frontend
+role admin extends frontend   # may also respond
+/// Creates a user.
+request new-user timeout 5000
+{
+  name : string(1..120)
+        tags: list< string>?
+
+
+  address: {street: string
+    # where mail goes
+    zip: string /^[0-9]+$/}
+}
+allow frontend request new-user when payload.name==\"x\\\"y\" and
+(payload.tags != null or not caller.admin)
+allow admin listen user-updated/{caller.user_id} {id,name}
+enum Status {pending
+  paid}
+";
+        assert_eq!(
+            format(source).unwrap(),
+            "role frontend
+role admin extends frontend # may also respond
+/// Creates a user.
+request new-user timeout 5s {
+    name: string(1..120)
+    tags: list<string>?
+
+    address: {
+        street: string
+        # where mail goes
+        zip: string /^[0-9]+$/
+    }
+}
+allow frontend request new-user when payload.name == \"x\\\"y\" and (payload.tags != null or not caller.admin)
+allow admin listen user-updated/{caller.user_id} { id name }
+enum Status { pending paid }
+"
+        );
+    }
+
+    #[test]
+    fn test_format_ignores_layout() {
+        let compact = "role a role b extends a type A { a: int } type B { b: list<A> } \
+            union Payment { card: A, other: B } allow a request r when payload.x>1 and payload.y<=caller.limit \
+            request r { x: int y: decimal } response r {}";
+        let spread = "
+role a
+role b
+    extends a
+type A {
+        a : int
+}
+type B {
+    b: list<A> 
+}
+union Payment {
+    card: A
+    other: B
+}
+allow a request r when payload.x > 1
+    and payload.y <= caller.limit
+request r {
+    x: int
+    y: decimal
+}
+response r {
+}
+";
+        assert_eq!(format(compact).unwrap(), format(spread).unwrap());
+        assert_eq!(
+            format(compact).unwrap(),
+            "role a
+role b extends a
+type A {
+    a: int
+}
+type B {
+    b: list<A>
+}
+union Payment {
+    card: A
+    other: B
+}
+allow a request r when payload.x > 1 and payload.y <= caller.limit
+request r {
+    x: int
+    y: decimal
+}
+response r {}
+"
+        );
+    }
+
+    #[test]
+    fn test_format_is_idempotent_and_keeps_comments() {
+        let example = format(include_str!("../../examples/example1.bus")).unwrap();
+        assert_eq!(format(&example).unwrap(), example);
+
+        let source = "# policy
+role a /* inline */ role b # b
+request r { # opens
+    /// The id.
+    id: uuid # trailing
+    // last
+} # closes
+allow a request r when payload.id != null # first
+    # second
+/* end */
+";
+        let formatted = format(source).unwrap();
+        assert_eq!(format(&formatted).unwrap(), formatted);
+        let comments = |input: &str| -> Vec<String> {
+            lex(input, &mut Vec::new())
+                .into_iter()
+                .filter_map(|token| match token.token {
+                    Token::Comment(comment) => Some(comment.trim_end().to_string()),
+                    Token::DocComment(doc) => Some(doc),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(comments(&formatted), comments(source));
+    }
+
+    #[test]
+    fn test_format_keeps_literals_and_comments_in_place() {
+        let source = "type t { id: int64(<=9007199254740993) }
+enum status { active # first
+  inactive }
+role a
+request r { x: int }
+allow a request r when /* why */ payload.x > 1
+";
+        let formatted = format(source).unwrap();
+        assert_eq!(
+            formatted,
+            "type t {
+    id: int64(<=9007199254740993)
+}
+enum status { active # first
+    inactive }
+role a
+request r {
+    x: int
+}
+allow a request r when /* why */ payload.x > 1
+"
+        );
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_format_rejects_invalid_policy() {
+        assert!(format("role {").is_err());
+    }
+}
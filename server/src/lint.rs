@@ -0,0 +1,170 @@
+use std::collections::HashSet;
+use crate::channel;
+use crate::policy::{AllowStmt, DenyStmt, Diagnostic, MsgStmt, MsgType, Span, Statement};
+use crate::resolver::Policy;
+
+struct Declaration<'a> {
+    msg_type: MsgType,
+    msg_name: &'a str,
+    span: Span,
+}
+
+fn declarations(statements: &[Statement]) -> Vec<Declaration<'_>> {
+    statements
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Statement::Msg(MsgStmt::Broadcast(broadcast_stmt))
+            | Statement::Allow(AllowStmt { schema: Some(broadcast_stmt), .. }) => {
+                Some((MsgType::Broadcast, &broadcast_stmt.msg_name, broadcast_stmt.span))
+            }
+            Statement::Msg(MsgStmt::Request(req_stmt)) => Some((MsgType::Request, &req_stmt.msg_name, req_stmt.span)),
+            Statement::Msg(MsgStmt::Response(res_stmt)) => Some((MsgType::Response, &res_stmt.msg_name, res_stmt.span)),
+            _ => None,
+        })
+        .map(|(msg_type, msg_name, span)| Declaration { msg_type, msg_name, span })
+        .collect()
+}
+
+/// Listeners subscribe to what is broadcast, so listen rules point at broadcast declarations.
+fn declared_type(msg_type: MsgType) -> MsgType {
+    match msg_type {
+        MsgType::Listen => MsgType::Broadcast,
+        other => other,
+    }
+}
+
+/// Problems that don't stop a policy from loading. Rules naming a role or message that is never
+/// declared are errors, since they can't take effect; the rest are most likely not what the author
+/// meant, but harmless.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub errors: Vec<Diagnostic>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+pub fn lint(policy: &Policy) -> Report {
+    let statements = &policy.statements;
+    let declarations = declarations(statements);
+    let roles: HashSet<&str> = statements
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Statement::Role(role_stmt) => Some(role_stmt.role_name.as_str()),
+            _ => None,
+        })
+        .collect();
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+
+    for stmt in statements {
+        let (role_name, msg_type, msg_name, span) = match stmt {
+            Statement::Allow(AllowStmt { role_name, msg_type, msg_name, span, .. })
+            | Statement::Deny(DenyStmt { role_name, msg_type, msg_name, span, .. }) => (role_name, *msg_type, msg_name, *span),
+            _ => continue,
+        };
+        if !roles.contains(role_name.as_str()) {
+            errors.push(Diagnostic {
+                message: format!("Role `{}` is not declared", role_name),
+                span,
+            });
+        }
+        let declared = declarations.iter().any(|declaration| {
+            declaration.msg_type == declared_type(msg_type) && channel::matches(msg_name, declaration.msg_name)
+        });
+        if !declared {
+            errors.push(Diagnostic {
+                message: format!("No {} message matches `{}`", declared_type(msg_type), msg_name),
+                span,
+            });
+        }
+    }
+
+    for declaration in &declarations {
+        let counterpart = match declaration.msg_type {
+            MsgType::Request => Some((MsgType::Response, "Request", "response")),
+            MsgType::Response => Some((MsgType::Request, "Response", "request")),
+            _ => None,
+        };
+        if let Some((counterpart_type, kind, missing)) = counterpart {
+            if !declarations.iter().any(|other| other.msg_type == counterpart_type && other.msg_name == declaration.msg_name) {
+                warnings.push(Diagnostic {
+                    message: format!("{} `{}` has no {} declared", kind, declaration.msg_name, missing),
+                    span: declaration.span,
+                });
+            }
+        }
+
+        let needed: &[(MsgType, &str)] = match declaration.msg_type {
+            MsgType::Broadcast => &[(MsgType::Broadcast, "broadcast"), (MsgType::Listen, "listen to")],
+            MsgType::Request => &[(MsgType::Request, "send")],
+            _ => &[(MsgType::Response, "respond to")],
+        };
+        for (msg_type, action) in needed {
+            let allowed = statements.iter().any(|stmt| match stmt {
+                Statement::Allow(allow_stmt) => {
+                    allow_stmt.msg_type == *msg_type && channel::matches(&allow_stmt.msg_name, declaration.msg_name)
+                }
+                _ => false,
+            });
+            if !allowed {
+                warnings.push(Diagnostic {
+                    message: format!("No role may {} `{}`", action, declaration.msg_name),
+                    span: declaration.span,
+                });
+            }
+        }
+    }
+
+    errors.sort_by_key(|error| error.span.start);
+    warnings.sort_by_key(|warning| warning.span.start);
+    Report { errors, warnings }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::lint;
+    use crate::policy::Diagnostic;
+    use crate::resolver::load;
+
+    fn messages(diagnostics: Vec<Diagnostic>) -> Vec<String> {
+        diagnostics.into_iter().map(|diagnostic| diagnostic.message).collect()
+    }
+
+    #[test]
+    fn test_lint_clean_policy() {
+        let report = lint(&load(include_str!("../../examples/example1.bus")).unwrap());
+        assert!(report.errors.is_empty());
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_lint() {
+        let report = lint(&load("
+                role frontend
+                request new-user {
+                    name: string
+                }
+                broadcast user-created {
+                    id: uuid
+                }
+                allow frontend request new-user
+                allow frontend broadcast user-created
+                allow backend response new-user
+                allow frontend listen orders.*
+            ").unwrap());
+        assert_eq!(
+            messages(report.errors),
+            vec![
+                "Role `backend` is not declared",
+                "No response message matches `new-user`",
+                "No broadcast message matches `orders.*`",
+            ]
+        );
+        assert_eq!(
+            messages(report.warnings),
+            vec![
+                "Request `new-user` has no response declared",
+                "No role may listen to `user-created`",
+            ]
+        );
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::policy::{parse_at, AllowStmt, DenyStmt, Diagnostic, MsgParam, MsgStmt, PolicyError, Span, Statement, TypeExpr};
+use crate::resolver::{resolve, Policy};
+
+/// A policy file, and the offset its spans start at among all loaded files.
+pub struct Source {
+    pub path: PathBuf,
+    pub body: String,
+    pub base: usize,
+    pub namespace: Option<String>,
+}
+
+/// Every file read while loading a policy, so diagnostics can point at the file they belong to.
+#[derive(Default)]
+pub struct Sources {
+    files: Vec<Source>,
+    unsaved: HashMap<PathBuf, String>,
+}
+
+impl Sources {
+    /// Leaves a gap after each file, so that an offset at the end of one is not the start of the next.
+    fn add(&mut self, path: PathBuf, body: String, namespace: Option<&str>) -> usize {
+        let base = self.files.last().map(|file| file.base + file.body.len() + 1).unwrap_or(0);
+        let namespace = namespace.map(str::to_string);
+        self.files.push(Source { path, body, base, namespace });
+        base
+    }
+
+    /// Contents to load instead of what is on disk, such as the unsaved changes of an editor.
+    pub fn set_unsaved(&mut self, path: &Path, body: String) {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        self.unsaved.insert(path, body);
+    }
+
+    pub fn files(&self) -> &[Source] {
+        &self.files
+    }
+
+    /// The file a global offset points into.
+    pub fn file_at(&self, offset: usize) -> Option<&Source> {
+        self.files.iter().rev().find(|file| file.base <= offset)
+    }
+
+    /// The file a diagnostic points into, and the diagnostic with a span local to that file.
+    pub fn locate(&self, diagnostic: &Diagnostic) -> Option<(&Source, Diagnostic)> {
+        let file = self.file_at(diagnostic.span.start)?;
+        let local = Diagnostic {
+            message: diagnostic.message.clone(),
+            span: Span {
+                start: diagnostic.span.start - file.base,
+                end: diagnostic.span.end - file.base,
+            },
+        };
+        Some((file, local))
+    }
+
+    /// Diagnostics are sorted by offset, so the ones of each file come in a row.
+    pub fn render(&self, err: &PolicyError) -> String {
+        let mut rendered: Vec<String> = Vec::new();
+        let mut current: Option<(&Source, PolicyError)> = None;
+        for (file, local) in err.diagnostics.iter().filter_map(|diagnostic| self.locate(diagnostic)) {
+            match &mut current {
+                Some((current_file, file_err)) if std::ptr::eq(*current_file, file) => file_err.diagnostics.push(local),
+                _ => {
+                    if let Some((current_file, file_err)) = current.replace((file, PolicyError { diagnostics: vec![local] })) {
+                        rendered.push(file_err.render(&current_file.path.display().to_string(), &current_file.body));
+                    }
+                }
+            }
+        }
+        if let Some((current_file, file_err)) = current {
+            rendered.push(file_err.render(&current_file.path.display().to_string(), &current_file.body));
+        }
+        rendered.join("\n")
+    }
+
+    pub fn render_warnings(&self, warnings: &[Diagnostic]) -> String {
+        warnings
+            .iter()
+            .filter_map(|warning| self.locate(warning))
+            .map(|(file, local)| local.render_as("warning", &file.path.display().to_string(), &file.body))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(PathBuf, io::Error),
+    Policy(PolicyError),
+}
+
+struct Loader<'a> {
+    sources: &'a mut Sources,
+    loaded: HashSet<PathBuf>,
+    statements: Vec<Statement>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Loads a policy file, or every `.bus` file of a directory, along with the files they import.
+/// Imported files, and the files of a directory, are namespaced by their file name, so that
+/// `billing.bus` declares `billing::invoice-paid`.
+pub fn load(path: &Path, sources: &mut Sources) -> Result<Policy, LoadError> {
+    let (statements, mut diagnostics) = parse_files(path, sources)?;
+    if !diagnostics.is_empty() {
+        diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+        return Err(LoadError::Policy(PolicyError { diagnostics }));
+    }
+    resolve(statements).map_err(LoadError::Policy)
+}
+
+/// Parses the files of a policy like `load`, but stops short of resolving them, so that whatever
+/// parsed is returned along with the syntax errors and unreadable imports.
+pub fn parse_files(path: &Path, sources: &mut Sources) -> Result<(Vec<Statement>, Vec<Diagnostic>), LoadError> {
+    let mut loader = Loader {
+        sources,
+        loaded: HashSet::new(),
+        statements: Vec::new(),
+        diagnostics: Vec::new(),
+    };
+    if path.is_dir() {
+        let paths = policy_files(path).map_err(|err| LoadError::Io(path.to_path_buf(), err))?;
+        for path in paths {
+            let namespace = namespace_of(&path);
+            loader.load_file(&path, Some(&namespace)).map_err(|err| LoadError::Io(path.clone(), err))?;
+        }
+    } else {
+        loader.load_file(path, None).map_err(|err| LoadError::Io(path.to_path_buf(), err))?;
+    }
+
+    let Loader { statements, diagnostics, .. } = loader;
+    Ok((statements, diagnostics))
+}
+
+/// The `.bus` files of a directory, sorted by name.
+pub fn policy_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "bus"))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn namespace_of(path: &Path) -> String {
+    path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+impl Loader<'_> {
+    /// A file that was already loaded, through another import or an import cycle, is skipped.
+    fn load_file(&mut self, path: &Path, namespace: Option<&str>) -> io::Result<()> {
+        let canonical = fs::canonicalize(path)?;
+        let body = match self.sources.unsaved.get(&canonical) {
+            Some(body) => body.clone(),
+            None => fs::read_to_string(path)?,
+        };
+        if !self.loaded.insert(canonical) {
+            return Ok(());
+        }
+        let base = self.sources.add(path.to_path_buf(), body.clone(), namespace);
+        let mut statements = match parse_at(&body, base) {
+            Ok(statements) => statements,
+            Err(err) => {
+                self.diagnostics.extend(err.diagnostics);
+                return Ok(());
+            }
+        };
+
+        for stmt in &statements {
+            if let Statement::Import(import_stmt) = stmt {
+                let target = path.parent().unwrap_or(Path::new("")).join(&import_stmt.path);
+                let namespace = namespace_of(&target);
+                if let Err(err) = self.load_file(&target, Some(&namespace)) {
+                    self.diagnostics.push(Diagnostic {
+                        message: format!("Could not read `{}`: {}", import_stmt.path, err),
+                        span: import_stmt.span,
+                    });
+                }
+            }
+        }
+
+        if let Some(namespace) = namespace {
+            qualify(&mut statements, namespace);
+        }
+        self.statements.extend(statements);
+        Ok(())
+    }
+}
+
+/// Prefixes the messages and types a file declares with its namespace, and so do references made to
+/// them from the same file. Wildcard rules are left as written.
+fn qualify(statements: &mut [Statement], namespace: &str) {
+    let mut messages: HashSet<String> = HashSet::new();
+    let mut types: HashSet<String> = HashSet::new();
+    for stmt in statements.iter() {
+        match stmt {
+            Statement::Msg(MsgStmt::Broadcast(broadcast_stmt))
+            | Statement::Allow(AllowStmt { schema: Some(broadcast_stmt), .. }) => {
+                messages.insert(broadcast_stmt.msg_name.clone());
+            }
+            Statement::Msg(MsgStmt::Request(req_stmt)) => {
+                messages.insert(req_stmt.msg_name.clone());
+            }
+            Statement::Msg(MsgStmt::Response(res_stmt)) => {
+                messages.insert(res_stmt.msg_name.clone());
+            }
+            Statement::Type(type_stmt) => {
+                types.insert(type_stmt.type_name.clone());
+            }
+            Statement::Alias(alias_stmt) => {
+                types.insert(alias_stmt.alias_name.clone());
+            }
+            Statement::Enum(enum_stmt) => {
+                types.insert(enum_stmt.enum_name.clone());
+            }
+            Statement::Union(union_stmt) => {
+                types.insert(union_stmt.union_name.clone());
+            }
+            _ => {}
+        }
+    }
+
+    let qualified = |name: &str| format!("{}::{}", namespace, name);
+    for stmt in statements.iter_mut() {
+        match stmt {
+            Statement::Msg(MsgStmt::Broadcast(broadcast_stmt)) => {
+                broadcast_stmt.msg_name = qualified(&broadcast_stmt.msg_name);
+                qualify_params(&mut broadcast_stmt.msg_params, namespace, &types);
+            }
+            Statement::Msg(MsgStmt::Request(req_stmt)) => {
+                req_stmt.msg_name = qualified(&req_stmt.msg_name);
+                qualify_params(&mut req_stmt.msg_params, namespace, &types);
+            }
+            Statement::Msg(MsgStmt::Response(res_stmt)) => {
+                res_stmt.msg_name = qualified(&res_stmt.msg_name);
+                qualify_params(&mut res_stmt.msg_params, namespace, &types);
+            }
+            Statement::Allow(allow_stmt) => {
+                if messages.contains(&allow_stmt.msg_name) {
+                    allow_stmt.msg_name = qualified(&allow_stmt.msg_name);
+                }
+                if let Some(broadcast_stmt) = &mut allow_stmt.schema {
+                    broadcast_stmt.msg_name = allow_stmt.msg_name.clone();
+                    qualify_params(&mut broadcast_stmt.msg_params, namespace, &types);
+                }
+            }
+            Statement::Deny(DenyStmt { msg_name, .. }) if messages.contains(msg_name) => *msg_name = qualified(msg_name),
+            Statement::Type(type_stmt) => {
+                type_stmt.type_name = qualified(&type_stmt.type_name);
+                qualify_params(&mut type_stmt.msg_params, namespace, &types);
+            }
+            Statement::Alias(alias_stmt) => {
+                alias_stmt.alias_name = qualified(&alias_stmt.alias_name);
+                qualify_type(&mut alias_stmt.alias_type, namespace, &types);
+            }
+            Statement::Enum(enum_stmt) => enum_stmt.enum_name = qualified(&enum_stmt.enum_name),
+            Statement::Union(union_stmt) => {
+                union_stmt.union_name = qualified(&union_stmt.union_name);
+                qualify_params(&mut union_stmt.variants, namespace, &types);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn qualify_params(params: &mut [MsgParam], namespace: &str, types: &HashSet<String>) {
+    for param in params {
+        qualify_type(&mut param.param_type, namespace, types);
+    }
+}
+
+fn qualify_type(param_type: &mut TypeExpr, namespace: &str, types: &HashSet<String>) {
+    match param_type {
+        TypeExpr::Named(name) if types.contains(name.as_str()) => *name = format!("{}::{}", namespace, name),
+        TypeExpr::List(item) | TypeExpr::Optional(item) | TypeExpr::Constrained(item, _) => {
+            qualify_type(item, namespace, types)
+        }
+        TypeExpr::Map(key, value) => {
+            qualify_type(key, namespace, types);
+            qualify_type(value, namespace, types);
+        }
+        TypeExpr::Object(params) | TypeExpr::Union(params) => qualify_params(params, namespace, types),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, LoadError, Sources};
+    use crate::test_utils::write_files;
+
+    #[test]
+    fn test_load_imports() {
+        let dir = write_files("loader-imports", &[
+            ("main.bus", "import \"./billing.bus\"\nrole backend\nallow backend listen billing::invoice-paid\n"),
+            ("billing.bus", "import \"./main.bus\"\ntype Money {\n    cents: int\n}\nbroadcast invoice-paid {\n    total: Money\n}\nallow billing broadcast invoice-paid\n"),
+        ]);
+        let policy = load(&dir.join("main.bus"), &mut Sources::default()).unwrap();
+        assert!(policy.types.contains_key("billing::Money"));
+        let channels: Vec<&str> = policy.roles["billing"].permissions.iter().map(|allow_stmt| allow_stmt.msg_name.as_str()).collect();
+        assert_eq!(channels, vec!["billing::invoice-paid"]);
+        assert_eq!(policy.roles["backend"].permissions[0].msg_name, "billing::invoice-paid");
+    }
+
+    #[test]
+    fn test_load_directory_conflicts() {
+        let dir = write_files("loader-conflicts", &[
+            ("billing.bus", "role backend\nrequest refund {\n    id: string\n}\n"),
+            ("orders.bus", "role backend\nimport \"./missing.bus\"\n"),
+        ]);
+        let mut sources = Sources::default();
+        let err = match load(&dir, &mut sources) {
+            Err(LoadError::Policy(err)) => err,
+            other => panic!("Expected policy error, got {:?}", other.map(|_| ())),
+        };
+        let rendered = sources.render(&err);
+        assert!(rendered.contains("error: Could not read `./missing.bus`"));
+        assert!(rendered.contains("orders.bus:2:1"));
+
+        let err = match load(&write_files("loader-roles", &[("a.bus", "role backend\n"), ("b.bus", "role backend\n")]), &mut sources) {
+            Err(LoadError::Policy(err)) => err,
+            other => panic!("Expected policy error, got {:?}", other.map(|_| ())),
+        };
+        assert_eq!(err.diagnostics[0].message, "Role `backend` is already declared");
+        assert!(sources.render(&err).contains("b.bus:1:6"));
+    }
+}
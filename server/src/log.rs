@@ -0,0 +1,3 @@
+pub fn log(message: &str) {
+    println!("{}", message);
+}
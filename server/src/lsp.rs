@@ -0,0 +1,714 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use serde_json::{json, Value};
+use crate::lint::lint;
+use crate::loader::{parse_files, Source, Sources};
+use crate::policy::{lex, AllowStmt, Diagnostic, MsgParam, MsgStmt, Span, SpannedToken, Statement, Token, TypeExpr};
+use crate::resolver::resolve;
+use crate::scalars::scalar_types;
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Reads one `Content-Length` framed message, or `None` once the client closes the stream. The body
+/// is read as it arrives rather than allocated up front, so a bogus length can't exhaust memory.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header"))?;
+    let mut body = Vec::new();
+    input.take(length as u64).read_to_end(&mut body)?;
+    if body.len() < length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Message shorter than its Content-Length"));
+    }
+    serde_json::from_slice(&body).map(Some).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Serves the Language Server Protocol over stdin and stdout until the client exits.
+pub fn run(policy: Option<PathBuf>) -> io::Result<()> {
+    let mut server = Server::new(policy);
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut output = io::stdout();
+    while let Some(message) = read_message(&mut input)? {
+        if message["method"] == "exit" {
+            break;
+        }
+        for reply in server.handle(&message) {
+            write_message(&mut output, &reply)?;
+        }
+    }
+    Ok(())
+}
+
+fn path_of(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut index = 0;
+    while index < encoded.len() {
+        let escaped = encoded.get(index + 1..index + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+        match escaped.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) if encoded[index] == b'%' => {
+                bytes.push(byte);
+                index += 3;
+            }
+            _ => {
+                bytes.push(encoded[index]);
+                index += 1;
+            }
+        }
+    }
+    Some(PathBuf::from(String::from_utf8(bytes).ok()?))
+}
+
+fn uri_of(path: &Path) -> String {
+    let path = canonical(path);
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    uri
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Positions count lines from zero and columns in UTF-16 code units.
+fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map(|index| index + 1).unwrap_or(0);
+    json!({
+        "line": before.matches('\n').count(),
+        "character": before[line_start..].encode_utf16().count(),
+    })
+}
+
+fn offset(text: &str, position: &Value) -> usize {
+    let line = position["line"].as_u64().unwrap_or(0) as usize;
+    let character = position["character"].as_u64().unwrap_or(0) as usize;
+    let line_start: usize = text.split_inclusive('\n').take(line).map(str::len).sum();
+    let mut units = 0;
+    for (index, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + index;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn range(text: &str, span: Span) -> Value {
+    json!({ "start": position(text, span.start), "end": position(text, span.end) })
+}
+
+const ROLE: &[&str] = &["role"];
+const TYPES: &[&str] = &["type", "alias", "enum", "union"];
+/// A request and its response share one channel name.
+const REQUEST: &[&str] = &["request", "response"];
+
+/// The kinds of declaration a name starting at `start` may refer to, going by the token before it:
+/// `allow admin` names a role, `request new-user` a request and `amount: Money` a type.
+fn kinds(tokens: &[SpannedToken], start: usize) -> &'static [&'static str] {
+    let mut previous = tokens
+        .iter()
+        .rev()
+        .skip_while(|token| token.span.start >= start)
+        .filter(|token| !matches!(token.token, Token::Whitespace | Token::Comment(_) | Token::DocComment(_)))
+        .map(|token| &token.token);
+    match (previous.next(), previous.next()) {
+        (Some(Token::Role | Token::Extends | Token::Allow), _) => ROLE,
+        (Some(Token::Broadcast | Token::Listen), _) => &["broadcast"],
+        (Some(Token::Request), _) => &["request"],
+        (Some(Token::Response), _) => &["response"],
+        (Some(Token::Identifier(keyword)), _) if keyword == "deny" => ROLE,
+        (Some(Token::Identifier(keyword)), _) if TYPES.contains(&keyword.as_str()) => TYPES,
+        (Some(Token::Colon | Token::LAngle | Token::Comma), _) => TYPES,
+        // `alias Money = decimal`, but not `payload.x == y`.
+        (Some(Token::Equals), before) if !matches!(before, Some(Token::Equals | Token::Bang | Token::LAngle | Token::RAngle)) => TYPES,
+        _ => &[],
+    }
+}
+
+/// The names written in a file, such as `admin`, `orders.created` or `billing::Money`, with the
+/// kinds of declaration each may refer to, leaving out comments, strings and field names.
+fn names(text: &str) -> Vec<(Span, String, &'static [&'static str])> {
+    let tokens: Vec<_> = lex(text, &mut Vec::new());
+    let mut names: Vec<(Span, String, &'static [&'static str])> = Vec::new();
+    for token in &tokens {
+        let part = match &token.token {
+            Token::Identifier(name) => name.as_str(),
+            Token::Dot => ".",
+            Token::DoubleColon => "::",
+            _ => continue,
+        };
+        match names.last_mut() {
+            Some((span, name, _)) if span.end == token.span.start => {
+                span.end = token.span.end;
+                name.push_str(part);
+            }
+            _ => names.push((token.span, part.to_string(), kinds(&tokens, token.span.start))),
+        }
+    }
+    names.retain(|(span, name, _)| {
+        let is_field = tokens
+            .iter()
+            .find(|token| token.span.start >= span.end && token.token != Token::Whitespace)
+            .is_some_and(|token| token.token == Token::Colon);
+        !is_field && !name.starts_with('.') && !name.ends_with('.')
+    });
+    names
+}
+
+fn name_at(text: &str, offset: usize) -> Option<(Span, String, &'static [&'static str])> {
+    names(text).into_iter().find(|(span, _, _)| span.start <= offset && offset <= span.end)
+}
+
+/// What a name can be declared as: a role, a message or a type.
+#[derive(Clone)]
+struct Declaration<'a> {
+    kind: &'static str,
+    name: &'a str,
+    span: Span,
+    doc: Option<&'a str>,
+    /// The declaration as it reads in a policy, shown on hover.
+    signature: String,
+}
+
+fn fields(params: &[MsgParam]) -> String {
+    let fields: String = params
+        .iter()
+        .map(|param| format!("    {}: {}\n", param.param_name, param.param_type))
+        .collect();
+    format!("{{\n{}}}", fields)
+}
+
+fn declarations(statements: &[Statement]) -> Vec<Declaration<'_>> {
+    let mut declarations = Vec::new();
+    for stmt in statements {
+        let (kind, name, span, doc, signature) = match stmt {
+            Statement::Role(role_stmt) => {
+                let extends = role_stmt.extends_role.as_ref().map(|role| format!(" extends {}", role)).unwrap_or_default();
+                ("role", &role_stmt.role_name, role_stmt.span, &role_stmt.doc, extends)
+            }
+            Statement::Msg(MsgStmt::Broadcast(broadcast_stmt))
+            | Statement::Allow(AllowStmt { schema: Some(broadcast_stmt), .. }) => {
+                let params = fields(&broadcast_stmt.msg_params);
+                ("broadcast", &broadcast_stmt.msg_name, broadcast_stmt.span, &broadcast_stmt.doc, format!(" {}", params))
+            }
+            Statement::Msg(MsgStmt::Request(req_stmt)) => {
+                let params = fields(&req_stmt.msg_params);
+                ("request", &req_stmt.msg_name, req_stmt.span, &req_stmt.doc, format!(" {}", params))
+            }
+            Statement::Msg(MsgStmt::Response(res_stmt)) => {
+                let params = fields(&res_stmt.msg_params);
+                ("response", &res_stmt.msg_name, res_stmt.span, &res_stmt.doc, format!(" {}", params))
+            }
+            Statement::Type(type_stmt) => {
+                let params = fields(&type_stmt.msg_params);
+                ("type", &type_stmt.type_name, type_stmt.span, &type_stmt.doc, format!(" {}", params))
+            }
+            Statement::Alias(alias_stmt) => {
+                ("alias", &alias_stmt.alias_name, alias_stmt.span, &alias_stmt.doc, format!(" = {}", alias_stmt.alias_type))
+            }
+            Statement::Enum(enum_stmt) => {
+                let variants = TypeExpr::Enum(enum_stmt.variants.clone()).to_string();
+                let variants = variants.trim_start_matches("enum").to_string();
+                ("enum", &enum_stmt.enum_name, enum_stmt.span, &enum_stmt.doc, variants)
+            }
+            Statement::Union(union_stmt) => {
+                let params = fields(&union_stmt.variants);
+                ("union", &union_stmt.union_name, union_stmt.span, &union_stmt.doc, format!(" {}", params))
+            }
+            _ => continue,
+        };
+        declarations.push(Declaration {
+            kind,
+            name,
+            span,
+            doc: doc.as_deref(),
+            signature: format!("{} {}{}", kind, name, signature),
+        });
+    }
+    declarations
+}
+
+/// A file's own declarations are qualified with its namespace, but written without it.
+fn qualified(source: &Source, name: &str) -> Option<String> {
+    match &source.namespace {
+        Some(namespace) if !name.contains("::") => Some(format!("{}::{}", namespace, name)),
+        _ => None,
+    }
+}
+
+/// How a declaration is written from inside a file.
+fn written(source: &Source, name: &str) -> String {
+    match (&source.namespace, name.split_once("::")) {
+        (Some(namespace), Some((prefix, local))) if namespace == prefix => local.to_string(),
+        _ => name.to_string(),
+    }
+}
+
+/// A policy loaded with the unsaved contents of the open documents. The statements are kept even
+/// when they fail to resolve, so that navigation works while a file is being edited.
+struct Analysis {
+    sources: Sources,
+    statements: Vec<Statement>,
+    errors: Vec<Diagnostic>,
+    warnings: Vec<Diagnostic>,
+}
+
+impl Analysis {
+    fn new(root: &Path, documents: &HashMap<PathBuf, String>) -> Option<Analysis> {
+        let mut sources = Sources::default();
+        for (path, text) in documents {
+            sources.set_unsaved(path, text.clone());
+        }
+        let (statements, errors) = parse_files(root, &mut sources).ok()?;
+        let (errors, warnings) = if errors.is_empty() {
+            match resolve(statements.clone()) {
+                Ok(policy) => {
+                    let report = lint(&policy);
+                    (report.errors, report.warnings)
+                }
+                Err(err) => (err.diagnostics, Vec::new()),
+            }
+        } else {
+            (errors, Vec::new())
+        };
+        Some(Analysis { sources, statements, errors, warnings })
+    }
+
+    fn source(&self, path: &Path) -> Option<&Source> {
+        self.sources.files().iter().find(|source| canonical(&source.path) == path)
+    }
+
+    fn location(&self, span: Span) -> Option<Value> {
+        let source = self.sources.file_at(span.start)?;
+        let local = Span { start: span.start - source.base, end: span.end - source.base };
+        Some(json!({ "uri": uri_of(&source.path), "range": range(&source.body, local) }))
+    }
+
+    /// The declarations of the given kinds that a name written in a file refers to.
+    fn resolve_name(&self, source: &Source, name: &str, kinds: &[&str]) -> Vec<Declaration<'_>> {
+        let declarations = declarations(&self.statements);
+        let candidates = qualified(source, name).into_iter().chain([name.to_string()]);
+        for candidate in candidates {
+            let found: Vec<Declaration> = declarations
+                .iter()
+                .filter(|declaration| declaration.name == candidate && kinds.contains(&declaration.kind))
+                .cloned()
+                .collect();
+            if !found.is_empty() {
+                return found;
+            }
+        }
+        Vec::new()
+    }
+}
+
+pub struct Server {
+    policy: Option<PathBuf>,
+    documents: HashMap<PathBuf, String>,
+    analyses: Vec<Analysis>,
+    published: HashSet<PathBuf>,
+}
+
+fn response(id: &Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+fn error(id: &Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+impl Server {
+    /// Without a policy, each open document is loaded on its own, along with the files it imports.
+    pub fn new(policy: Option<PathBuf>) -> Server {
+        Server { policy, documents: HashMap::new(), analyses: Vec::new(), published: HashSet::new() }
+    }
+
+    /// Answers a request or notification, with the response and any diagnostics to publish.
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let params = &message["params"];
+        let id = &message["id"];
+        let document = params["textDocument"]["uri"].as_str().and_then(path_of).map(|path| canonical(&path));
+        match message["method"].as_str().unwrap_or("") {
+            "initialize" => vec![response(id, json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": [" ", ":"] },
+                    "renameProvider": true,
+                },
+                "serverInfo": { "name": "bus" },
+            }))],
+            "shutdown" => vec![response(id, Value::Null)],
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                let text = params["textDocument"]["text"]
+                    .as_str()
+                    .or_else(|| params["contentChanges"].as_array()?.last()?["text"].as_str());
+                if let (Some(path), Some(text)) = (document, text) {
+                    self.documents.insert(path, text.to_string());
+                }
+                self.analyze()
+            }
+            "textDocument/didClose" => {
+                if let Some(path) = document {
+                    self.documents.remove(&path);
+                }
+                self.analyze()
+            }
+            "textDocument/didSave" => self.analyze(),
+            "textDocument/definition" => vec![response(id, self.definition(document, &params["position"]))],
+            "textDocument/hover" => vec![response(id, self.hover(document, &params["position"]))],
+            "textDocument/completion" => vec![response(id, self.completion(document, &params["position"]))],
+            "textDocument/rename" => {
+                let new_name = params["newName"].as_str().unwrap_or("");
+                match self.rename(document, &params["position"], new_name) {
+                    Ok(edit) => vec![response(id, edit)],
+                    Err(message) => vec![error(id, INVALID_PARAMS, &message)],
+                }
+            }
+            method if !id.is_null() => vec![error(id, METHOD_NOT_FOUND, &format!("Unknown method {}", method))],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Reloads the policy and publishes the diagnostics of every file it spans, clearing those of
+    /// files that no longer have any.
+    fn analyze(&mut self) -> Vec<Value> {
+        let roots: Vec<PathBuf> = match &self.policy {
+            Some(policy) => vec![policy.clone()],
+            None => self.documents.keys().cloned().collect(),
+        };
+        self.analyses = roots.iter().filter_map(|root| Analysis::new(root, &self.documents)).collect();
+
+        let mut diagnostics: HashMap<PathBuf, Vec<Value>> = HashMap::new();
+        for analysis in &self.analyses {
+            let mut found: HashMap<PathBuf, Vec<Value>> = analysis
+                .sources
+                .files()
+                .iter()
+                .map(|source| (canonical(&source.path), Vec::new()))
+                .collect();
+            let errors = analysis.errors.iter().map(|diagnostic| (diagnostic, 1));
+            let warnings = analysis.warnings.iter().map(|diagnostic| (diagnostic, 2));
+            for (diagnostic, severity) in errors.chain(warnings) {
+                if let Some((source, local)) = analysis.sources.locate(diagnostic) {
+                    found.entry(canonical(&source.path)).or_default().push(json!({
+                        "range": range(&source.body, local.span),
+                        "severity": severity,
+                        "source": "bus",
+                        "message": local.message,
+                    }));
+                }
+            }
+            for (path, file_diagnostics) in found {
+                diagnostics.entry(path).or_insert(file_diagnostics);
+            }
+        }
+        for path in self.published.drain() {
+            diagnostics.entry(path).or_default();
+        }
+
+        let mut notifications = Vec::new();
+        for (path, file_diagnostics) in diagnostics {
+            if !file_diagnostics.is_empty() {
+                self.published.insert(path.clone());
+            }
+            notifications.push(json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/publishDiagnostics",
+                "params": { "uri": uri_of(&path), "diagnostics": file_diagnostics },
+            }));
+        }
+        notifications
+    }
+
+    /// The analysis and file a document belongs to, and the position as an offset into it.
+    fn locate(&self, document: Option<PathBuf>, position: &Value) -> Option<(&Analysis, &Source, usize)> {
+        let path = document?;
+        self.analyses.iter().find_map(|analysis| {
+            let source = analysis.source(&path)?;
+            Some((analysis, source, offset(&source.body, position)))
+        })
+    }
+
+    fn definition(&self, document: Option<PathBuf>, position: &Value) -> Value {
+        let Some((analysis, source, offset)) = self.locate(document, position) else { return Value::Null };
+        let Some((_, name, kinds)) = name_at(&source.body, offset) else { return Value::Null };
+        let locations: Vec<Value> = analysis
+            .resolve_name(source, &name, kinds)
+            .iter()
+            .filter_map(|declaration| analysis.location(declaration.span))
+            .collect();
+        json!(locations)
+    }
+
+    fn hover(&self, document: Option<PathBuf>, position: &Value) -> Value {
+        let Some((analysis, source, offset)) = self.locate(document, position) else { return Value::Null };
+        let Some((span, name, kinds)) = name_at(&source.body, offset) else { return Value::Null };
+        let declarations = analysis.resolve_name(source, &name, kinds);
+        if declarations.is_empty() {
+            return Value::Null;
+        }
+        let sections: Vec<String> = declarations
+            .iter()
+            .map(|declaration| {
+                let doc = declaration.doc.map(|doc| format!("\n{}", doc)).unwrap_or_default();
+                format!("```bus\n{}\n```{}", declaration.signature, doc)
+            })
+            .collect();
+        json!({
+            "contents": { "kind": "markdown", "value": sections.join("\n\n---\n\n") },
+            "range": range(&source.body, span),
+        })
+    }
+
+    /// Suggests what fits where the cursor is: roles and channels in rules, types after a field's `:`,
+    /// and statement keywords at the start of a line.
+    fn completion(&self, document: Option<PathBuf>, position: &Value) -> Value {
+        let Some((analysis, source, offset)) = self.locate(document, position) else { return json!([]) };
+        let line_start = source.body[..offset].rfind('\n').map(|index| index + 1).unwrap_or(0);
+        let before = &source.body[line_start..offset];
+        let mut words: Vec<&str> = before.split_whitespace().collect();
+        if !before.ends_with(char::is_whitespace) {
+            words.pop();
+        }
+
+        let declarations = declarations(&analysis.statements);
+        let named = |kinds: &[&str], item_kind: u8| -> Vec<Value> {
+            let mut labels: Vec<String> = declarations
+                .iter()
+                .filter(|declaration| kinds.contains(&declaration.kind))
+                .map(|declaration| written(source, declaration.name))
+                .collect();
+            labels.sort();
+            labels.dedup();
+            labels.into_iter().map(|label| json!({ "label": label, "kind": item_kind })).collect()
+        };
+        let keywords = |keywords: &[&str]| -> Vec<Value> {
+            keywords.iter().map(|keyword| json!({ "label": keyword, "kind": 14 })).collect()
+        };
+
+        let items = match words.as_slice() {
+            [] => keywords(&["allow", "deny", "role", "broadcast", "request", "response", "type", "alias", "enum", "union", "import"]),
+            ["allow" | "deny"] | ["role", _, "extends"] => named(&["role"], 7),
+            ["allow" | "deny", _] => keywords(&["broadcast", "listen", "request", "response"]),
+            ["allow" | "deny", _, "listen" | "broadcast"] => named(&["broadcast"], 23),
+            ["allow" | "deny", _, "request"] => named(&["request"], 23),
+            ["allow" | "deny", _, "response"] => named(&["response"], 23),
+            _ if before.contains(':') || words.first() == Some(&"alias") && before.contains('=') => {
+                let mut items = keywords(&["list", "map"]);
+                items.extend(scalar_types().iter().map(|scalar| json!({ "label": scalar.name, "kind": 22, "detail": scalar.description })));
+                items.extend(named(&["type", "alias", "enum", "union"], 22));
+                items
+            }
+            _ => Vec::new(),
+        };
+        json!(items)
+    }
+
+    /// Renames a role, message or type, along with every reference to it in the files of the policy.
+    /// Names that refer to a declaration of another kind, such as a type named like a role, are kept,
+    /// except that a request and its response are renamed together.
+    fn rename(&self, document: Option<PathBuf>, position: &Value, new_name: &str) -> Result<Value, String> {
+        let (analysis, source, offset) = self.locate(document, position).ok_or("The document is not part of a policy")?;
+        let (_, name, kinds) = name_at(&source.body, offset).ok_or("Nothing to rename here")?;
+        let declarations = analysis.resolve_name(source, &name, kinds);
+        let declaration = declarations.first().ok_or_else(|| format!("`{}` is not declared", name))?;
+        let (kind, target) = (declaration.kind, declaration.name.to_string());
+        let new_local = new_name.rsplit("::").next().unwrap_or(new_name);
+        let valid = !new_local.is_empty()
+            && new_local.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+            && names(new_local).len() == 1;
+        if !valid {
+            return Err(format!("`{}` is not a valid name", new_name));
+        }
+        let renamed = match target.split_once("::") {
+            Some((namespace, _)) => format!("{}::{}", namespace, new_local),
+            None => new_local.to_string(),
+        };
+
+        let mut changes = serde_json::Map::new();
+        for file in analysis.sources.files() {
+            let edits: Vec<Value> = names(&file.body)
+                .into_iter()
+                .filter(|(_, name, kinds)| {
+                    analysis
+                        .resolve_name(file, name, kinds)
+                        .iter()
+                        .any(|declaration| {
+                            let same_kind = declaration.kind == kind || REQUEST.contains(&kind) && REQUEST.contains(&declaration.kind);
+                            same_kind && declaration.name == target
+                        })
+                })
+                .map(|(span, name, _)| {
+                    let new_text = if name == target { written(file, &renamed) } else { new_local.to_string() };
+                    json!({ "range": range(&file.body, span), "newText": new_text })
+                })
+                .collect();
+            if !edits.is_empty() {
+                changes.insert(uri_of(&file.path), json!(edits));
+            }
+        }
+        Ok(json!({ "changes": changes }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{names, offset, position, read_message, uri_of, Server};
+    use serde_json::{json, Value};
+    use crate::test_utils::write_files;
+
+    fn request(server: &mut Server, method: &str, params: Value) -> Value {
+        let replies = server.handle(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }));
+        replies[0]["result"].clone()
+    }
+
+    #[test]
+    fn test_positions() {
+        let text = "role a\nallow é b\n";
+        assert_eq!(position(text, 15), json!({ "line": 1, "character": 7 }));
+        assert_eq!(offset(text, &json!({ "line": 1, "character": 7 })), 15);
+        assert_eq!(offset(text, &json!({ "line": 0, "character": 40 })), 6);
+    }
+
+    #[test]
+    fn test_read_message() {
+        let mut input: &[u8] = b"Content-Length: 2\r\n\r\n{}Content-Length: 18446744073709551615\r\n\r\n{}";
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({})));
+        assert_eq!(read_message(&mut input).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(read_message(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_names() {
+        let names: Vec<String> = names("allow a listen billing::orders.created when payload.x == \"y\" # z\nrequest r {\n  admin: Money\n}")
+            .into_iter()
+            .map(|(_, name, _)| name)
+            .collect();
+        assert_eq!(names, vec!["a", "billing::orders.created", "payload.x", "r", "Money"]);
+    }
+
+    #[test]
+    fn test_language_server() {
+        let dir = write_files("lsp-features", &[
+            ("main.bus", "import \"./billing.bus\"\nrole support\nallow support listen billing::invoice-paid\n"),
+            ("billing.bus", "/// Sent once paid.\nbroadcast invoice-paid {\n    amount: Money\n}\nalias Money = decimal\nallow support broadcast invoice-paid\n"),
+        ]);
+        let main = uri_of(&dir.join("main.bus"));
+        let billing = uri_of(&dir.join("billing.bus"));
+        let mut server = Server::new(Some(dir.join("main.bus")));
+        let published = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": main, "text": "import \"./billing.bus\"\nrole support\nallow support listen billing::invoice-paid\nallow nobody request x\n" } },
+        }));
+        let main_diagnostics = published.iter().find(|notification| notification["params"]["uri"] == main).unwrap();
+        let messages: Vec<&Value> = main_diagnostics["params"]["diagnostics"].as_array().unwrap().iter().map(|diagnostic| &diagnostic["message"]).collect();
+        assert_eq!(messages, vec!["Role `nobody` is not declared", "No request message matches `x`"]);
+
+        let at = |uri: &str, line: u64, character: u64| json!({ "textDocument": { "uri": uri }, "position": { "line": line, "character": character } });
+        assert_eq!(
+            request(&mut server, "textDocument/definition", at(&main, 2, 35)),
+            json!([{ "uri": billing, "range": { "start": { "line": 1, "character": 10 }, "end": { "line": 1, "character": 22 } } }])
+        );
+        assert_eq!(
+            request(&mut server, "textDocument/definition", at(&main, 2, 8))[0]["range"]["start"],
+            json!({ "line": 1, "character": 5 })
+        );
+        assert_eq!(
+            request(&mut server, "textDocument/hover", at(&billing, 5, 26))["contents"]["value"],
+            "```bus\nbroadcast billing::invoice-paid {\n    amount: billing::Money\n}\n```\nSent once paid."
+        );
+
+        let labels = |result: Value| -> Vec<String> {
+            result.as_array().unwrap().iter().map(|item| item["label"].as_str().unwrap().to_string()).collect()
+        };
+        assert_eq!(labels(request(&mut server, "textDocument/completion", at(&main, 2, 6))), vec!["support"]);
+        assert_eq!(labels(request(&mut server, "textDocument/completion", at(&main, 2, 21))), vec!["billing::invoice-paid"]);
+        assert!(labels(request(&mut server, "textDocument/completion", at(&billing, 2, 12))).contains(&"Money".to_string()));
+
+        let mut params = at(&billing, 4, 7);
+        params["newName"] = json!("Amount");
+        let edit = request(&mut server, "textDocument/rename", params);
+        let new_texts = |uri: &str| -> Vec<Value> {
+            edit["changes"][uri].as_array().unwrap().iter().map(|edit| edit["newText"].clone()).collect()
+        };
+        assert_eq!(new_texts(&billing), vec!["Amount", "Amount"]);
+        assert!(edit["changes"].get(&main).is_none());
+
+        let mut params = at(&billing, 1, 12);
+        params["newName"] = json!("invoice-settled");
+        let edit = request(&mut server, "textDocument/rename", params);
+        assert_eq!(edit["changes"][&main][0]["newText"], "billing::invoice-settled");
+        assert_eq!(edit["changes"][&main][0]["range"]["start"], json!({ "line": 2, "character": 21 }));
+        assert_eq!(edit["changes"][&billing].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_rename_keeps_declarations_of_other_kinds() {
+        let text = "role Admin\ntype Admin {\n    id: uuid\n}\nrequest r {\n    by: Admin\n}\nallow Admin request r\n";
+        let dir = write_files("lsp-rename", &[("main.bus", text)]);
+        let main = uri_of(&dir.join("main.bus"));
+        let mut server = Server::new(Some(dir.join("main.bus")));
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": main, "text": text } },
+        }));
+        let mut lines = |line: u64, character: u64| -> Vec<Value> {
+            let params = json!({ "textDocument": { "uri": main }, "position": { "line": line, "character": character }, "newName": "Staff" });
+            let edit = request(&mut server, "textDocument/rename", params);
+            edit["changes"][&main].as_array().unwrap().iter().map(|edit| edit["range"]["start"]["line"].clone()).collect()
+        };
+        assert_eq!(lines(0, 6), vec![0, 7]);
+        assert_eq!(lines(1, 6), vec![1, 5]);
+        assert_eq!(lines(7, 8), vec![0, 7]);
+    }
+
+    #[test]
+    fn test_rename_request_with_its_response() {
+        let text = "role a\nrequest r {}\nresponse r {}\nallow a request r\nallow a response r\nbroadcast r {}\n";
+        let dir = write_files("lsp-rename-request", &[("main.bus", text)]);
+        let main = uri_of(&dir.join("main.bus"));
+        let mut server = Server::new(Some(dir.join("main.bus")));
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": main, "text": text } },
+        }));
+        let params = json!({ "textDocument": { "uri": main }, "position": { "line": 1, "character": 8 }, "newName": "s" });
+        let edit = request(&mut server, "textDocument/rename", params);
+        let lines: Vec<Value> = edit["changes"][&main].as_array().unwrap().iter().map(|edit| edit["range"]["start"]["line"].clone()).collect();
+        assert_eq!(lines, vec![1, 2, 3, 4]);
+    }
+}
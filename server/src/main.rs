@@ -1,98 +1,435 @@
 use futures::{StreamExt, SinkExt};
-use policy::{parse, Statement};
+use policy::{MsgStmt, MsgType, Statement};
 use std::collections::HashMap;
 use std::env;
+use std::path::{Path, PathBuf};
 use warp::{ws::WebSocket};
+use warp::http::StatusCode;
 use warp::Filter;
 mod policy;
+mod loader;
+mod resolver;
+mod lint;
+mod format;
+mod lsp;
+mod diff;
+use resolver::Policy;
+mod scalars;
 mod validator;
-use validator::{message_from_str, Message};
+use validator::{message_from_str, ErrorFrame, Message, MessageError, ParseError, RateLimitedError, UnauthorizedError};
+mod authorizer;
+mod channel;
+mod filter;
+use filter::Caller;
+use authorizer::{authorize_delivery, authorize_message, project};
 mod broker;
+mod rate_limit;
+use rate_limit::RateLimiter;
+mod cli;
+use cli::{parse_args, Command, ServeOptions, USAGE};
+mod tokens;
+mod typegen;
+use tokens::TokenStore;
 use lazy_static::lazy_static;
 use uuid::Uuid;
-use serde_json;
 use std::sync::Arc;
-use std::sync::Mutex;
-use crate::validator::{ResponseMessage, BroadcastMessage};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use crate::validator::{RequestMessage, ResponseMessage, BroadcastMessage};
 mod log;
+#[cfg(test)]
+mod test_utils;
 use log::{log};
 
 
-fn get_policy() -> Vec<Statement> {
-    let args: Vec<String> = env::args().collect();
-    match args.len() {
-        1 => {
-            panic!("Usage: cargo run --bin server <path to policy file>");
+fn get_policy(path: &str) -> Policy {
+    let mut sources = loader::Sources::default();
+    match loader::load(Path::new(path), &mut sources) {
+        Ok(policy) => policy,
+        Err(loader::LoadError::Io(file, err)) => {
+            eprintln!("Could not read policy file {}: {}", file.display(), err);
+            std::process::exit(1);
         }
-        2 => {
-            let path = args[1].clone();
-            let body = std::fs::read_to_string(path).unwrap();
-            return parse(&body);
-        }
-        _ => {
-            panic!("Usage: cargo run --bin server <path to policy file>");
+        Err(loader::LoadError::Policy(err)) => {
+            eprint!("{}", sources.render(&err));
+            std::process::exit(1);
         }
     }
 }
 
 lazy_static! {
-    static ref POLICY: Vec<Statement> = get_policy();
     static ref BROKER: broker::Broker = broker::Broker::new();
 }
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match parse_args(&args) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
+    match command {
+        Command::Serve { policy, tokens, options } => {
+            serve(&policy, PathBuf::from(tokens), options).await
+        }
+        Command::GenerateToken { role, tokens, claims } => generate_token(&role, claims, PathBuf::from(tokens)),
+        Command::GenerateTypes { policy, language } => print!("{}", typegen::generate(&get_policy(&policy), language)),
+        Command::Check { policy, deny_warnings } => std::process::exit(check(&policy, deny_warnings)),
+        Command::Format { policy, check } => format_files(Path::new(&policy), check),
+        Command::Diff { old, new, json } => diff_policies(&old, &new, json),
+        Command::Lsp { policy } => {
+            if let Err(err) = lsp::run(policy.map(PathBuf::from)) {
+                eprintln!("Language server stopped: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Fails when any change is breaking, so that CI can hold back the merge.
+fn diff_policies(old: &str, new: &str, json: bool) {
+    let changes = diff::diff(&get_policy(old), &get_policy(new));
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diff::to_json(&changes)).unwrap());
+    } else if changes.is_empty() {
+        println!("No changes");
+    } else {
+        for change in &changes {
+            let kind = if change.breaking { "breaking" } else { "safe" };
+            println!("{:<9} {}: {}", kind, change.subject, change.description);
+        }
+    }
+    if changes.iter().any(|change| change.breaking) {
+        std::process::exit(1);
+    }
+}
+
+/// Formats a policy file, or every `.bus` file of a directory, in place. With `check`, lists the
+/// files that are not formatted instead, and fails if there are any.
+fn format_files(path: &Path, check: bool) {
+    let paths = if path.is_dir() {
+        loader::policy_files(path)
+    } else {
+        Ok(vec![path.to_path_buf()])
+    };
+    let paths = paths.unwrap_or_else(|err| {
+        eprintln!("Could not read policy directory {}: {}", path.display(), err);
+        std::process::exit(1);
+    });
+    let mut failed = false;
+    for path in paths {
+        let source = match std::fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(err) => {
+                eprintln!("Could not read policy file {}: {}", path.display(), err);
+                failed = true;
+                continue;
+            }
+        };
+        let formatted = match format::format(&source) {
+            Ok(formatted) => formatted,
+            Err(err) => {
+                eprint!("{}", err.render(&path.display().to_string(), &source));
+                failed = true;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", path.display());
+            failed = true;
+        } else if let Err(err) = std::fs::write(&path, formatted) {
+            eprintln!("Could not write policy file {}: {}", path.display(), err);
+            failed = true;
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Prints the problems of a policy and returns the exit code: 1 on errors, and on warnings with
+/// `deny_warnings`.
+fn check(path: &str, deny_warnings: bool) -> i32 {
+    let mut sources = loader::Sources::default();
+    let report = match loader::load(Path::new(path), &mut sources) {
+        Ok(policy) => lint::lint(&policy),
+        Err(loader::LoadError::Io(file, err)) => {
+            eprintln!("Could not read policy file {}: {}", file.display(), err);
+            return 1;
+        }
+        Err(loader::LoadError::Policy(err)) => lint::Report { errors: err.diagnostics, warnings: Vec::new() },
+    };
+    let lint::Report { errors, warnings } = report;
+    if errors.is_empty() && warnings.is_empty() {
+        println!("No problems found");
+        return 0;
+    }
+    let mut rendered = Vec::new();
+    if !errors.is_empty() {
+        rendered.push(sources.render(&policy::PolicyError { diagnostics: errors.clone() }));
+    }
+    if !warnings.is_empty() {
+        rendered.push(sources.render_warnings(&warnings));
+    }
+    print!("{}", rendered.join("\n"));
+    println!("{} error(s), {} warning(s)", errors.len(), warnings.len());
+    if !errors.is_empty() || deny_warnings && !warnings.is_empty() {
+        1
+    } else {
+        0
+    }
+}
+
+fn generate_token(role: &str, claims: validator::Params, tokens: PathBuf) {
+    let token = TokenStore::load(&tokens)
+        .and_then(|mut store| store.generate(role, claims));
+    match token {
+        Ok(token) => println!("{}", token),
+        Err(err) => {
+            eprintln!("Could not write token store {}: {}", tokens.display(), err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn get_token(authorization: Option<String>, query: &HashMap<String, String>) -> Option<String> {
+    if let Some(token) = authorization.as_deref().and_then(|value| value.strip_prefix("Bearer ")) {
+        return Some(token.trim().to_string());
+    }
+    query.get("token").cloned()
+}
+
+fn authenticate(tokens: &Path, token: Option<String>) -> Option<Caller> {
+    let store = match TokenStore::load(tokens) {
+        Ok(store) => store,
+        Err(err) => {
+            log(&format!("Could not read token store {}: {}", tokens.display(), err));
+            return None;
+        }
+    };
+    store.lookup(&token?).map(|entry| Caller::new(&entry.role, &entry.claims))
+}
+
+async fn serve(policy_path: &str, tokens: PathBuf, options: ServeOptions) {
+    let policy = Arc::new(get_policy(policy_path));
     let ws_route = warp::path("ws")
         .and(warp::ws())
-        .map(|ws: warp::ws::Ws| {
-            ws.on_upgrade(handle_websocket)
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<HashMap<String, String>>())
+        .map(move |ws: warp::ws::Ws, authorization: Option<String>, query: HashMap<String, String>| {
+            let caller = match authenticate(&tokens, get_token(authorization, &query)) {
+                Some(caller) => caller,
+                None => {
+                    return Box::new(warp::reply::with_status("Invalid token", StatusCode::UNAUTHORIZED))
+                        as Box<dyn warp::Reply>;
+                }
+            };
+            let policy = Arc::clone(&policy);
+            Box::new(ws.on_upgrade(move |socket| handle_websocket(socket, policy, caller, options)))
         });
-    
+
+    println!("listening http://localhost:3030");
     warp::serve(ws_route).run(([127, 0, 0, 1], 3030)).await;
 }
 
-async fn handle_websocket(ws: WebSocket) {
-    println!("New websocket connection");
-    let (ws_tx, mut ws_rx) = ws.split();
-    let ws_tx = Arc::new(Mutex::new(ws_tx));
+fn send_message(tx: &mpsc::UnboundedSender<warp::ws::Message>, message: &Message) {
+    let json = serde_json::to_string(message).unwrap();
+    let _ = tx.send(warp::ws::Message::text(json));
+}
+
+fn send_error(tx: &mpsc::UnboundedSender<warp::ws::Message>, id: Option<&str>, err: &MessageError) {
+    log(&err.to_string());
+    let json = serde_json::to_string(&ErrorFrame { id, error: err }).unwrap();
+    let _ = tx.send(warp::ws::Message::text(json));
+}
+
+/// Best effort lookup of the frame id, so that even frames that fail to parse can be correlated.
+fn get_frame_id(text: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    value.get("id")?.as_str().map(|id| id.to_string())
+}
+
+/// The channel's timeout from the policy, or the server's. A client may ask for a shorter one but
+/// not hold a responder slot for longer.
+fn get_request_timeout(policy: &[Statement], request: &RequestMessage, default: Duration) -> Duration {
+    let mut maximum = default;
+    for stmt in policy {
+        if let Statement::Msg(MsgStmt::Request(req_stmt)) = stmt {
+            if req_stmt.msg_name == request.channel {
+                if let Some(timeout) = req_stmt.timeout {
+                    maximum = timeout;
+                }
+            }
+        }
+    }
+    match request.timeout_ms {
+        Some(timeout_ms) => Duration::from_millis(timeout_ms).min(maximum),
+        None => maximum,
+    }
+}
+
+async fn handle_websocket(ws: WebSocket, policy: Arc<Policy>, caller: Caller, options: ServeOptions) {
+    let ServeOptions { request_timeout, rate_limit } = options;
+    let role = caller.role();
+    match policy.roles.get(role) {
+        Some(resolved) if resolved.chain.len() > 1 => {
+            println!("New websocket connection with role {} (extends {})", role, resolved.chain[1..].join(", "))
+        }
+        _ => println!("New websocket connection with role {}", role),
+    }
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<warp::ws::Message>();
+    tokio::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            if ws_tx.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
     let uuid = Uuid::new_v4();
+    let mut rate_limiter = RateLimiter::new(rate_limit);
     while let Some(result) = ws_rx.next().await {
-        let message = message_from_str(
-            POLICY.clone(),
-            result.unwrap().to_str().unwrap()
-        ).unwrap();
+        let frame = match result {
+            Ok(frame) => frame,
+            Err(err) => {
+                log(&format!("Websocket error: {}", err));
+                break;
+            }
+        };
+        if frame.is_close() {
+            break;
+        }
+        if frame.is_ping() || frame.is_pong() {
+            continue;
+        }
+        let text = match frame.to_str() {
+            Ok(text) => text,
+            Err(_) => {
+                let err = MessageError::Parse(ParseError {
+                    reason: "expected a text frame".to_string(),
+                });
+                send_error(&tx, None, &err);
+                continue;
+            }
+        };
+        if !rate_limiter.check() {
+            let err = MessageError::RateLimited(RateLimitedError {
+                limit: rate_limiter.limit(),
+            });
+            send_error(&tx, get_frame_id(text).as_deref(), &err);
+            continue;
+        }
+        let message = match message_from_str(&policy, text) {
+            Ok(message) => message,
+            Err(err) => {
+                send_error(&tx, get_frame_id(text).as_deref(), &err);
+                continue;
+            }
+        };
         log(&message.to_string());
+        if let Some(err) = authorize_message(&policy, &caller, &message) {
+            send_error(&tx, message.id(), &err);
+            continue;
+        }
         match message {
             Message::Request(request) => {
-                let ws_tx = Arc::clone(&ws_tx);
-                BROKER.request(uuid, request, Box::new(move |response: ResponseMessage| {
-                    let json = serde_json::to_string(&response.payload).unwrap();
-                    let response = warp::ws::Message::text(json);
-                    let mut ws_tx = ws_tx.lock().unwrap();
-                    ws_tx.send(response);
+                let tx = tx.clone();
+                let id = request.id.clone();
+                let timeout = get_request_timeout(&policy.statements, &request, request_timeout);
+                let policy = Arc::clone(&policy);
+                let caller = caller.clone();
+                BROKER.request(uuid, request, timeout, Arc::new(move |response: Result<ResponseMessage, MessageError>| {
+                    match response {
+                        Ok(mut response) => match project(&policy, &caller, MsgType::Response, &response.channel, &response.payload) {
+                            Some(payload) => {
+                                response.payload = payload;
+                                send_message(&tx, &Message::Response(response));
+                            }
+                            None => {
+                                let err = MessageError::Unauthorized(UnauthorizedError {
+                                    role_name: caller.role().to_string(),
+                                    msg_type: MsgType::Response,
+                                    channel: response.channel,
+                                });
+                                send_error(&tx, id.as_deref(), &err);
+                            }
+                        },
+                        Err(err) => send_error(&tx, id.as_deref(), &err),
+                    }
                 }));
             },
             Message::Response(response) => {
-                let ws_tx = Arc::clone(&ws_tx);
-                BROKER.respond(uuid, response, Arc::new(move |request| {
-                    return ResponseMessage {
-                        channel: request.channel,
-                        payload: HashMap::new(),
-                    };
+                let id = response.id.clone();
+                if let Some(err) = BROKER.respond(uuid, response) {
+                    send_error(&tx, id.as_deref(), &err);
+                }
+            },
+            Message::Handle(event) => {
+                let tx = tx.clone();
+                BROKER.handle(uuid, event, Arc::new(move |request: RequestMessage| {
+                    send_message(&tx, &Message::Request(request));
                 }));
             },
             Message::Broadcast(event) => {
                 BROKER.broadcast(event);
             },
             Message::Listen(event) => {
-                let ws_tx = Arc::clone(&ws_tx);
+                let tx = tx.clone();
+                let policy = Arc::clone(&policy);
+                let caller = caller.clone();
                 BROKER.listen(uuid, event, Arc::new(move |event: BroadcastMessage| {
-                    let json = serde_json::to_string(&event).unwrap();
-                    let event = warp::ws::Message::text(json);
-                    let mut ws_tx = ws_tx.lock().unwrap();
-                    ws_tx.send(event);
+                    if let Some(event) = authorize_delivery(&policy, &caller, &event) {
+                        send_message(&tx, &Message::Broadcast(event));
+                    }
                 }));
             }
         }
     }
-}
\ No newline at end of file
+    BROKER.disconnect(uuid);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, get_request_timeout};
+    use crate::policy::parse;
+    use crate::validator::{Params, RequestMessage};
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn test_request_timeout_is_capped() {
+        let policy = parse("request new-user timeout 5s {}\nrequest delete-user {}").unwrap();
+        let request = |channel: &str, timeout_ms: Option<u64>| RequestMessage {
+            id: None,
+            payload: Params::new(),
+            channel: channel.to_string(),
+            timeout_ms,
+        };
+        let default = Duration::from_secs(30);
+        assert_eq!(get_request_timeout(&policy, &request("new-user", None), default), Duration::from_secs(5));
+        assert_eq!(get_request_timeout(&policy, &request("new-user", Some(1000)), default), Duration::from_secs(1));
+        assert_eq!(get_request_timeout(&policy, &request("new-user", Some(60_000)), default), Duration::from_secs(5));
+        assert_eq!(get_request_timeout(&policy, &request("delete-user", Some(u64::MAX)), default), default);
+    }
+
+    #[test]
+    fn test_check_exit_code() {
+        let path = std::env::temp_dir().join(format!("bus-check-{}.bus", uuid::Uuid::new_v4()));
+        let path_str = path.to_str().unwrap();
+
+        fs::write(&path, "role frontend\nallow frontend request new-user\nallow ghost request new-user\nrequest new-user {\n    id: uuid\n}\nresponse new-user {\n    id: uuid\n}\nallow frontend response new-user\n").unwrap();
+        assert_eq!(check(path_str, false), 1);
+
+        fs::write(&path, "role frontend\nallow frontend request new-user\nrequest new-user {\n    id: uuid\n}\n").unwrap();
+        assert_eq!(check(path_str, false), 0);
+        assert_eq!(check(path_str, true), 1);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,20 +1,106 @@
+use crate::channel;
+use crate::scalars::Decimal;
+use regex::Regex;
+use serde_json::Value;
+use std::time::Duration;
+
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub enum Statement {
     Allow(AllowStmt),
+    Deny(DenyStmt),
+    Import(ImportStmt),
     Msg(MsgStmt),
     Role(RoleStmt),
+    Type(TypeStmt),
+    Alias(AliasStmt),
+    Enum(EnumStmt),
+    Union(UnionStmt),
+}
+
+#[derive(Debug, Clone)]
+pub struct AllowStmt {
+    pub role_name: String,
+    pub msg_type: MsgType,
+    pub msg_name: String,
+    /// `when <expr>`, checked against each message and the caller's token claims.
+    pub filter: Option<Expr>,
+    /// Inline schema of `allow <role> broadcast <channel> { ... }`, which also declares the message.
+    pub schema: Option<BroadcastStmt>,
+    /// `allow <role> listen|response <channel> { a b }`, the only payload fields delivered through the rule.
+    pub fields: Option<Vec<(String, Span)>>,
+    /// Claims in `<channel>/{caller.<claim>}` that the leading instance values of the channel must equal.
+    pub bindings: Vec<(String, Span)>,
+    /// From `allow` to the end of the channel.
+    pub span: Span,
+}
+
+/// `import "./billing.bus"`, resolved relative to the importing file by the loader.
+#[derive(Debug, Clone)]
+pub struct ImportStmt {
+    pub path: String,
+    pub span: Span,
 }
 
+/// `deny <role> <msg_type> <channel> [when <expr>]`, which takes precedence over allow rules of the
+/// same role and of the roles it extends.
 #[derive(Debug, Clone)]
-struct AllowStmt {
-    role_name: String,
-    msg_type: MsgType,
-    msg_name: String,
-    filter_exp: Option<String>,
+pub struct DenyStmt {
+    pub role_name: String,
+    pub msg_type: MsgType,
+    pub msg_name: String,
+    pub filter: Option<Expr>,
+    pub bindings: Vec<(String, Span)>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// How the operands of a comparison are matched, decided by the type checker from their schema
+/// types once the policy is resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Comparison {
+    /// Values only match values of the same JSON type, so `"1"` is not equal to `1`.
+    #[default]
+    Exact,
+    /// A number field is involved, and `decimal` strings such as `"12.50"` compare by value.
+    Numeric,
 }
 
 #[derive(Debug, Clone)]
-enum MsgType {
+pub enum Expr {
+    /// `payload.tenant_id` or `caller.tenant_id`, split on the dots.
+    Path(Vec<String>, Span),
+    Literal(Value, Span),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(CompareOp, Box<Expr>, Box<Expr>, Comparison),
+}
+
+impl Expr {
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Path(_, span) | Expr::Literal(_, span) => *span,
+            Expr::Not(inner) => inner.span(),
+            Expr::And(left, right) | Expr::Or(left, right) | Expr::Compare(_, left, right, _) => Span {
+                start: left.span().start,
+                end: right.span().end,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MsgType {
     Broadcast,
     Listen,
     Request,
@@ -32,35 +118,320 @@ pub enum MsgStmt {
 pub struct BroadcastStmt {
     pub msg_name: String,
     pub msg_params: Vec<MsgParam>,
+    pub doc: Option<String>,
+    /// Fields of `broadcast <channel>/{field}` whose values route each event to a channel instance.
+    pub bound_fields: Vec<(String, Span)>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub struct RequestStmt {
     pub msg_name: String,
     pub msg_params: Vec<MsgParam>,
+    pub timeout: Option<Duration>,
+    pub doc: Option<String>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub struct ResponseStmt {
     pub msg_name: String,
     pub msg_params: Vec<MsgParam>,
+    pub doc: Option<String>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub struct MsgParam {
     pub param_name: String,
-    pub param_type: String,
+    pub param_type: TypeExpr,
+    pub type_span: Span,
+    pub doc: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum TypeExpr {
+    Named(String),
+    List(Box<TypeExpr>),
+    Map(Box<TypeExpr>, Box<TypeExpr>),
+    Object(Vec<MsgParam>),
+    Optional(Box<TypeExpr>),
+    /// Only produced by `enum` and `union` declarations, never written inline.
+    Enum(Vec<String>),
+    Union(Vec<MsgParam>),
+    Constrained(Box<TypeExpr>, Vec<Constraint>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bound {
+    /// Kept exact, and printed as written.
+    pub value: Decimal,
+    pub inclusive: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum Constraint {
+    /// `(1..120)` or `(>=1, <100)`: a length for strings, lists and maps, a value for numbers.
+    Range { min: Option<Bound>, max: Option<Bound> },
+    /// `/^[A-Z0-9-]+$/`, matched anywhere in the string unless anchored.
+    Pattern(Regex),
+}
+
+/// `type Address { ... }`, a named object shape that fields can refer to.
+#[derive(Debug, Clone)]
+pub struct TypeStmt {
+    pub type_name: String,
+    pub msg_params: Vec<MsgParam>,
+    pub doc: Option<String>,
+    pub span: Span,
+}
+
+/// `alias OrderId = uuid`, another name for an existing type.
+#[derive(Debug, Clone)]
+pub struct AliasStmt {
+    pub alias_name: String,
+    pub alias_type: TypeExpr,
+    pub type_span: Span,
+    pub doc: Option<String>,
+    pub span: Span,
+}
+
+/// `enum OrderStatus { pending paid shipped }`, sent on the wire as one of the variant names.
+#[derive(Debug, Clone)]
+pub struct EnumStmt {
+    pub enum_name: String,
+    pub variants: Vec<String>,
+    pub doc: Option<String>,
+    pub span: Span,
+}
+
+/// `union Payment { card: Card, pix: Pix }`, sent on the wire as `{"card": {...}}`.
+#[derive(Debug, Clone)]
+pub struct UnionStmt {
+    pub union_name: String,
+    pub variants: Vec<MsgParam>,
+    pub doc: Option<String>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub struct RoleStmt {
-    role_name: String,
-    extends_role: Option<String>,
+    pub role_name: String,
+    pub extends_role: Option<String>,
+    pub doc: Option<String>,
+    pub span: Span,
+    pub extends_span: Option<Span>,
+}
+
+impl std::fmt::Display for MsgType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MsgType::Broadcast => write!(f, "broadcast"),
+            MsgType::Listen => write!(f, "listen"),
+            MsgType::Request => write!(f, "request"),
+            MsgType::Response => write!(f, "response"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn shift(self, offset: usize) -> Span {
+        Span { start: self.start + offset, end: self.end + offset }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct PolicyError {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Returns the 1-based line and column of a byte offset.
+pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let offset = offset.min(source.len());
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+impl Diagnostic {
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        self.render_as("error", file_name, source)
+    }
+
+    /// Renders with another severity than `error`, such as `warning`.
+    pub fn render_as(&self, severity: &str, file_name: &str, source: &str) -> String {
+        let (line, col) = line_col(source, self.span.start);
+        let text = source.lines().nth(line - 1).unwrap_or("");
+        let width = line.to_string().len();
+        let carets = text.chars()
+            .skip(col - 1)
+            .take(self.span.end.saturating_sub(self.span.start))
+            .count()
+            .max(1);
+        format!(
+            "{}: {}\n{:w$}--> {}:{}:{}\n{:w$} |\n{} | {}\n{:w$} | {}{}\n",
+            severity,
+            self.message,
+            "", file_name, line, col,
+            "",
+            line, text,
+            "", " ".repeat(col - 1), "^".repeat(carets),
+            w = width,
+        )
+    }
+}
+
+impl PolicyError {
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        self.diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.render(file_name, source))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+impl std::fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let messages: Vec<&str> = self.diagnostics.iter().map(|d| d.message.as_str()).collect();
+        write!(f, "{}", messages.join("; "))
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+impl std::fmt::Display for TypeExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TypeExpr::Named(name) => write!(f, "{}", name),
+            TypeExpr::List(item) => write!(f, "list<{}>", item),
+            TypeExpr::Map(key, value) => write!(f, "map<{}, {}>", key, value),
+            TypeExpr::Object(params) => {
+                write!(f, "{{")?;
+                for param in params {
+                    write!(f, " {}: {}", param.param_name, param.param_type)?;
+                }
+                write!(f, " }}")
+            }
+            TypeExpr::Optional(inner) => write!(f, "{}?", inner),
+            TypeExpr::Enum(variants) => write!(f, "enum {{ {} }}", variants.join(" ")),
+            TypeExpr::Union(variants) => {
+                let variants: Vec<String> = variants
+                    .iter()
+                    .map(|variant| format!("{}: {}", variant.param_name, variant.param_type))
+                    .collect();
+                write!(f, "union {{ {} }}", variants.join(", "))
+            }
+            TypeExpr::Constrained(inner, constraints) => {
+                write!(f, "{}", inner)?;
+                for constraint in constraints {
+                    match constraint {
+                        Constraint::Range { .. } => write!(f, "({})", constraint)?,
+                        Constraint::Pattern(_) => write!(f, " {}", constraint)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for CompareOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompareOp::Eq => write!(f, "=="),
+            CompareOp::Ne => write!(f, "!="),
+            CompareOp::Lt => write!(f, "<"),
+            CompareOp::Le => write!(f, "<="),
+            CompareOp::Gt => write!(f, ">"),
+            CompareOp::Ge => write!(f, ">="),
+        }
+    }
+}
+
+/// Writes a condition back in policy syntax, with parentheses where precedence needs them.
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Path(path, _) => write!(f, "{}", path.join(".")),
+            Expr::Literal(Value::String(text), _) => write!(f, "{}", quote(text)),
+            Expr::Literal(value, _) => write!(f, "{}", value),
+            Expr::Not(inner) => match **inner {
+                Expr::Path(..) | Expr::Literal(..) | Expr::Not(_) => write!(f, "not {}", inner),
+                _ => write!(f, "not ({})", inner),
+            },
+            Expr::And(left, right) => {
+                for (index, operand) in [left, right].into_iter().enumerate() {
+                    if index > 0 {
+                        write!(f, " and ")?;
+                    }
+                    match **operand {
+                        Expr::Or(..) => write!(f, "({})", operand)?,
+                        _ => write!(f, "{}", operand)?,
+                    }
+                }
+                Ok(())
+            }
+            Expr::Or(left, right) => write!(f, "{} or {}", left, right),
+            Expr::Compare(op, left, right, _) => {
+                for (index, operand) in [left, right].into_iter().enumerate() {
+                    if index > 0 {
+                        write!(f, " {} ", op)?;
+                    }
+                    match **operand {
+                        Expr::Path(..) | Expr::Literal(..) => write!(f, "{}", operand)?,
+                        _ => write!(f, "({})", operand)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Writes a string in policy syntax, escaping only what the lexer reads back.
+pub fn quote(text: &str) -> String {
+    let escaped = text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n").replace('\t', "\\t");
+    format!("\"{}\"", escaped)
+}
+
+impl std::fmt::Display for Constraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Constraint::Range { min: Some(min), max: Some(max) } if min.inclusive && max.inclusive => {
+                write!(f, "{}..{}", min.value, max.value)
+            }
+            Constraint::Range { min, max } => {
+                let mut bounds = Vec::new();
+                if let Some(min) = min {
+                    bounds.push(format!("{}{}", if min.inclusive { ">=" } else { ">" }, min.value));
+                }
+                if let Some(max) = max {
+                    bounds.push(format!("{}{}", if max.inclusive { "<=" } else { "<" }, max.value));
+                }
+                write!(f, "{}", bounds.join(", "))
+            }
+            Constraint::Pattern(regex) => write!(f, "/{}/", regex.as_str().replace('/', "\\/")),
+        }
+    }
 }
 
 // lexer
 #[derive(Debug, PartialEq)]
-enum Token {
+pub enum Token {
     Allow,
     Broadcast,
     Listen,
@@ -72,38 +443,248 @@ enum Token {
     LBrace,
     RBrace,
     Colon,
+    LAngle,
+    RAngle,
+    Comma,
+    Question,
+    Equals,
+    LParen,
+    RParen,
+    DotDot,
+    Dot,
+    Bang,
+    Star,
+    Slash,
+    DoubleColon,
     Identifier(String),
+    Pattern(String),
+    Str(String),
+    Number(String),
     Whitespace,
+    Comment(String),
+    DocComment(String),
 }
 
-fn lex(input: &str) -> Vec<Token> {
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Allow => write!(f, "`allow`"),
+            Token::Broadcast => write!(f, "`broadcast`"),
+            Token::Listen => write!(f, "`listen`"),
+            Token::Request => write!(f, "`request`"),
+            Token::Response => write!(f, "`response`"),
+            Token::Role => write!(f, "`role`"),
+            Token::Extends => write!(f, "`extends`"),
+            Token::When => write!(f, "`when`"),
+            Token::LBrace => write!(f, "`{{`"),
+            Token::RBrace => write!(f, "`}}`"),
+            Token::Colon => write!(f, "`:`"),
+            Token::LAngle => write!(f, "`<`"),
+            Token::RAngle => write!(f, "`>`"),
+            Token::Comma => write!(f, "`,`"),
+            Token::Question => write!(f, "`?`"),
+            Token::Equals => write!(f, "`=`"),
+            Token::LParen => write!(f, "`(`"),
+            Token::RParen => write!(f, "`)`"),
+            Token::DotDot => write!(f, "`..`"),
+            Token::Dot => write!(f, "`.`"),
+            Token::Bang => write!(f, "`!`"),
+            Token::Star => write!(f, "`*`"),
+            Token::Slash => write!(f, "`/`"),
+            Token::DoubleColon => write!(f, "`::`"),
+            Token::Str(text) => write!(f, "{:?}", text),
+            Token::Pattern(pattern) => write!(f, "`/{}/`", pattern),
+            Token::Identifier(name) => write!(f, "`{}`", name),
+            Token::Number(number) => write!(f, "`{}`", number),
+            Token::Whitespace => write!(f, "whitespace"),
+            Token::Comment(_) => write!(f, "comment"),
+            Token::DocComment(_) => write!(f, "doc comment"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+pub fn lex(input: &str, diagnostics: &mut Vec<Diagnostic>) -> Vec<SpannedToken> {
     let mut tokens = Vec::new();
-    let mut chars = input.chars().peekable();
-    while let Some(c) = chars.next() {
+    let mut chars = input.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let next_is_digit = input[start + c.len_utf8()..].starts_with(|c: char| c.is_ascii_digit());
         let token = match c {
+            '0'..='9' | '-' if c.is_ascii_digit() || next_is_digit => {
+                let mut number = String::new();
+                number.push(c);
+                while let Some(&(i, c)) = chars.peek() {
+                    let is_fraction = c == '.' && input[i + 1..].starts_with(|c: char| c.is_ascii_digit());
+                    if c.is_ascii_alphanumeric() || is_fraction {
+                        number.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                Token::Number(number)
+            }
             'a'..='z' | 'A'..='Z' | '_' | '-' => {
                 let mut identifier = String::new();
                 identifier.push(c);
-                while let Some(&c) = chars.peek() {
+                while let Some(&(_, c)) = chars.peek() {
                     if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
-                        identifier.push(chars.next().unwrap());
+                        identifier.push(c);
+                        chars.next();
                     } else {
                         break;
                     }
                 }
-                recognize_reserved_word(identifier)
+                // Field names after a dot, as in `caller.role`, are never keywords.
+                if matches!(tokens.last(), Some(SpannedToken { token: Token::Dot, .. })) {
+                    Token::Identifier(identifier)
+                } else {
+                    recognize_reserved_word(identifier)
+                }
             }
             ' ' | '\t' | '\r' | '\n' => Token::Whitespace,
+            '#' => Token::Comment(lex_line_comment(c, &mut chars)),
+            '/' if matches!(chars.peek(), Some(&(_, '/'))) => {
+                let comment = lex_line_comment(c, &mut chars);
+                match comment.strip_prefix("///") {
+                    Some(doc) if !doc.starts_with('/') => {
+                        Token::DocComment(doc.strip_prefix(' ').unwrap_or(doc).trim_end().to_string())
+                    }
+                    _ => Token::Comment(comment),
+                }
+            }
+            '/' if matches!(chars.peek(), Some(&(_, '*'))) => {
+                let mut comment = String::from("/*");
+                chars.next();
+                let mut terminated = false;
+                while let Some((_, c)) = chars.next() {
+                    comment.push(c);
+                    if c == '*' && matches!(chars.peek(), Some(&(_, '/'))) {
+                        comment.push('/');
+                        chars.next();
+                        terminated = true;
+                        break;
+                    }
+                }
+                if !terminated {
+                    diagnostics.push(Diagnostic {
+                        message: "Unterminated block comment".to_string(),
+                        span: Span { start, end: start + 2 },
+                    });
+                }
+                Token::Comment(comment)
+            }
+            '/' if matches!(chars.peek(), Some(&(_, '{'))) => Token::Slash,
+            '/' => {
+                let mut pattern = String::new();
+                let mut terminated = false;
+                while let Some(&(_, c)) = chars.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    chars.next();
+                    match c {
+                        '/' => {
+                            terminated = true;
+                            break;
+                        }
+                        '\\' if matches!(chars.peek(), Some(&(_, '/'))) => {
+                            pattern.push('/');
+                            chars.next();
+                        }
+                        _ => pattern.push(c),
+                    }
+                }
+                if !terminated {
+                    diagnostics.push(Diagnostic {
+                        message: "Unterminated pattern".to_string(),
+                        span: Span { start, end: start + 1 },
+                    });
+                }
+                Token::Pattern(pattern)
+            }
+            '.' if matches!(chars.peek(), Some(&(_, '.'))) => {
+                chars.next();
+                Token::DotDot
+            }
+            '.' => Token::Dot,
+            '!' => Token::Bang,
+            '*' => Token::Star,
+            '"' => {
+                let mut text = String::new();
+                let mut terminated = false;
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '"' => {
+                            terminated = true;
+                            break;
+                        }
+                        '\\' => match chars.next() {
+                            Some((_, 'n')) => text.push('\n'),
+                            Some((_, 't')) => text.push('\t'),
+                            Some((_, c)) => text.push(c),
+                            None => break,
+                        },
+                        _ => text.push(c),
+                    }
+                }
+                if !terminated {
+                    diagnostics.push(Diagnostic {
+                        message: "Unterminated string".to_string(),
+                        span: Span { start, end: start + 1 },
+                    });
+                }
+                Token::Str(text)
+            }
+            '(' => Token::LParen,
+            ')' => Token::RParen,
             '{' => Token::LBrace,
             '}' => Token::RBrace,
+            ':' if matches!(chars.peek(), Some(&(_, ':'))) => {
+                chars.next();
+                Token::DoubleColon
+            }
             ':' => Token::Colon,
-            _ => panic!("Unexpected character: {}", c),
+            '<' => Token::LAngle,
+            '>' => Token::RAngle,
+            ',' => Token::Comma,
+            '?' => Token::Question,
+            '=' => Token::Equals,
+            _ => {
+                diagnostics.push(Diagnostic {
+                    message: format!("Unexpected character `{}`", c),
+                    span: Span { start, end: start + c.len_utf8() },
+                });
+                continue;
+            }
         };
-        tokens.push(token);
+        let end = chars.peek().map(|&(i, _)| i).unwrap_or(input.len());
+        tokens.push(SpannedToken {
+            token,
+            span: Span { start, end },
+        });
     }
     tokens
 }
 
+fn lex_line_comment(first: char, chars: &mut std::iter::Peekable<std::str::CharIndices>) -> String {
+    let mut comment = String::new();
+    comment.push(first);
+    while let Some(&(_, c)) = chars.peek() {
+        if c == '\n' {
+            break;
+        }
+        comment.push(c);
+        chars.next();
+    }
+    comment
+}
+
 fn recognize_reserved_word(identifier: String) -> Token {
     match identifier.as_str() {
         "allow" => Token::Allow,
@@ -119,196 +700,670 @@ fn recognize_reserved_word(identifier: String) -> Token {
 }
 
 //parser
+type ParseResult<T> = Result<T, Diagnostic>;
+
 struct Parser {
-    tokens: Vec<Token>,
+    tokens: Vec<SpannedToken>,
     index: usize,
+    end: usize,
 }
 
 impl Parser {
-    fn new(tokens: Vec<Token>) -> Self {
-        Parser { tokens, index: 0 }
+    fn new(tokens: Vec<SpannedToken>, end: usize) -> Self {
+        Parser { tokens, index: 0, end }
     }
 
-    fn parse(&mut self) -> Vec<Statement> {
+    fn parse(&mut self, diagnostics: &mut Vec<Diagnostic>) -> Vec<Statement> {
         let mut statements = Vec::new();
+        self.skip_whitespace();
         while self.index < self.tokens.len() {
-            let statement = self.parse_statement();
-            statements.push(statement);
+            let start = self.index;
+            match self.parse_statement() {
+                Ok(statement) => statements.push(statement),
+                Err(diagnostic) => {
+                    diagnostics.push(diagnostic);
+                    self.recover(start);
+                }
+            }
             self.skip_whitespace();
         }
         statements
     }
 
-    fn parse_statement(&mut self) -> Statement {
+    /// Skips to the next statement keyword so that one mistake doesn't hide the following ones.
+    fn recover(&mut self, start: usize) {
+        if self.index == start {
+            self.index += 1;
+        }
+        while let Some(token) = self.peek() {
+            if matches!(token, Token::Allow | Token::Broadcast | Token::Request | Token::Response | Token::Role)
+                || self.at_import()
+                || self.at_declaration("deny")
+                || self.at_declaration("type")
+                || self.at_declaration("alias")
+                || self.at_declaration("enum")
+                || self.at_declaration("union")
+            {
+                break;
+            }
+            self.index += 1;
+        }
+    }
+
+    /// `deny`, `type`, `alias`, `enum` and `union` are only keywords at the start of a statement, so fields can still be called `type`.
+    fn at_declaration(&self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Identifier(name)) if name == keyword => self.tokens[self.index + 1..]
+                .iter()
+                .find(|token| !matches!(token.token, Token::Whitespace | Token::Comment(_)))
+                .map(|token| matches!(token.token, Token::Identifier(_)))
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    fn at_import(&self) -> bool {
+        match self.peek() {
+            Some(Token::Identifier(name)) if name == "import" => self.tokens[self.index + 1..]
+                .iter()
+                .find(|token| !matches!(token.token, Token::Whitespace | Token::Comment(_)))
+                .map(|token| matches!(token.token, Token::Str(_)))
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    fn parse_import_stmt(&mut self) -> ParseResult<ImportStmt> {
+        let start = self.position();
+        self.expect(Token::Identifier("import".to_string()))?;
         self.skip_whitespace();
-        match &self.tokens[self.index] {
-            Token::Allow => Statement::Allow(self.parse_allow_stmt()),
-            Token::Broadcast | Token::Request | Token::Response => {
-                Statement::Msg(self.parse_msg_stmt())
+
+        match self.peek() {
+            Some(Token::Str(path)) => {
+                let path = path.clone();
+                self.index += 1;
+                Ok(ImportStmt { path, span: Span { start, end: self.last_end() } })
             }
-            Token::Role => Statement::Role(self.parse_role_stmt()),
-            _ => panic!("Unexpected token {:?}", self.tokens[self.index]),
+            _ => Err(self.error("Expected a quoted path")),
+        }
+    }
+
+    fn parse_statement(&mut self) -> ParseResult<Statement> {
+        let doc = self.parse_doc();
+        match self.peek() {
+            Some(Token::Allow) => Ok(Statement::Allow(self.parse_allow_stmt(doc)?)),
+            Some(Token::Broadcast | Token::Request | Token::Response) => {
+                Ok(Statement::Msg(self.parse_msg_stmt(doc)?))
+            }
+            Some(Token::Role) => Ok(Statement::Role(self.parse_role_stmt(doc)?)),
+            _ if self.at_import() => Ok(Statement::Import(self.parse_import_stmt()?)),
+            _ if self.at_declaration("deny") => Ok(Statement::Deny(self.parse_deny_stmt()?)),
+            _ if self.at_declaration("type") => Ok(Statement::Type(self.parse_type_stmt(doc)?)),
+            _ if self.at_declaration("alias") => Ok(Statement::Alias(self.parse_alias_stmt(doc)?)),
+            _ if self.at_declaration("enum") => Ok(Statement::Enum(self.parse_enum_stmt(doc)?)),
+            _ if self.at_declaration("union") => Ok(Statement::Union(self.parse_union_stmt(doc)?)),
+            _ => Err(self.error("Expected a statement")),
+        }
+    }
+
+    /// Collects the `///` lines right before a declaration, skipping the trivia around them.
+    fn parse_doc(&mut self) -> Option<String> {
+        let mut lines = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::DocComment(line)) => {
+                    lines.push(line.clone());
+                    self.index += 1;
+                }
+                Some(Token::Whitespace | Token::Comment(_)) => self.index += 1,
+                _ => break,
+            }
+        }
+        if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
         }
     }
 
-    fn parse_role_stmt(&mut self) -> RoleStmt {
-        self.expect(Token::Role);
+    fn parse_role_stmt(&mut self, doc: Option<String>) -> ParseResult<RoleStmt> {
+        self.expect(Token::Role)?;
         self.skip_whitespace();
 
-        let role_name = self.parse_role_name();
+        let (role_name, span) = self.parse_spanned_identifier("Expected role name")?;
         self.skip_whitespace();
 
-        let extends_role = if self.maybe_expect(Token::Extends) {
+        let (extends_role, extends_span) = if self.maybe_expect(Token::Extends) {
             self.skip_whitespace();
-            let extends_role_name = self.parse_role_name();
+            let (extends_role_name, extends_span) = self.parse_spanned_identifier("Expected role name")?;
             self.skip_whitespace();
-            Some(extends_role_name)
+            (Some(extends_role_name), Some(extends_span))
         } else {
-            None
+            (None, None)
         };
 
-        RoleStmt {
+        Ok(RoleStmt {
             role_name,
             extends_role,
-        }
+            doc,
+            span,
+            extends_span,
+        })
     }
 
-    fn parse_msg_stmt(&mut self) -> MsgStmt {
+    fn parse_msg_stmt(&mut self, doc: Option<String>) -> ParseResult<MsgStmt> {
         self.skip_whitespace();
-        match &self.tokens[self.index] {
-            Token::Broadcast => MsgStmt::Broadcast(self.parse_broadcast_stmt()),
-            Token::Request => MsgStmt::Request(self.parse_request_stmt()),
-            Token::Response => MsgStmt::Response(self.parse_response_stmt()),
-            _ => panic!("Expected message statement"),
+        match self.peek() {
+            Some(Token::Broadcast) => Ok(MsgStmt::Broadcast(self.parse_broadcast_stmt(doc)?)),
+            Some(Token::Request) => Ok(MsgStmt::Request(self.parse_request_stmt(doc)?)),
+            Some(Token::Response) => Ok(MsgStmt::Response(self.parse_response_stmt(doc)?)),
+            _ => Err(self.error("Expected message statement")),
         }
     }
 
-    fn parse_broadcast_stmt(&mut self) -> BroadcastStmt {
-        self.expect(Token::Broadcast);
+    fn parse_broadcast_stmt(&mut self, doc: Option<String>) -> ParseResult<BroadcastStmt> {
+        self.expect(Token::Broadcast)?;
+        self.skip_whitespace();
+
+        let start = self.position();
+        let msg_name = self.parse_msg_name(false)?;
+        let span = Span { start, end: self.last_end() };
+        let bound_fields = self.parse_bound_fields()?;
+        self.skip_whitespace();
+
+        let msg_params = self.parse_msg_params()?;
+
+        Ok(BroadcastStmt { msg_name, msg_params, doc, bound_fields, span })
+    }
+
+    fn parse_request_stmt(&mut self, doc: Option<String>) -> ParseResult<RequestStmt> {
+        self.expect(Token::Request)?;
+        self.skip_whitespace();
+
+        let start = self.position();
+        let msg_name = self.parse_msg_name(false)?;
+        let span = Span { start, end: self.last_end() };
+        self.skip_whitespace();
+
+        let timeout = if self.maybe_expect(Token::Identifier("timeout".to_string())) {
+            self.skip_whitespace();
+
+            let timeout = self.parse_duration()?;
+            self.skip_whitespace();
+
+            Some(timeout)
+        } else {
+            None
+        };
+
+        let msg_params = self.parse_msg_params()?;
+
+        Ok(RequestStmt { msg_name, msg_params, timeout, doc, span })
+    }
+
+    fn parse_response_stmt(&mut self, doc: Option<String>) -> ParseResult<ResponseStmt> {
+        self.expect(Token::Response)?;
+        self.skip_whitespace();
+
+        let start = self.position();
+        let msg_name = self.parse_msg_name(false)?;
+        let span = Span { start, end: self.last_end() };
+        self.skip_whitespace();
+
+        let msg_params = self.parse_msg_params()?;
+
+        Ok(ResponseStmt { msg_name, msg_params, doc, span })
+    }
+
+    fn parse_type_stmt(&mut self, doc: Option<String>) -> ParseResult<TypeStmt> {
+        self.expect(Token::Identifier("type".to_string()))?;
+        self.skip_whitespace();
+
+        let (type_name, span) = self.parse_spanned_identifier("Expected type name")?;
+        self.skip_whitespace();
+
+        let msg_params = self.parse_msg_params()?;
+
+        Ok(TypeStmt { type_name, msg_params, doc, span })
+    }
+
+    fn parse_alias_stmt(&mut self, doc: Option<String>) -> ParseResult<AliasStmt> {
+        self.expect(Token::Identifier("alias".to_string()))?;
         self.skip_whitespace();
 
-        let msg_name = self.parse_msg_name();
+        let (alias_name, span) = self.parse_spanned_identifier("Expected alias name")?;
         self.skip_whitespace();
 
-        let msg_params = self.parse_msg_params();
+        self.expect(Token::Equals)?;
+        self.skip_whitespace();
+
+        let start = self.position();
+        let alias_type = self.parse_param_type()?;
+        let type_span = Span { start, end: self.last_end() };
 
-        BroadcastStmt { msg_name, msg_params }
+        Ok(AliasStmt { alias_name, alias_type, type_span, doc, span })
     }
 
-    fn parse_request_stmt(&mut self) -> RequestStmt {
-        self.expect(Token::Request);
+    fn parse_enum_stmt(&mut self, doc: Option<String>) -> ParseResult<EnumStmt> {
+        self.expect(Token::Identifier("enum".to_string()))?;
+        self.skip_whitespace();
+
+        let (enum_name, span) = self.parse_spanned_identifier("Expected enum name")?;
         self.skip_whitespace();
 
-        let msg_name = self.parse_msg_name();
+        self.expect(Token::LBrace)?;
         self.skip_whitespace();
 
-        let msg_params = self.parse_msg_params();
+        let mut variants: Vec<String> = Vec::new();
+        while !self.maybe_expect(Token::RBrace) {
+            let (variant, variant_span) = self.parse_spanned_identifier("Expected enum variant")?;
+            if variants.contains(&variant) {
+                return Err(Diagnostic {
+                    message: format!("Variant `{}` is already declared", variant),
+                    span: variant_span,
+                });
+            }
+            variants.push(variant);
+            self.skip_whitespace();
+            self.maybe_expect(Token::Comma);
+            self.skip_whitespace();
+        }
 
-        RequestStmt { msg_name, msg_params }
+        Ok(EnumStmt { enum_name, variants, doc, span })
     }
 
-    fn parse_response_stmt(&mut self) -> ResponseStmt {
-        self.expect(Token::Response);
+    fn parse_union_stmt(&mut self, doc: Option<String>) -> ParseResult<UnionStmt> {
+        self.expect(Token::Identifier("union".to_string()))?;
         self.skip_whitespace();
 
-        let msg_name = self.parse_msg_name();
+        let (union_name, span) = self.parse_spanned_identifier("Expected union name")?;
         self.skip_whitespace();
 
-        let msg_params = self.parse_msg_params();
+        self.expect(Token::LBrace)?;
+        self.skip_whitespace();
 
-        ResponseStmt { msg_name, msg_params }
+        let mut variants: Vec<MsgParam> = Vec::new();
+        loop {
+            let doc = self.parse_doc();
+            if self.maybe_expect(Token::RBrace) {
+                break;
+            }
+            let start = self.position();
+            let variant = self.parse_msg_param(doc)?;
+            if variants.iter().any(|other| other.param_name == variant.param_name) {
+                return Err(Diagnostic {
+                    message: format!("Variant `{}` is already declared", variant.param_name),
+                    span: Span { start, end: start + variant.param_name.len() },
+                });
+            }
+            variants.push(variant);
+            self.skip_whitespace();
+            self.maybe_expect(Token::Comma);
+            self.skip_whitespace();
+        }
+
+        Ok(UnionStmt { union_name, variants, doc, span })
     }
 
-    fn parse_msg_params(&mut self) -> Vec<MsgParam> {
-        self.expect(Token::LBrace);
+    fn parse_msg_params(&mut self) -> ParseResult<Vec<MsgParam>> {
+        self.expect(Token::LBrace)?;
         self.skip_whitespace();
 
         let mut msg_params = Vec::new();
-        while self.tokens[self.index] != Token::RBrace {
-            let msg_param = self.parse_msg_param();
+        loop {
+            let doc = self.parse_doc();
+            if self.maybe_expect(Token::RBrace) {
+                break;
+            }
+            let msg_param = self.parse_msg_param(doc)?;
             self.skip_whitespace();
             msg_params.push(msg_param);
         }
 
-        self.expect(Token::RBrace);
-
-        msg_params
+        Ok(msg_params)
     }
 
-    fn parse_msg_param(&mut self) -> MsgParam {
-        let param_name = self.parse_param_name();
+    fn parse_msg_param(&mut self, doc: Option<String>) -> ParseResult<MsgParam> {
+        let param_name = self.parse_param_name()?;
         self.skip_whitespace();
 
-        self.expect(Token::Colon);
+        self.expect(Token::Colon)?;
         self.skip_whitespace();
 
-        let param_type = self.parse_param_type();
+        let start = self.position();
+        let param_type = self.parse_param_type()?;
+        let type_span = Span { start, end: self.last_end() };
 
-        MsgParam {
+        Ok(MsgParam {
             param_name,
             param_type,
+            type_span,
+            doc,
+        })
+    }
+
+    fn parse_param_name(&mut self) -> ParseResult<String> {
+        self.parse_identifier("Expected parameter name")
+    }
+
+    fn parse_param_type(&mut self) -> ParseResult<TypeExpr> {
+        let param_type = match self.peek() {
+            Some(Token::LBrace) => TypeExpr::Object(self.parse_msg_params()?),
+            Some(Token::Identifier(name)) if name == "list" => {
+                self.index += 1;
+                self.expect(Token::LAngle)?;
+                self.skip_whitespace();
+                let item = self.parse_param_type()?;
+                self.skip_whitespace();
+                self.expect(Token::RAngle)?;
+                TypeExpr::List(Box::new(item))
+            }
+            Some(Token::Identifier(name)) if name == "map" => {
+                self.index += 1;
+                self.expect(Token::LAngle)?;
+                self.skip_whitespace();
+                let key = self.parse_param_type()?;
+                self.skip_whitespace();
+                self.expect(Token::Comma)?;
+                self.skip_whitespace();
+                let value = self.parse_param_type()?;
+                self.skip_whitespace();
+                self.expect(Token::RAngle)?;
+                TypeExpr::Map(Box::new(key), Box::new(value))
+            }
+            _ => TypeExpr::Named(self.parse_qualified_identifier("Expected parameter type")?),
+        };
+        let param_type = self.parse_constraints(param_type)?;
+
+        if self.maybe_expect(Token::Question) {
+            Ok(TypeExpr::Optional(Box::new(param_type)))
+        } else {
+            Ok(param_type)
         }
     }
 
-    fn parse_param_name(&mut self) -> String {
-        if let Token::Identifier(name) = &self.tokens[self.index] {
-            self.index += 1;
-            name.clone()
+    /// Parses the `(1..120)` range and `/pattern/` that may follow a type.
+    fn parse_constraints(&mut self, param_type: TypeExpr) -> ParseResult<TypeExpr> {
+        let mut constraints = Vec::new();
+        if self.maybe_expect(Token::LParen) {
+            self.skip_whitespace();
+            constraints.push(self.parse_range()?);
+            self.skip_whitespace();
+            self.expect(Token::RParen)?;
+        }
+
+        let start = self.index;
+        self.skip_whitespace();
+        match self.tokens.get(self.index) {
+            Some(SpannedToken { token: Token::Pattern(pattern), span }) => match Regex::new(pattern) {
+                Ok(regex) => {
+                    constraints.push(Constraint::Pattern(regex));
+                    self.index += 1;
+                }
+                Err(err) => {
+                    return Err(Diagnostic {
+                        message: format!("Invalid pattern: {}", err.to_string().lines().last().unwrap_or("")),
+                        span: *span,
+                    });
+                }
+            },
+            _ => self.index = start,
+        }
+
+        if constraints.is_empty() {
+            Ok(param_type)
         } else {
-            panic!("Expected parameter name");
+            Ok(TypeExpr::Constrained(Box::new(param_type), constraints))
         }
     }
 
-    fn parse_param_type(&mut self) -> String {
-        if let Token::Identifier(ty) = &self.tokens[self.index] {
-            self.index += 1;
-            ty.clone()
+    /// `a..b` (both ends included), `a..`, `..b`, a single `a`, or comparisons such as `>=1, <100`.
+    fn parse_range(&mut self) -> ParseResult<Constraint> {
+        let mut min = None;
+        let mut max = None;
+        if self.maybe_expect(Token::DotDot) {
+            self.skip_whitespace();
+            max = Some(Bound { value: self.parse_number()?, inclusive: true });
+        } else if let Some(Token::Number(_)) = self.peek() {
+            let value = self.parse_number()?;
+            self.skip_whitespace();
+            min = Some(Bound { value, inclusive: true });
+            if self.maybe_expect(Token::DotDot) {
+                self.skip_whitespace();
+                if let Some(Token::Number(_)) = self.peek() {
+                    max = Some(Bound { value: self.parse_number()?, inclusive: true });
+                }
+            } else {
+                max = min.clone();
+            }
         } else {
-            panic!("Expected parameter type");
+            loop {
+                let (is_min, inclusive) = match self.peek() {
+                    Some(Token::RAngle) => (true, false),
+                    Some(Token::LAngle) => (false, false),
+                    _ => return Err(self.error("Expected a range such as `1..120` or `>=1`")),
+                };
+                self.index += 1;
+                let inclusive = inclusive || self.maybe_expect(Token::Equals);
+                self.skip_whitespace();
+                let bound = Some(Bound { value: self.parse_number()?, inclusive });
+                if is_min {
+                    min = bound;
+                } else {
+                    max = bound;
+                }
+                self.skip_whitespace();
+                if !self.maybe_expect(Token::Comma) {
+                    break;
+                }
+                self.skip_whitespace();
+            }
         }
+        Ok(Constraint::Range { min, max })
     }
 
-    fn parse_allow_stmt(&mut self) -> AllowStmt {
-        self.expect(Token::Allow);
-        self.skip_whitespace();
+    fn parse_number(&mut self) -> ParseResult<Decimal> {
+        match self.peek() {
+            Some(Token::Number(number)) => match Decimal::parse(number) {
+                Some(value) => {
+                    self.index += 1;
+                    Ok(value)
+                }
+                None => Err(self.error("Expected a number")),
+            },
+            _ => Err(self.error("Expected a number")),
+        }
+    }
 
-        let role_name = self.parse_role_name();
+    fn parse_allow_stmt(&mut self, doc: Option<String>) -> ParseResult<AllowStmt> {
+        let rule_start = self.position();
+        self.expect(Token::Allow)?;
         self.skip_whitespace();
 
-        let msg_type = self.parse_msg_type();
+        let role_name = self.parse_role_name()?;
         self.skip_whitespace();
 
-        let msg_name = self.parse_msg_name();
+        let msg_type = self.parse_msg_type()?;
         self.skip_whitespace();
 
-        let filter_exp = if self.maybe_expect(Token::When) {
-            self.skip_whitespace();
+        let start = self.position();
+        let msg_name = self.parse_msg_name(true)?;
+        let span = Span { start, end: self.last_end() };
+        let instance = self.parse_instance()?;
+        let rule_span = Span { start: rule_start, end: self.last_end() };
+        self.skip_whitespace();
 
-            let filter_exp = self.parse_filter_exp();
-            self.skip_whitespace();
+        let filter = self.parse_when()?;
 
-            Some(filter_exp)
-        } else {
-            None
+        let (schema, fields) = match (self.peek(), msg_type) {
+            (Some(Token::LBrace), MsgType::Broadcast) => {
+                if channel::is_pattern(&msg_name) || msg_name.contains("::") {
+                    return Err(Diagnostic {
+                        message: "Inline schemas can only be declared on plain channel names".to_string(),
+                        span,
+                    });
+                }
+                let msg_params = self.parse_msg_params()?;
+                let schema = BroadcastStmt {
+                    msg_name: msg_name.clone(),
+                    msg_params,
+                    doc,
+                    bound_fields: bound_fields(instance)?,
+                    span,
+                };
+                return Ok(AllowStmt {
+                    role_name,
+                    msg_type,
+                    msg_name,
+                    filter,
+                    schema: Some(schema),
+                    fields: None,
+                    bindings: Vec::new(),
+                    span: rule_span,
+                });
+            }
+            (Some(Token::LBrace), MsgType::Listen | MsgType::Response) => (None, Some(self.parse_fields()?)),
+            (Some(Token::LBrace), MsgType::Request) => {
+                return Err(self.error("Inline schemas are only allowed on broadcast rules, and field lists on listen and response rules"));
+            }
+            _ => (None, None),
         };
 
-        AllowStmt {
+        Ok(AllowStmt {
             role_name,
             msg_type,
             msg_name,
-            filter_exp,
+            filter,
+            schema,
+            fields,
+            bindings: bindings(instance)?,
+            span: rule_span,
+        })
+    }
+
+    fn parse_deny_stmt(&mut self) -> ParseResult<DenyStmt> {
+        let start = self.position();
+        self.expect(Token::Identifier("deny".to_string()))?;
+        self.skip_whitespace();
+
+        let role_name = self.parse_role_name()?;
+        self.skip_whitespace();
+
+        let msg_type = self.parse_msg_type()?;
+        self.skip_whitespace();
+
+        let msg_name = self.parse_msg_name(true)?;
+        let instance = self.parse_instance()?;
+        let span = Span { start, end: self.last_end() };
+        self.skip_whitespace();
+
+        let filter = self.parse_when()?;
+
+        Ok(DenyStmt {
+            role_name,
+            msg_type,
+            msg_name,
+            filter,
+            bindings: bindings(instance)?,
+            span,
+        })
+    }
+
+    /// The `/{...}` parameters right after a channel name, as dotted paths.
+    fn parse_instance(&mut self) -> ParseResult<Vec<(Vec<String>, Span)>> {
+        let mut params = Vec::new();
+        while self.maybe_expect(Token::Slash) {
+            let start = self.position();
+            self.expect(Token::LBrace)?;
+            let mut path = vec![self.parse_identifier("Expected channel parameter")?];
+            while self.maybe_expect(Token::Dot) {
+                path.push(self.parse_identifier("Expected channel parameter")?);
+            }
+            self.expect(Token::RBrace)?;
+            params.push((path, Span { start, end: self.last_end() }));
         }
+        Ok(params)
+    }
+
+    fn parse_bound_fields(&mut self) -> ParseResult<Vec<(String, Span)>> {
+        let instance = self.parse_instance()?;
+        bound_fields(instance)
     }
 
-    fn expect(&mut self, expected: Token) {
-        if self.index >= self.tokens.len() || self.tokens[self.index] != expected {
-            panic!("Expected {:?}, found {:?}", expected, self.tokens.get(self.index));
+    fn parse_when(&mut self) -> ParseResult<Option<Expr>> {
+        if !self.maybe_expect(Token::When) {
+            return Ok(None);
+        }
+        self.skip_whitespace();
+
+        let filter = self.parse_filter_exp()?;
+        self.skip_whitespace();
+
+        Ok(Some(filter))
+    }
+
+    fn parse_fields(&mut self) -> ParseResult<Vec<(String, Span)>> {
+        self.expect(Token::LBrace)?;
+        self.skip_whitespace();
+
+        let mut fields: Vec<(String, Span)> = Vec::new();
+        while !self.maybe_expect(Token::RBrace) {
+            let (field, span) = self.parse_spanned_identifier("Expected field name")?;
+            self.skip_whitespace();
+            if self.peek() == Some(&Token::Colon) {
+                return Err(self.error("Inline schemas are only allowed on broadcast rules"));
+            }
+            if fields.iter().any(|(listed, _)| *listed == field) {
+                return Err(Diagnostic {
+                    message: format!("Field `{}` is already listed", field),
+                    span,
+                });
+            }
+            fields.push((field, span));
+            self.skip_whitespace();
+            self.maybe_expect(Token::Comma);
+            self.skip_whitespace();
+        }
+
+        Ok(fields)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|token| &token.token)
+    }
+
+    /// Start of the current token, used to build spans of multi-token constructs.
+    fn position(&self) -> usize {
+        self.tokens.get(self.index).map(|token| token.span.start).unwrap_or(self.end)
+    }
+
+    fn last_end(&self) -> usize {
+        self.tokens[..self.index].last().map(|token| token.span.end).unwrap_or(0)
+    }
+
+    fn error(&self, message: &str) -> Diagnostic {
+        match self.tokens.get(self.index) {
+            Some(token) => Diagnostic {
+                message: format!("{}, found {}", message, token.token),
+                span: token.span,
+            },
+            None => Diagnostic {
+                message: format!("{}, found end of file", message),
+                span: Span { start: self.end, end: self.end },
+            },
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> ParseResult<()> {
+        if self.peek() != Some(&expected) {
+            return Err(self.error(&format!("Expected {}", expected)));
         }
         self.index += 1;
+        Ok(())
     }
 
     fn maybe_expect(&mut self, expected: Token) -> bool {
-        if self.index < self.tokens.len() && self.tokens[self.index] == expected {
+        if self.peek() == Some(&expected) {
             self.index += 1;
             true
         } else {
@@ -316,68 +1371,421 @@ impl Parser {
         }
     }
 
-    fn parse_role_name(&mut self) -> String {
-        if let Token::Identifier(name) = &self.tokens[self.index] {
+    fn parse_identifier(&mut self, message: &str) -> ParseResult<String> {
+        if let Some(Token::Identifier(name)) = self.peek() {
+            let name = name.clone();
             self.index += 1;
-            name.clone()
+            Ok(name)
         } else {
-            panic!("Expected role name");
+            Err(self.error(message))
         }
     }
 
-    fn parse_msg_type(&mut self) -> MsgType {
-        match &self.tokens[self.index] {
-            Token::Broadcast => {
-                self.index += 1;
-                MsgType::Broadcast
+    /// A name, optionally prefixed with the namespace of the file that declares it.
+    fn parse_qualified_identifier(&mut self, message: &str) -> ParseResult<String> {
+        let name = self.parse_identifier(message)?;
+        if !self.maybe_expect(Token::DoubleColon) {
+            return Ok(name);
+        }
+        Ok(format!("{}::{}", name, self.parse_identifier(message)?))
+    }
+
+    fn parse_spanned_identifier(&mut self, message: &str) -> ParseResult<(String, Span)> {
+        let start = self.position();
+        let name = self.parse_identifier(message)?;
+        Ok((name, Span { start, end: self.last_end() }))
+    }
+
+    fn parse_role_name(&mut self) -> ParseResult<String> {
+        self.parse_identifier("Expected role name")
+    }
+
+    fn parse_msg_type(&mut self) -> ParseResult<MsgType> {
+        let msg_type = match self.peek() {
+            Some(Token::Broadcast) => MsgType::Broadcast,
+            Some(Token::Listen) => MsgType::Listen,
+            Some(Token::Request) => MsgType::Request,
+            Some(Token::Response) => MsgType::Response,
+            _ => return Err(self.error("Expected message type")),
+        };
+        self.index += 1;
+        Ok(msg_type)
+    }
+
+    /// Dot-separated segments. Rules may also use `*` and a trailing `>`, and name a channel of
+    /// another file as `namespace::channel`.
+    fn parse_msg_name(&mut self, rule: bool) -> ParseResult<String> {
+        let start = self.position();
+        let mut segments = Vec::new();
+        loop {
+            let segment = match self.peek() {
+                Some(Token::Star) if rule => "*".to_string(),
+                Some(Token::RAngle) if rule => ">".to_string(),
+                Some(Token::Star | Token::RAngle) => {
+                    return Err(self.error("Wildcards are only allowed in allow and deny rules"));
+                }
+                Some(Token::Identifier(_)) if rule && segments.is_empty() => {
+                    segments.push(self.parse_qualified_identifier("Expected message name")?);
+                    if !self.maybe_expect(Token::Dot) {
+                        break;
+                    }
+                    continue;
+                }
+                Some(Token::Identifier(name)) => name.clone(),
+                _ => return Err(self.error("Expected message name")),
+            };
+            self.index += 1;
+            segments.push(segment);
+            if !self.maybe_expect(Token::Dot) {
+                break;
             }
-            Token::Listen => {
-                self.index += 1;
-                MsgType::Listen
+        }
+        if segments[..segments.len() - 1].iter().any(|segment| segment == ">") {
+            return Err(Diagnostic {
+                message: "`>` must be the last segment of a channel".to_string(),
+                span: Span { start, end: self.last_end() },
+            });
+        }
+        Ok(segments.join("."))
+    }
+
+    /// `or` binds loosest, then `and`, then `not`, then comparisons.
+    fn parse_filter_exp(&mut self) -> ParseResult<Expr> {
+        let mut left = self.parse_and_exp()?;
+        while self.maybe_expect_word("or") {
+            self.skip_whitespace();
+            left = Expr::Or(Box::new(left), Box::new(self.parse_and_exp()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_and_exp(&mut self) -> ParseResult<Expr> {
+        let mut left = self.parse_not_exp()?;
+        while self.maybe_expect_word("and") {
+            self.skip_whitespace();
+            left = Expr::And(Box::new(left), Box::new(self.parse_not_exp()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_not_exp(&mut self) -> ParseResult<Expr> {
+        if self.maybe_expect(Token::Identifier("not".to_string())) {
+            self.skip_whitespace();
+            return Ok(Expr::Not(Box::new(self.parse_not_exp()?)));
+        }
+        let left = self.parse_operand()?;
+        let start = self.index;
+        self.skip_whitespace();
+        let op = match self.peek() {
+            Some(Token::Equals) => CompareOp::Eq,
+            Some(Token::Bang) => CompareOp::Ne,
+            Some(Token::LAngle) => CompareOp::Lt,
+            Some(Token::RAngle) => CompareOp::Gt,
+            _ => {
+                self.index = start;
+                return Ok(left);
             }
-            Token::Request => {
+        };
+        self.index += 1;
+        let op = match (op, self.maybe_expect(Token::Equals)) {
+            (CompareOp::Lt, true) => CompareOp::Le,
+            (CompareOp::Gt, true) => CompareOp::Ge,
+            (CompareOp::Eq | CompareOp::Ne, false) => return Err(self.error("Expected `=`")),
+            (op, _) => op,
+        };
+        self.skip_whitespace();
+        let right = self.parse_operand()?;
+        Ok(Expr::Compare(op, Box::new(left), Box::new(right), Comparison::default()))
+    }
+
+    fn parse_operand(&mut self) -> ParseResult<Expr> {
+        let start = self.position();
+        let literal = match self.peek() {
+            Some(Token::LParen) => {
                 self.index += 1;
-                MsgType::Request
+                self.skip_whitespace();
+                let inner = self.parse_filter_exp()?;
+                self.skip_whitespace();
+                self.expect(Token::RParen)?;
+                return Ok(inner);
             }
-            Token::Response => {
-                self.index += 1;
-                MsgType::Response
+            Some(Token::Str(text)) => Value::String(text.clone()),
+            Some(Token::Number(number)) => match serde_json::from_str(number) {
+                Ok(number) => Value::Number(number),
+                Err(_) => return Err(self.error("Expected a number")),
+            },
+            Some(Token::Identifier(word)) if word == "true" || word == "false" => Value::Bool(word == "true"),
+            Some(Token::Identifier(word)) if word == "null" => Value::Null,
+            Some(Token::Identifier(_)) => {
+                let mut path = vec![self.parse_identifier("Expected a field")?];
+                while self.maybe_expect(Token::Dot) {
+                    path.push(self.parse_identifier("Expected a field name")?);
+                }
+                return Ok(Expr::Path(path, Span { start, end: self.last_end() }));
             }
-            _ => panic!("Expected message type"),
-        }
+            _ => return Err(self.error("Expected a condition such as `payload.amount < 1000`")),
+        };
+        self.index += 1;
+        Ok(Expr::Literal(literal, Span { start, end: self.last_end() }))
     }
 
-    fn parse_msg_name(&mut self) -> String {
-        if let Token::Identifier(name) = &self.tokens[self.index] {
-            self.index += 1;
-            name.clone()
+    /// Matches a contextual keyword such as `and`, skipping the whitespace before it only if it is there.
+    fn maybe_expect_word(&mut self, word: &str) -> bool {
+        let start = self.index;
+        self.skip_whitespace();
+        if self.maybe_expect(Token::Identifier(word.to_string())) {
+            self.skip_whitespace();
+            true
         } else {
-            panic!("Expected message name");
+            self.index = start;
+            false
         }
     }
 
-    fn parse_filter_exp(&mut self) -> String {
-        if let Token::Identifier(exp) = &self.tokens[self.index] {
-            self.index += 1;
-            exp.clone()
+    fn parse_duration(&mut self) -> ParseResult<Duration> {
+        if let Some(Token::Number(duration)) = self.peek() {
+            match parse_duration(duration) {
+                Some(duration) => {
+                    self.index += 1;
+                    Ok(duration)
+                }
+                None => Err(self.error("Expected a duration such as `500ms`, `5s` or `1m`")),
+            }
         } else {
-            panic!("Expected filter expression");
+            Err(self.error("Expected duration"))
         }
     }
 
-
-
+    /// Skips whitespace and plain comments; doc comments are left for `parse_doc` to attach.
     fn skip_whitespace(&mut self) {
-        while self.index < self.tokens.len() && matches!(self.tokens[self.index], Token::Whitespace) {
+        while matches!(self.peek(), Some(Token::Whitespace | Token::Comment(_))) {
             self.index += 1;
         }
     }
 }
 
-pub fn parse(input: &str) -> Vec<Statement> {
-    let tokens = lex(input);
-    let mut parser = Parser::new(tokens);
-    let statements = parser.parse();
-    println!("{:?}", statements);
-    statements
-}
\ No newline at end of file
+/// Parses durations such as `500ms`, `5s` or `1m`; a bare number is read as milliseconds. Minutes
+/// too many to count in seconds are rejected like any other invalid duration.
+pub fn parse_duration(input: &str) -> Option<Duration> {
+    let split = input.find(|c: char| !c.is_ascii_digit()).unwrap_or(input.len());
+    let value: u64 = input[..split].parse().ok()?;
+    match &input[split..] {
+        "" | "ms" => Some(Duration::from_millis(value)),
+        "s" => Some(Duration::from_secs(value)),
+        "m" => value.checked_mul(60).map(Duration::from_secs),
+        _ => None,
+    }
+}
+
+/// Declarations name the payload fields of each channel parameter, as in `user-updated/{user_id}`.
+fn bound_fields(instance: Vec<(Vec<String>, Span)>) -> ParseResult<Vec<(String, Span)>> {
+    let mut fields: Vec<(String, Span)> = Vec::new();
+    for (path, span) in instance {
+        if path.len() != 1 {
+            return Err(Diagnostic {
+                message: "Channel parameters of a message name one of its fields, as in `{user_id}`".to_string(),
+                span,
+            });
+        }
+        if fields.iter().any(|(field, _)| *field == path[0]) {
+            return Err(Diagnostic {
+                message: format!("Field `{}` is already bound", path[0]),
+                span,
+            });
+        }
+        fields.push((path[0].clone(), span));
+    }
+    Ok(fields)
+}
+
+/// Rules bind channel parameters to claims of the caller, as in `user-updated/{caller.user_id}`.
+fn bindings(instance: Vec<(Vec<String>, Span)>) -> ParseResult<Vec<(String, Span)>> {
+    instance
+        .into_iter()
+        .map(|(path, span)| match &path[..] {
+            [caller, claim] if caller == "caller" => Ok((claim.clone(), span)),
+            _ => Err(Diagnostic {
+                message: "Channel parameters of a rule name a claim of the caller, as in `{caller.user_id}`".to_string(),
+                span,
+            }),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub fn parse(input: &str) -> Result<Vec<Statement>, PolicyError> {
+    parse_at(input, 0)
+}
+
+/// Parses a file whose spans start at `base`, so that spans of several files don't overlap.
+pub fn parse_at(input: &str, base: usize) -> Result<Vec<Statement>, PolicyError> {
+    let mut diagnostics = Vec::new();
+    let mut tokens = lex(input, &mut diagnostics);
+    for token in tokens.iter_mut() {
+        token.span = token.span.shift(base);
+    }
+    for diagnostic in diagnostics.iter_mut() {
+        diagnostic.span = diagnostic.span.shift(base);
+    }
+    let mut parser = Parser::new(tokens, base + input.len());
+    let statements = parser.parse(&mut diagnostics);
+    if diagnostics.is_empty() {
+        Ok(statements)
+    } else {
+        diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+        Err(PolicyError { diagnostics })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{line_col, parse, Statement};
+
+    #[test]
+    fn test_parse_error_position() {
+        let source = "role frontend\nrole {\n";
+        let err = parse(source).unwrap_err();
+        assert_eq!(err.diagnostics.len(), 1);
+        assert_eq!(err.diagnostics[0].message, "Expected role name, found `{`");
+        assert_eq!(line_col(source, err.diagnostics[0].span.start), (2, 6));
+    }
+
+    #[test]
+    fn test_parse_reports_multiple_errors() {
+        let source = "role {\nrequest a {\n    b string\n}\nallow x listen\nrole y $\n";
+        let err = parse(source).unwrap_err();
+        let messages: Vec<&str> = err.diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "Expected role name, found `{`",
+            "Expected `:`, found `string`",
+            "Expected message name, found `role`",
+            "Unexpected character `$`",
+        ]);
+    }
+
+    #[test]
+    fn test_render_diagnostic() {
+        let source = "role frontend\nrole {\n";
+        let err = parse(source).unwrap_err();
+        assert_eq!(
+            err.render("policy.bus", source),
+            "error: Expected role name, found `{`\n --> policy.bus:2:6\n  |\n2 | role {\n  |      ^\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_example() {
+        let statements = parse(include_str!("../../examples/example1.bus")).unwrap();
+        let schema = statements.iter().find_map(|stmt| match stmt {
+            Statement::Allow(allow_stmt) => allow_stmt.schema.as_ref(),
+            _ => None,
+        });
+        assert_eq!(schema.unwrap().msg_name, "user-created");
+        assert_eq!(schema.unwrap().msg_params.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_inline_schema_on_listen() {
+        let err = parse("allow frontend listen user-created {\n    id: uuid\n}\n").unwrap_err();
+        assert_eq!(err.diagnostics[0].message, "Inline schemas are only allowed on broadcast rules, found `:`");
+    }
+
+    #[test]
+    fn test_parse_comments() {
+        let source = "
+            # a line comment
+            // another line comment
+            /* a block
+               comment */
+            /// Services that talk to end users.
+            role frontend
+
+            /// Creates a user.
+            /// Answered by the backend.
+            request new-user {
+                /// Full name.
+                name: string // trailing comment
+                //// not a doc comment
+                email: string
+            }
+        ";
+        let statements = parse(source).unwrap();
+        match &statements[0] {
+            Statement::Role(role_stmt) => {
+                assert_eq!(role_stmt.doc.as_deref(), Some("Services that talk to end users."));
+            }
+            _ => panic!("Expected role statement"),
+        }
+        match &statements[1] {
+            Statement::Msg(super::MsgStmt::Request(req_stmt)) => {
+                assert_eq!(req_stmt.doc.as_deref(), Some("Creates a user.\nAnswered by the backend."));
+                assert_eq!(req_stmt.msg_params[0].doc.as_deref(), Some("Full name."));
+                assert_eq!(req_stmt.msg_params[1].doc, None);
+            }
+            _ => panic!("Expected request statement"),
+        }
+    }
+
+    #[test]
+    fn test_parse_enum_and_union() {
+        let statements = parse("
+            enum OrderStatus { pending paid shipped }
+            union Payment {
+                /// Paid by credit card.
+                card: { last4: string },
+                pix: { key: string }
+            }
+        ").unwrap();
+        match (&statements[0], &statements[1]) {
+            (Statement::Enum(enum_stmt), Statement::Union(union_stmt)) => {
+                assert_eq!(enum_stmt.variants, vec!["pending", "paid", "shipped"]);
+                assert_eq!(union_stmt.variants.len(), 2);
+                assert_eq!(union_stmt.variants[0].doc.as_deref(), Some("Paid by credit card."));
+            }
+            _ => panic!("Expected enum and union statements"),
+        }
+        let err = parse("enum OrderStatus { paid paid }").unwrap_err();
+        assert_eq!(err.diagnostics[0].message, "Variant `paid` is already declared");
+    }
+
+    #[test]
+    fn test_parse_constraints() {
+        let statements = parse("type A {\n    x: float(-1.5..1.5)\n    y: string(>0, <=8) /^a\\/b$/?\n}\n").unwrap();
+        match &statements[0] {
+            Statement::Type(type_stmt) => {
+                assert_eq!(type_stmt.msg_params[0].param_type.to_string(), "float(-1.5..1.5)");
+                assert_eq!(type_stmt.msg_params[1].param_type.to_string(), "string(>0, <=8) /^a\\/b$/?");
+            }
+            _ => panic!("Expected type statement"),
+        }
+        let err = parse("type A {\n    x: string /[a-/\n}\n").unwrap_err();
+        assert!(err.diagnostics[0].message.starts_with("Invalid pattern"));
+    }
+
+    #[test]
+    fn test_parse_channel_patterns() {
+        let statements = parse("allow a listen orders.*\nallow a listen orders.>\n").unwrap();
+        match &statements[1] {
+            Statement::Allow(allow_stmt) => assert_eq!(allow_stmt.msg_name, "orders.>"),
+            _ => panic!("Expected allow statement"),
+        }
+        let err = parse("broadcast orders.* {\n    id: uuid\n}\nallow a listen orders.>.eu\n").unwrap_err();
+        let messages: Vec<&str> = err.diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "Wildcards are only allowed in allow and deny rules, found `*`",
+            "`>` must be the last segment of a channel",
+        ]);
+    }
+
+    #[test]
+    fn test_parse_duration_overflow() {
+        let err = parse("request a timeout 18446744073709551615m {\n    id: uuid\n}\n").unwrap_err();
+        assert_eq!(err.diagnostics[0].message, "Expected a duration such as `500ms`, `5s` or `1m`, found `18446744073709551615m`");
+    }
+
+    #[test]
+    fn test_parse_unterminated_block_comment() {
+        let err = parse("role frontend\n/* never closed").unwrap_err();
+        assert_eq!(err.diagnostics[0].message, "Unterminated block comment");
+    }
+}
@@ -0,0 +1,35 @@
+use std::time::{Duration, Instant};
+
+/// Fixed one-second window limiter, one per connection. A limit of zero disables it.
+pub struct RateLimiter {
+    limit: u32,
+    window_start: Instant,
+    count: u32,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32) -> RateLimiter {
+        RateLimiter {
+            limit,
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    pub fn check(&mut self) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count <= self.limit
+    }
+}
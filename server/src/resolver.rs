@@ -0,0 +1,602 @@
+use std::collections::HashMap;
+use crate::channel;
+use crate::filter;
+use crate::policy::{
+    AllowStmt, BroadcastStmt, Constraint, DenyStmt, Diagnostic, MsgParam, MsgStmt, MsgType, PolicyError, RoleStmt, Span, Statement, TypeExpr,
+};
+use crate::scalars::{scalar_type, ScalarKind};
+
+/// Declared types by name: a `type` is stored as the object it describes, an alias as its target.
+pub type Types = HashMap<String, TypeExpr>;
+
+#[derive(Debug, Clone)]
+pub struct Role {
+    /// The role itself followed by the roles it extends, nearest first.
+    pub chain: Vec<String>,
+    /// Allow rules of the role and of its ancestors, in the order of `chain`.
+    pub permissions: Vec<AllowStmt>,
+    /// Deny rules of the role and of its ancestors, in the order of `chain`.
+    pub denials: Vec<DenyStmt>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub statements: Vec<Statement>,
+    pub types: Types,
+    pub roles: HashMap<String, Role>,
+}
+
+/// Parses a policy and resolves the type names it uses.
+#[cfg(test)]
+pub fn load(source: &str) -> Result<Policy, PolicyError> {
+    resolve(crate::policy::parse(source)?)
+}
+
+pub fn resolve(mut statements: Vec<Statement>) -> Result<Policy, PolicyError> {
+    let mut diagnostics = Vec::new();
+    let mut types = Types::new();
+    let mut spans: HashMap<String, Span> = HashMap::new();
+
+    for stmt in &statements {
+        let (name, declared, span) = match stmt {
+            Statement::Type(type_stmt) => {
+                (&type_stmt.type_name, TypeExpr::Object(type_stmt.msg_params.clone()), type_stmt.span)
+            }
+            Statement::Alias(alias_stmt) => (&alias_stmt.alias_name, alias_stmt.alias_type.clone(), alias_stmt.span),
+            Statement::Enum(enum_stmt) => {
+                (&enum_stmt.enum_name, TypeExpr::Enum(enum_stmt.variants.clone()), enum_stmt.span)
+            }
+            Statement::Union(union_stmt) => {
+                (&union_stmt.union_name, TypeExpr::Union(union_stmt.variants.clone()), union_stmt.span)
+            }
+            _ => continue,
+        };
+        if is_builtin(name) {
+            diagnostics.push(Diagnostic {
+                message: format!("`{}` is a built-in type and cannot be redeclared", name),
+                span,
+            });
+        } else if spans.contains_key(name) {
+            diagnostics.push(Diagnostic {
+                message: format!("Type `{}` is already declared", name),
+                span,
+            });
+        } else {
+            spans.insert(name.clone(), span);
+            types.insert(name.clone(), declared);
+        }
+    }
+
+    let mut messages: HashMap<(MsgType, &str), Span> = HashMap::new();
+    for stmt in &statements {
+        let (msg_type, msg_name, span) = match stmt {
+            Statement::Msg(MsgStmt::Broadcast(broadcast_stmt))
+            | Statement::Allow(AllowStmt { schema: Some(broadcast_stmt), .. }) => {
+                (MsgType::Broadcast, &broadcast_stmt.msg_name, broadcast_stmt.span)
+            }
+            Statement::Msg(MsgStmt::Request(req_stmt)) => (MsgType::Request, &req_stmt.msg_name, req_stmt.span),
+            Statement::Msg(MsgStmt::Response(res_stmt)) => (MsgType::Response, &res_stmt.msg_name, res_stmt.span),
+            _ => continue,
+        };
+        if messages.insert((msg_type, msg_name), span).is_some() {
+            diagnostics.push(Diagnostic {
+                message: format!("Message `{} {}` is already declared", msg_type, msg_name),
+                span,
+            });
+        }
+    }
+
+    // Constraint checks look through aliases, so loops have to be ruled out first.
+    check_cycles(&types, &spans, &mut diagnostics);
+    if !diagnostics.is_empty() {
+        return Err(PolicyError { diagnostics });
+    }
+
+    for stmt in &statements {
+        match stmt {
+            Statement::Msg(MsgStmt::Broadcast(broadcast_stmt)) => check_params(&types, &broadcast_stmt.msg_params, &mut diagnostics),
+            Statement::Msg(MsgStmt::Request(req_stmt)) => check_params(&types, &req_stmt.msg_params, &mut diagnostics),
+            Statement::Msg(MsgStmt::Response(res_stmt)) => check_params(&types, &res_stmt.msg_params, &mut diagnostics),
+            Statement::Allow(AllowStmt { schema: Some(broadcast_stmt), .. }) => {
+                check_params(&types, &broadcast_stmt.msg_params, &mut diagnostics)
+            }
+            Statement::Type(type_stmt) => check_params(&types, &type_stmt.msg_params, &mut diagnostics),
+            Statement::Union(union_stmt) => check_params(&types, &union_stmt.variants, &mut diagnostics),
+            Statement::Alias(alias_stmt) => {
+                check_type(&types, &alias_stmt.alias_type, alias_stmt.type_span, &mut diagnostics)
+            }
+            _ => {}
+        }
+    }
+
+    for broadcast_stmt in broadcast_stmts(&statements) {
+        let BroadcastStmt { msg_name, msg_params, bound_fields, .. } = broadcast_stmt;
+        check_fields(Some(msg_params), msg_name, bound_fields, &mut diagnostics);
+    }
+
+    // Filters are taken out while they are checked, since checking reads the other statements.
+    for index in 0..statements.len() {
+        let (msg_type, msg_name, mut filter) = match &mut statements[index] {
+            Statement::Allow(AllowStmt { msg_type, msg_name, filter, .. })
+            | Statement::Deny(DenyStmt { msg_type, msg_name, filter, .. }) => match filter.take() {
+                Some(filter) => (*msg_type, msg_name.clone(), filter),
+                None => continue,
+            },
+            _ => continue,
+        };
+        filter::check(&types, message_schema(&statements, msg_type, &msg_name), &mut filter, &mut diagnostics);
+        if let Statement::Allow(AllowStmt { filter: slot, .. }) | Statement::Deny(DenyStmt { filter: slot, .. }) = &mut statements[index] {
+            *slot = Some(filter);
+        }
+    }
+
+    for stmt in &statements {
+        if let Statement::Allow(AllowStmt { msg_type, msg_name, fields: Some(fields), .. }) = stmt {
+            check_fields(message_schema(&statements, *msg_type, msg_name), msg_name, fields, &mut diagnostics);
+        }
+        if let Statement::Allow(AllowStmt { msg_type, msg_name, bindings, .. })
+        | Statement::Deny(DenyStmt { msg_type, msg_name, bindings, .. }) = stmt
+        {
+            check_bindings(&statements, *msg_type, msg_name, bindings, &mut diagnostics);
+        }
+    }
+
+    let roles = resolve_roles(&statements, &mut diagnostics);
+
+    if diagnostics.is_empty() {
+        Ok(Policy { statements, types, roles })
+    } else {
+        diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+        Err(PolicyError { diagnostics })
+    }
+}
+
+fn check_fields(schema: Option<&[MsgParam]>, msg_name: &str, fields: &[(String, Span)], diagnostics: &mut Vec<Diagnostic>) {
+    let params = match schema {
+        Some(params) => params,
+        None => return,
+    };
+    for (field, span) in fields {
+        if !params.iter().any(|param| param.param_name == *field) {
+            diagnostics.push(Diagnostic {
+                message: format!("Unknown field `{}` on `{}`", field, msg_name),
+                span: *span,
+            });
+        }
+    }
+}
+
+/// Every broadcast declaration, either on its own or inline in an allow rule.
+pub fn broadcast_stmts(statements: &[Statement]) -> impl Iterator<Item = &BroadcastStmt> {
+    statements.iter().filter_map(|stmt| match stmt {
+        Statement::Msg(MsgStmt::Broadcast(broadcast_stmt))
+        | Statement::Allow(AllowStmt { schema: Some(broadcast_stmt), .. }) => Some(broadcast_stmt),
+        _ => None,
+    })
+}
+
+/// Rules may bind at most as many channel parameters as the channel declares.
+fn check_bindings(statements: &[Statement], msg_type: MsgType, msg_name: &str, bindings: &[(String, Span)], diagnostics: &mut Vec<Diagnostic>) {
+    if bindings.is_empty() || channel::is_pattern(msg_name) {
+        return;
+    }
+    let declared = match msg_type {
+        MsgType::Broadcast | MsgType::Listen => broadcast_stmts(statements)
+            .find(|broadcast_stmt| broadcast_stmt.msg_name == msg_name)
+            .map(|broadcast_stmt| broadcast_stmt.bound_fields.len()),
+        MsgType::Request | MsgType::Response => Some(0),
+    };
+    if let Some(declared) = declared.filter(|declared| bindings.len() > *declared) {
+        diagnostics.push(Diagnostic {
+            message: format!(
+                "Channel `{}` takes {} parameter{}, found {}",
+                msg_name,
+                declared,
+                if declared == 1 { "" } else { "s" },
+                bindings.len()
+            ),
+            span: bindings[declared].1,
+        });
+    }
+}
+
+/// The payload fields of a channel, as seen by rules on it; listeners see what is broadcast.
+pub fn message_schema<'a>(statements: &'a [Statement], msg_type: MsgType, channel: &str) -> Option<&'a [MsgParam]> {
+    statements.iter().find_map(|stmt| match (stmt, msg_type) {
+        (Statement::Msg(MsgStmt::Broadcast(broadcast_stmt)), MsgType::Broadcast | MsgType::Listen)
+        | (Statement::Allow(AllowStmt { schema: Some(broadcast_stmt), .. }), MsgType::Broadcast | MsgType::Listen)
+            if broadcast_stmt.msg_name == channel =>
+        {
+            Some(&broadcast_stmt.msg_params[..])
+        }
+        (Statement::Msg(MsgStmt::Request(req_stmt)), MsgType::Request) if req_stmt.msg_name == channel => {
+            Some(&req_stmt.msg_params[..])
+        }
+        (Statement::Msg(MsgStmt::Response(res_stmt)), MsgType::Response) if res_stmt.msg_name == channel => {
+            Some(&res_stmt.msg_params[..])
+        }
+        _ => None,
+    })
+}
+
+/// Follows `extends` from every role; roles only named in allow or deny rules stand on their own.
+fn resolve_roles(statements: &[Statement], diagnostics: &mut Vec<Diagnostic>) -> HashMap<String, Role> {
+    let mut parents: HashMap<&str, Option<&str>> = HashMap::new();
+    let mut spans: HashMap<&str, Span> = HashMap::new();
+    for stmt in statements {
+        if let Statement::Role(role_stmt) = stmt {
+            if spans.contains_key(role_stmt.role_name.as_str()) {
+                diagnostics.push(Diagnostic {
+                    message: format!("Role `{}` is already declared", role_stmt.role_name),
+                    span: role_stmt.span,
+                });
+                continue;
+            }
+            spans.insert(&role_stmt.role_name, role_stmt.span);
+            parents.insert(&role_stmt.role_name, role_stmt.extends_role.as_deref());
+        }
+    }
+    for stmt in statements {
+        match stmt {
+            Statement::Role(RoleStmt { extends_role: Some(parent), extends_span: Some(span), .. })
+                if !parents.contains_key(parent.as_str()) =>
+            {
+                diagnostics.push(Diagnostic {
+                    message: format!("Undefined role `{}`", parent),
+                    span: *span,
+                });
+            }
+            Statement::Allow(AllowStmt { role_name, .. }) | Statement::Deny(DenyStmt { role_name, .. }) => {
+                parents.entry(role_name).or_insert(None);
+            }
+            _ => {}
+        }
+    }
+
+    let mut names: Vec<&str> = parents.keys().copied().collect();
+    names.sort_by_key(|name| spans.get(name).map(|span| span.start));
+    let mut reported: Vec<&str> = Vec::new();
+    let mut roles = HashMap::new();
+    for name in names {
+        let mut chain = vec![name];
+        while let Some(Some(parent)) = parents.get(chain[chain.len() - 1]) {
+            if let Some(position) = chain.iter().position(|role| role == parent) {
+                if !chain[position..].iter().any(|role| reported.contains(role)) {
+                    let mut cycle = chain[position..].to_vec();
+                    cycle.push(parent);
+                    diagnostics.push(Diagnostic {
+                        message: format!("Role `{}` extends itself ({})", parent, cycle.join(" -> ")),
+                        span: spans[parent],
+                    });
+                    reported.extend(&chain[position..]);
+                }
+                break;
+            }
+            chain.push(parent);
+        }
+        let permissions = chain
+            .iter()
+            .flat_map(|role| {
+                statements.iter().filter_map(move |stmt| match stmt {
+                    Statement::Allow(allow_stmt) if allow_stmt.role_name == *role => Some(allow_stmt.clone()),
+                    _ => None,
+                })
+            })
+            .collect();
+        let denials = chain
+            .iter()
+            .flat_map(|role| {
+                statements.iter().filter_map(move |stmt| match stmt {
+                    Statement::Deny(deny_stmt) if deny_stmt.role_name == *role => Some(deny_stmt.clone()),
+                    _ => None,
+                })
+            })
+            .collect();
+        roles.insert(
+            name.to_string(),
+            Role {
+                chain: chain.iter().map(|role| role.to_string()).collect(),
+                permissions,
+                denials,
+            },
+        );
+    }
+    roles
+}
+
+/// What a range constraint measures on a type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RangeTarget {
+    Characters,
+    Items,
+    Entries,
+    Number,
+}
+
+/// Looks through aliases and other constraints to find what `(min..max)` means for a type.
+pub fn range_target(types: &Types, param_type: &TypeExpr) -> Option<RangeTarget> {
+    match param_type {
+        TypeExpr::Named(name) => match scalar_type(name) {
+            Some(scalar) => match scalar.kind {
+                ScalarKind::Text => Some(RangeTarget::Characters),
+                ScalarKind::Number => Some(RangeTarget::Number),
+                ScalarKind::Bool => None,
+            },
+            None => types.get(name).and_then(|declared| range_target(types, declared)),
+        },
+        TypeExpr::List(_) => Some(RangeTarget::Items),
+        TypeExpr::Map(_, _) => Some(RangeTarget::Entries),
+        TypeExpr::Constrained(inner, _) => range_target(types, inner),
+        _ => None,
+    }
+}
+
+/// Whether a field may be left out, looking through aliases such as `alias Note = string?`.
+pub fn is_optional(types: &Types, param_type: &TypeExpr) -> bool {
+    match param_type {
+        TypeExpr::Optional(_) => true,
+        TypeExpr::Named(name) => types.get(name).map(|declared| is_optional(types, declared)).unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn is_builtin(name: &str) -> bool {
+    scalar_type(name).is_some() || name == "list" || name == "map"
+}
+
+fn check_params(types: &Types, params: &[MsgParam], diagnostics: &mut Vec<Diagnostic>) {
+    for param in params {
+        check_type(types, &param.param_type, param.type_span, diagnostics);
+    }
+}
+
+fn check_type(types: &Types, param_type: &TypeExpr, span: Span, diagnostics: &mut Vec<Diagnostic>) {
+    match param_type {
+        TypeExpr::Named(name) => {
+            if scalar_type(name).is_none() && !types.contains_key(name) {
+                diagnostics.push(Diagnostic {
+                    message: format!("Undefined type `{}`", name),
+                    span,
+                });
+            }
+        }
+        TypeExpr::List(item) | TypeExpr::Optional(item) => check_type(types, item, span, diagnostics),
+        TypeExpr::Map(key, value) => {
+            check_type(types, key, span, diagnostics);
+            check_type(types, value, span, diagnostics);
+        }
+        TypeExpr::Object(params) | TypeExpr::Union(params) => check_params(types, params, diagnostics),
+        TypeExpr::Enum(_) => {}
+        TypeExpr::Constrained(inner, constraints) => {
+            check_type(types, inner, span, diagnostics);
+            let target = range_target(types, inner);
+            for constraint in constraints {
+                let message = match (constraint, target) {
+                    (Constraint::Range { .. }, None) => {
+                        format!("Ranges only apply to strings, numbers, lists and maps, not `{}`", inner)
+                    }
+                    (Constraint::Range { min: Some(min), max: Some(max) }, _) if min.value > max.value => {
+                        format!("Empty range `{}`", constraint)
+                    }
+                    (Constraint::Pattern(_), target) if target != Some(RangeTarget::Characters) => {
+                        format!("Patterns only apply to strings, not `{}`", inner)
+                    }
+                    _ => continue,
+                };
+                diagnostics.push(Diagnostic { message, span });
+            }
+        }
+    }
+}
+
+/// Names a value of this type must contain right away; lists, maps and optionals can be empty,
+/// and a union can always pick another variant.
+fn direct_references<'a>(param_type: &'a TypeExpr, references: &mut Vec<&'a str>) {
+    match param_type {
+        TypeExpr::Named(name) => references.push(name),
+        TypeExpr::Object(params) => {
+            for param in params {
+                direct_references(&param.param_type, references);
+            }
+        }
+        TypeExpr::Constrained(inner, _) => direct_references(inner, references),
+        TypeExpr::List(_) | TypeExpr::Map(_, _) | TypeExpr::Optional(_) | TypeExpr::Enum(_) | TypeExpr::Union(_) => {}
+    }
+}
+
+/// Rejects types that could only be satisfied by an infinitely nested value, and alias loops.
+fn check_cycles(types: &Types, spans: &HashMap<String, Span>, diagnostics: &mut Vec<Diagnostic>) {
+    let mut names: Vec<&String> = types.keys().collect();
+    names.sort_by_key(|name| spans[*name].start);
+    let mut done: Vec<&str> = Vec::new();
+    for name in names {
+        let mut stack = Vec::new();
+        visit(types, name, &mut stack, &mut done, spans, diagnostics);
+    }
+}
+
+fn visit<'a>(
+    types: &'a Types,
+    name: &'a str,
+    stack: &mut Vec<&'a str>,
+    done: &mut Vec<&'a str>,
+    spans: &HashMap<String, Span>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    if done.contains(&name) {
+        return;
+    }
+    if let Some(position) = stack.iter().position(|visiting| *visiting == name) {
+        let mut cycle = stack[position..].to_vec();
+        cycle.push(name);
+        diagnostics.push(Diagnostic {
+            message: format!(
+                "Type `{}` contains itself without a list, map or optional in between ({})",
+                name,
+                cycle.join(" -> ")
+            ),
+            span: spans[name],
+        });
+        return;
+    }
+    let declared = match types.get(name) {
+        Some(declared) => declared,
+        None => return,
+    };
+    stack.push(name);
+    let mut references = Vec::new();
+    direct_references(declared, &mut references);
+    for reference in references {
+        visit(types, reference, stack, done, spans, diagnostics);
+    }
+    stack.pop();
+    done.push(name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::load;
+    use crate::policy::line_col;
+
+    fn messages(source: &str) -> Vec<String> {
+        load(source).unwrap_err().diagnostics.into_iter().map(|d| d.message).collect()
+    }
+
+    #[test]
+    fn test_resolve_types() {
+        let policy = load("
+            alias OrderId = uuid
+            type Address {
+                street: string
+                zip: string?
+            }
+            type Category {
+                name: string
+                children: list<Category>
+                parent: Category?
+            }
+            request place-order {
+                id: OrderId
+                shipping: Address
+                billing: Address?
+            }
+        ").unwrap();
+        assert_eq!(policy.types.len(), 3);
+        assert_eq!(policy.types["OrderId"].to_string(), "uuid");
+    }
+
+    #[test]
+    fn test_resolve_undefined_type() {
+        let source = "request place-order {\n    shipping: list<Adress>\n}\n";
+        let err = load(source).unwrap_err();
+        assert_eq!(err.diagnostics[0].message, "Undefined type `Adress`");
+        assert_eq!(line_col(source, err.diagnostics[0].span.start), (2, 15));
+    }
+
+    #[test]
+    fn test_resolve_cycles() {
+        assert_eq!(messages("alias A = B\nalias B = A\n"), vec![
+            "Type `A` contains itself without a list, map or optional in between (A -> B -> A)",
+        ]);
+        assert_eq!(messages("type Node {\n    next: { node: Node }\n}\n"), vec![
+            "Type `Node` contains itself without a list, map or optional in between (Node -> Node)",
+        ]);
+    }
+
+    #[test]
+    fn test_resolve_redeclared_type() {
+        assert_eq!(messages("alias uuid = string\ntype A {}\nalias A = int\n"), vec![
+            "`uuid` is a built-in type and cannot be redeclared",
+            "Type `A` is already declared",
+        ]);
+    }
+
+    #[test]
+    fn test_resolve_constraints() {
+        load("
+            alias Sku = string /^[A-Z0-9-]+$/
+            request place-order {
+                name: string(1..120)
+                qty: int(>=1)
+                sku: Sku(..32)
+                items: list<Sku>(1..)
+                price: decimal(>0, <=1000000)
+            }
+        ").unwrap();
+        assert_eq!(messages("request a {\n    b: bool(1..2)\n    c: int /x/\n    d: string(3..1)\n}\n"), vec![
+            "Ranges only apply to strings, numbers, lists and maps, not `bool`",
+            "Patterns only apply to strings, not `int`",
+            "Empty range `3..1`",
+        ]);
+    }
+
+    #[test]
+    fn test_resolve_roles() {
+        let policy = load("
+            role reader
+            role writer extends reader
+            role admin extends writer
+            allow reader listen a
+            allow writer broadcast a
+            allow admin request b
+            allow auditor listen a
+        ").unwrap();
+        let admin = &policy.roles["admin"];
+        assert_eq!(admin.chain, vec!["admin", "writer", "reader"]);
+        let permissions: Vec<&str> = admin.permissions.iter().map(|allow_stmt| allow_stmt.msg_name.as_str()).collect();
+        assert_eq!(permissions, vec!["b", "a", "a"]);
+        assert_eq!(policy.roles["auditor"].chain, vec!["auditor"]);
+    }
+
+    #[test]
+    fn test_resolve_role_errors() {
+        let source = "role a extends b\nrole b extends c\nrole c extends a\nrole d extends e\nrole d\n";
+        assert_eq!(messages(source), vec![
+            "Role `a` extends itself (a -> b -> c -> a)",
+            "Undefined role `e`",
+            "Role `d` is already declared",
+        ]);
+        let err = load(source).unwrap_err();
+        assert_eq!(line_col(source, err.diagnostics[1].span.start), (4, 16));
+    }
+
+    #[test]
+    fn test_resolve_fields() {
+        let source = "broadcast user-created {\n    id: uuid\n    email: email\n}\nallow analytics listen user-created { id mail }\n";
+        assert_eq!(messages(source), vec!["Unknown field `mail` on `user-created`"]);
+        let err = load(source).unwrap_err();
+        assert_eq!(line_col(source, err.diagnostics[0].span.start), (5, 42));
+        assert!(messages("allow backend request new-user { id }").contains(
+            &"Inline schemas are only allowed on broadcast rules, and field lists on listen and response rules, found `{`".to_string()
+        ));
+    }
+
+    #[test]
+    fn test_resolve_channel_parameters() {
+        let source = "broadcast a/{id}/{missing} {\n    id: string\n}\nallow u listen a/{caller.x}/{caller.y}/{caller.z}\nallow u request b/{caller.x}\n";
+        assert_eq!(messages(source), vec![
+            "Unknown field `missing` on `a`",
+            "Channel `a` takes 2 parameters, found 3",
+            "Channel `b` takes 0 parameters, found 1",
+        ]);
+        assert_eq!(
+            messages("allow u listen a/{x}"),
+            vec!["Channel parameters of a rule name a claim of the caller, as in `{caller.user_id}`"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_redeclared_message() {
+        let source = "request a {\n}\nresponse a {\n}\nrequest a {\n}\nallow x broadcast b { }\nbroadcast b {\n}\n";
+        assert_eq!(messages(source), vec![
+            "Message `request a` is already declared",
+            "Message `broadcast b` is already declared",
+        ]);
+    }
+
+    #[test]
+    fn test_field_named_type() {
+        let policy = load("type Event {\n    type: string\n}\nbroadcast x {\n    type: Event\n}\n").unwrap();
+        assert_eq!(policy.types.len(), 1);
+    }
+}
@@ -0,0 +1,375 @@
+use serde_json::Value;
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScalarKind {
+    /// Sent as a JSON string; can take length constraints and patterns.
+    Text,
+    /// Can take value ranges, e.g. `int(>=1)`.
+    Number,
+    Bool,
+}
+
+pub struct ScalarType {
+    pub name: &'static str,
+    /// Used in schema violation messages, e.g. "expected uuid (RFC 4122 UUID)".
+    pub description: &'static str,
+    pub kind: ScalarKind,
+    check: fn(&Value) -> bool,
+}
+
+impl ScalarType {
+    pub fn check(&self, value: &Value) -> bool {
+        (self.check)(value)
+    }
+}
+
+static SCALAR_TYPES: &[ScalarType] = &[
+    ScalarType { name: "string", description: "string", kind: ScalarKind::Text, check: Value::is_string },
+    ScalarType { name: "int", description: "int (32-bit integer)", kind: ScalarKind::Number, check: is_int },
+    ScalarType { name: "int64", description: "int64 (64-bit integer)", kind: ScalarKind::Number, check: is_int64 },
+    ScalarType { name: "float", description: "float (number)", kind: ScalarKind::Number, check: Value::is_number },
+    ScalarType { name: "bool", description: "bool", kind: ScalarKind::Bool, check: Value::is_boolean },
+    ScalarType { name: "uuid", description: "uuid (RFC 4122 UUID)", kind: ScalarKind::Text, check: is_uuid },
+    ScalarType { name: "datetime", description: "datetime (RFC 3339 timestamp)", kind: ScalarKind::Text, check: is_datetime },
+    ScalarType { name: "email", description: "email (email address)", kind: ScalarKind::Text, check: is_email },
+    ScalarType { name: "url", description: "url (absolute URL)", kind: ScalarKind::Text, check: is_url },
+    ScalarType { name: "decimal", description: "decimal (decimal number or decimal string)", kind: ScalarKind::Number, check: is_decimal },
+];
+
+pub fn scalar_types() -> &'static [ScalarType] {
+    SCALAR_TYPES
+}
+
+pub fn scalar_type(name: &str) -> Option<&'static ScalarType> {
+    SCALAR_TYPES.iter().find(|scalar| scalar.name == name)
+}
+
+fn is_int(value: &Value) -> bool {
+    value.as_i64().map(|i| i32::try_from(i).is_ok()).unwrap_or(false)
+}
+
+fn is_int64(value: &Value) -> bool {
+    value.as_i64().is_some()
+}
+
+fn is_uuid(value: &Value) -> bool {
+    let s = match value.as_str() {
+        Some(s) => s,
+        None => return false,
+    };
+    let groups: Vec<&str> = s.split('-').collect();
+    let lengths = [8, 4, 4, 4, 12];
+    if groups.len() != lengths.len()
+        || groups.iter().zip(lengths).any(|(group, len)| group.len() != len || !group.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        return false;
+    }
+    if s.chars().all(|c| c == '0' || c == '-') {
+        return true;
+    }
+    let version = groups[2].as_bytes()[0];
+    let variant = groups[3].as_bytes()[0].to_ascii_lowercase();
+    (b'1'..=b'8').contains(&version) && matches!(variant, b'8' | b'9' | b'a' | b'b')
+}
+
+fn digits(s: &str, len: usize) -> Option<u32> {
+    if s.len() == len && s.chars().all(|c| c.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        None
+    }
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn is_datetime(value: &Value) -> bool {
+    let s = match value.as_str() {
+        Some(s) if s.is_ascii() && s.len() >= 20 => s,
+        _ => return false,
+    };
+    let (date, rest) = s.split_at(10);
+    let (year, month, day) = match (digits(&date[0..4], 4), digits(&date[5..7], 2), digits(&date[8..10], 2)) {
+        (Some(year), Some(month), Some(day)) if &date[4..5] == "-" && &date[7..8] == "-" => (year, month, day),
+        _ => return false,
+    };
+    if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+        return false;
+    }
+    if !matches!(&rest[0..1], "T" | "t" | " ") {
+        return false;
+    }
+    let time = &rest[1..];
+    let (hour, minute, second) = match (digits(&time[0..2], 2), digits(&time[3..5], 2), digits(&time[6..8], 2)) {
+        (Some(hour), Some(minute), Some(second)) if &time[2..3] == ":" && &time[5..6] == ":" => (hour, minute, second),
+        _ => return false,
+    };
+    if hour > 23 || minute > 59 || second > 60 {
+        return false;
+    }
+    let mut offset = &time[8..];
+    if let Some(fraction) = offset.strip_prefix('.') {
+        let len = fraction.chars().take_while(|c| c.is_ascii_digit()).count();
+        if len == 0 {
+            return false;
+        }
+        offset = &fraction[len..];
+    }
+    match offset {
+        "Z" | "z" => true,
+        _ if offset.len() == 6 && matches!(&offset[0..1], "+" | "-") && &offset[3..4] == ":" => {
+            matches!((digits(&offset[1..3], 2), digits(&offset[4..6], 2)), (Some(h), Some(m)) if h <= 23 && m <= 59)
+        }
+        _ => false,
+    }
+}
+
+fn is_domain(domain: &str) -> bool {
+    domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+fn is_email(value: &Value) -> bool {
+    let s = match value.as_str() {
+        Some(s) => s,
+        None => return false,
+    };
+    match s.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && local.len() <= 64
+                && !local.starts_with('.')
+                && !local.ends_with('.')
+                && !local.contains("..")
+                && local.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(c))
+                && is_domain(domain)
+        }
+        None => false,
+    }
+}
+
+fn is_url(value: &Value) -> bool {
+    let s = match value.as_str() {
+        Some(s) => s,
+        None => return false,
+    };
+    let (scheme, rest) = match s.split_once("://") {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    let host = authority.rsplit('@').next().unwrap_or("");
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        Some(_) if !host.starts_with('[') => return false,
+        _ => host,
+    };
+    scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+.-".contains(c))
+        && !host.is_empty()
+        && !s.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+fn is_decimal_str(s: &str) -> bool {
+    let s = s.strip_prefix('-').unwrap_or(s);
+    let (integer, fraction) = match s.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (s, None),
+    };
+    !integer.is_empty()
+        && integer.chars().all(|c| c.is_ascii_digit())
+        && fraction.map(|f| !f.is_empty() && f.chars().all(|c| c.is_ascii_digit())).unwrap_or(true)
+}
+
+/// A number read exactly from its decimal text, such as `12.50`, `-3` or `1e21`, so that values
+/// beyond what a float holds still compare correctly. It prints as the text it was read from.
+#[derive(Debug, Clone)]
+pub struct Decimal {
+    text: String,
+    negative: bool,
+    /// Significant digits, without leading or trailing zeros; empty for zero.
+    digits: String,
+    /// The value is `0.<digits>` times ten to this power.
+    exponent: i64,
+}
+
+impl Decimal {
+    /// Reads JSON number syntax: an optional `-`, digits, an optional fraction and exponent.
+    pub fn parse(text: &str) -> Option<Decimal> {
+        let (negative, unsigned) = match text.strip_prefix('-') {
+            Some(unsigned) => (true, unsigned),
+            None => (false, text),
+        };
+        let (mantissa, power) = match unsigned.split_once(['e', 'E']) {
+            Some((mantissa, power)) => {
+                let power = power.strip_prefix('+').unwrap_or(power);
+                (mantissa, power.parse::<i64>().ok().filter(|power| power.unsigned_abs() < 1 << 32)?)
+            }
+            None => (unsigned, 0),
+        };
+        let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        let all_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+        if integer.is_empty() || !all_digits(integer) || !all_digits(fraction) || mantissa.ends_with('.') {
+            return None;
+        }
+        let digits = format!("{}{}", integer, fraction);
+        let significant = digits.trim_start_matches('0');
+        let exponent = integer.len() as i64 - (digits.len() - significant.len()) as i64 + power;
+        let digits = significant.trim_end_matches('0').to_string();
+        let exponent = if digits.is_empty() { 0 } else { exponent };
+        Some(Decimal { text: text.to_string(), negative: negative && !digits.is_empty(), digits, exponent })
+    }
+
+    /// The whole part, rounded towards zero, and whether anything was left after the point.
+    fn whole(&self) -> Option<(i128, bool)> {
+        let length = self.exponent.clamp(0, self.digits.len() as i64) as usize;
+        let zeros = usize::try_from((self.exponent - length as i64).max(0)).ok().filter(|zeros| *zeros < 40)?;
+        let whole: i128 = format!("0{}{}", &self.digits[..length], "0".repeat(zeros)).parse().ok()?;
+        Some((if self.negative { -whole } else { whole }, length < self.digits.len()))
+    }
+
+    /// The largest whole number not above this one.
+    pub fn floor(&self) -> Option<i128> {
+        let (whole, fraction) = self.whole()?;
+        Some(if fraction && self.negative { whole - 1 } else { whole })
+    }
+
+    /// The smallest whole number not below this one.
+    pub fn ceil(&self) -> Option<i128> {
+        let (whole, fraction) = self.whole()?;
+        Some(if fraction && !self.negative { whole + 1 } else { whole })
+    }
+}
+
+impl From<usize> for Decimal {
+    fn from(count: usize) -> Decimal {
+        Decimal::parse(&count.to_string()).expect("counts are whole numbers")
+    }
+}
+
+impl std::fmt::Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Decimal) -> Ordering {
+        let sign = |decimal: &Decimal| match (decimal.digits.is_empty(), decimal.negative) {
+            (true, _) => 0,
+            (false, true) => -1,
+            (false, false) => 1,
+        };
+        let magnitude = self.exponent.cmp(&other.exponent).then_with(|| self.digits.cmp(&other.digits));
+        sign(self).cmp(&sign(other)).then(if self.negative { magnitude.reverse() } else { magnitude })
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Decimal) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Decimal) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal {}
+
+fn is_decimal(value: &Value) -> bool {
+    match value {
+        Value::String(s) => is_decimal_str(s),
+        Value::Number(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{scalar_type, Decimal};
+    use serde_json::json;
+
+    fn check(name: &str, value: serde_json::Value) -> bool {
+        scalar_type(name).unwrap().check(&value)
+    }
+
+    #[test]
+    fn test_integers() {
+        assert!(check("int", json!(2147483647)));
+        assert!(!check("int", json!(2147483648i64)));
+        assert!(!check("int", json!(1.5)));
+        assert!(check("int64", json!(9223372036854775807i64)));
+        assert!(!check("int64", json!("1")));
+        assert!(check("float", json!(1)));
+    }
+
+    #[test]
+    fn test_uuid() {
+        assert!(check("uuid", json!("e1375bc9-0708-4eb9-b3d6-2c46398d2da9")));
+        assert!(check("uuid", json!("00000000-0000-0000-0000-000000000000")));
+        assert!(!check("uuid", json!("e1375bc907084eb9b3d62c46398d2da9")));
+        assert!(!check("uuid", json!("e1375bc9-0708-0eb9-b3d6-2c46398d2da9")));
+        assert!(!check("uuid", json!("e1375bc9-0708-4eb9-73d6-2c46398d2da9")));
+    }
+
+    #[test]
+    fn test_datetime() {
+        assert!(check("datetime", json!("2023-04-01T12:30:00Z")));
+        assert!(check("datetime", json!("2024-02-29T23:59:60.123-03:00")));
+        assert!(!check("datetime", json!("2023-02-29T12:30:00Z")));
+        assert!(!check("datetime", json!("2023-04-01T12:30:00")));
+        assert!(!check("datetime", json!("2023-04-01")));
+        assert!(!check("datetime", json!("2023-04-01T24:00:00Z")));
+    }
+
+    #[test]
+    fn test_email_and_url() {
+        assert!(check("email", json!("help@abstra.app")));
+        assert!(!check("email", json!("help@localhost")));
+        assert!(!check("email", json!("help abstra.app")));
+        assert!(check("url", json!("https://abstra.app/docs?a=1")));
+        assert!(check("url", json!("http://localhost:3030")));
+        assert!(!check("url", json!("abstra.app")));
+        assert!(!check("url", json!("http://:3030")));
+    }
+
+    #[test]
+    fn test_decimal() {
+        assert!(check("decimal", json!("-12345678901234567890.000000001")));
+        assert!(check("decimal", json!(12.5)));
+        assert!(!check("decimal", json!("1.")));
+        assert!(!check("decimal", json!("1e3")));
+    }
+
+    #[test]
+    fn test_decimal_comparison() {
+        let decimal = |text: &str| Decimal::parse(text).unwrap();
+        assert!(decimal("9007199254740993") > decimal("9007199254740992"));
+        assert!(decimal("0.10000000000000000001") > decimal("0.1"));
+        assert!(decimal("-2.5") < decimal("-2.4999999999999999999"));
+        assert_eq!(decimal("12.50"), decimal("1.25e1"));
+        assert_eq!(decimal("-0.0"), decimal("0"));
+        assert!(decimal("-1") < decimal("0") && decimal("0") < decimal("0.001"));
+        assert_eq!(decimal("12.5").to_string(), "12.5");
+        assert_eq!((decimal("2.5").floor(), decimal("2.5").ceil()), (Some(2), Some(3)));
+        assert_eq!((decimal("-2.5").floor(), decimal("-2.5").ceil()), (Some(-3), Some(-2)));
+        assert_eq!(decimal("9007199254740993").floor(), Some(9007199254740993));
+        assert!(Decimal::parse("1.").is_none());
+        assert!(Decimal::parse("5s").is_none());
+    }
+}
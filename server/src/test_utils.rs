@@ -0,0 +1,13 @@
+use std::fs;
+use std::path::PathBuf;
+
+/// Writes the files into a fresh directory under the system's temporary one, named after the test.
+pub fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bus-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for (file, body) in files {
+        fs::write(dir.join(file), body).unwrap();
+    }
+    fs::canonicalize(dir).unwrap()
+}
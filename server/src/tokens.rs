@@ -0,0 +1,116 @@
+use crate::validator::Params;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+pub const DEFAULT_TOKENS_PATH: &str = "./bus-tokens.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenEntry {
+    pub role: String,
+    /// Identity attributes that `when` filters read as `caller.<claim>`.
+    #[serde(default, skip_serializing_if = "Params::is_empty")]
+    pub claims: Params,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TokenFile {
+    tokens: HashMap<String, TokenEntry>,
+}
+
+pub struct TokenStore {
+    path: PathBuf,
+    file: TokenFile,
+}
+
+impl TokenStore {
+    pub fn load(path: &Path) -> io::Result<TokenStore> {
+        let file = match std::fs::read_to_string(path) {
+            Ok(body) => serde_json::from_str(&body)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => TokenFile::default(),
+            Err(err) => return Err(err),
+        };
+        Ok(TokenStore {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    pub fn generate(&mut self, role_name: &str, claims: Params) -> io::Result<String> {
+        let token = Uuid::new_v4().to_string();
+        self.file.tokens.insert(
+            token.clone(),
+            TokenEntry {
+                role: role_name.to_string(),
+                claims,
+            },
+        );
+        self.save()?;
+        Ok(token)
+    }
+
+    pub fn lookup(&self, token: &str) -> Option<&TokenEntry> {
+        self.file.tokens.get(token)
+    }
+
+    /// Tokens are bearer credentials, so the store is only readable by its owner, including one
+    /// that was created with wider permissions.
+    fn save(&self) -> io::Result<()> {
+        let body = serde_json::to_string_pretty(&self.file)?;
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&self.path)?;
+        #[cfg(unix)]
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        file.write_all(body.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenStore;
+
+    #[test]
+    fn test_generate_and_lookup() {
+        let path = std::env::temp_dir().join(format!("bus-tokens-{}.json", uuid::Uuid::new_v4()));
+        let mut store = TokenStore::load(&path).unwrap();
+        let claims = serde_json::json!({ "tenant_id": "acme" });
+        let token = store.generate("service-1", claims.as_object().unwrap().clone()).unwrap();
+
+        let store = TokenStore::load(&path).unwrap();
+        assert_eq!(store.lookup(&token).unwrap().role, "service-1");
+        assert_eq!(store.lookup(&token).unwrap().claims["tenant_id"], "acme");
+        assert!(store.lookup("unknown").is_none());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_save_restricts_existing_store() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("bus-tokens-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, r#"{"tokens": {}}"#).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let mut store = TokenStore::load(&path).unwrap();
+        store.generate("service-1", Default::default()).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,400 @@
+use serde_json::{json, Map, Value};
+use crate::policy::{AllowStmt, Bound, Constraint, MsgParam, MsgStmt, Statement, TypeExpr};
+use crate::resolver::{is_optional, range_target, Policy, RangeTarget, Types};
+use crate::scalars::Decimal;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Language {
+    TypeScript,
+    JsonSchema,
+}
+
+impl Language {
+    pub fn from_name(name: &str) -> Option<Language> {
+        match name {
+            "typescript" | "ts" => Some(Language::TypeScript),
+            "json-schema" => Some(Language::JsonSchema),
+            _ => None,
+        }
+    }
+}
+
+pub fn generate(policy: &Policy, language: Language) -> String {
+    match language {
+        Language::TypeScript => typescript(policy),
+        Language::JsonSchema => json_schema(policy),
+    }
+}
+
+struct Declaration<'a> {
+    name: String,
+    doc: &'a Option<String>,
+    declared: TypeExpr,
+}
+
+/// Every named type and message in policy order; requests and responses get a suffix since they share a name.
+fn declarations(policy: &Policy) -> Vec<Declaration<'_>> {
+    let mut declarations = Vec::new();
+    for stmt in &policy.statements {
+        let (name, doc, declared) = match stmt {
+            Statement::Type(type_stmt) => {
+                (pascal_case(&type_stmt.type_name), &type_stmt.doc, policy.types[&type_stmt.type_name].clone())
+            }
+            Statement::Alias(alias_stmt) => {
+                (pascal_case(&alias_stmt.alias_name), &alias_stmt.doc, alias_stmt.alias_type.clone())
+            }
+            Statement::Enum(enum_stmt) => {
+                (pascal_case(&enum_stmt.enum_name), &enum_stmt.doc, policy.types[&enum_stmt.enum_name].clone())
+            }
+            Statement::Union(union_stmt) => {
+                (pascal_case(&union_stmt.union_name), &union_stmt.doc, policy.types[&union_stmt.union_name].clone())
+            }
+            Statement::Msg(MsgStmt::Broadcast(broadcast_stmt))
+            | Statement::Allow(AllowStmt { schema: Some(broadcast_stmt), .. }) => (
+                pascal_case(&broadcast_stmt.msg_name),
+                &broadcast_stmt.doc,
+                TypeExpr::Object(broadcast_stmt.msg_params.clone()),
+            ),
+            Statement::Msg(MsgStmt::Request(req_stmt)) => (
+                pascal_case(&req_stmt.msg_name) + "Request",
+                &req_stmt.doc,
+                TypeExpr::Object(req_stmt.msg_params.clone()),
+            ),
+            Statement::Msg(MsgStmt::Response(res_stmt)) => (
+                pascal_case(&res_stmt.msg_name) + "Response",
+                &res_stmt.doc,
+                TypeExpr::Object(res_stmt.msg_params.clone()),
+            ),
+            _ => continue,
+        };
+        declarations.push(Declaration { name, doc, declared });
+    }
+    declarations
+}
+
+/// `user-created` becomes `UserCreated`; names that are already PascalCase are kept.
+fn pascal_case(name: &str) -> String {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+fn ts_doc(doc: &Option<String>, indent: &str) -> String {
+    match doc {
+        Some(doc) if !doc.contains('\n') => format!("{}/** {} */\n", indent, doc),
+        Some(doc) => {
+            let lines: String = doc.lines().map(|line| format!("{} * {}\n", indent, line).replace(" * \n", " *\n")).collect();
+            format!("{}/**\n{}{} */\n", indent, lines, indent)
+        }
+        None => String::new(),
+    }
+}
+
+fn ts_field_name(name: &str) -> String {
+    let is_identifier = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+    if is_identifier {
+        name.to_string()
+    } else {
+        serde_json::to_string(name).unwrap()
+    }
+}
+
+fn ts_object(params: &[MsgParam], depth: usize) -> String {
+    if params.is_empty() {
+        return "{}".to_string();
+    }
+    let indent = "    ".repeat(depth + 1);
+    let mut out = String::from("{\n");
+    for param in params {
+        out += &ts_doc(&param.doc, &indent);
+        match &param.param_type {
+            TypeExpr::Optional(inner) => {
+                out += &format!("{}{}?: {} | null;\n", indent, ts_field_name(&param.param_name), ts_type(inner, depth + 1));
+            }
+            param_type => {
+                out += &format!("{}{}: {};\n", indent, ts_field_name(&param.param_name), ts_type(param_type, depth + 1));
+            }
+        }
+    }
+    out + &"    ".repeat(depth) + "}"
+}
+
+fn ts_type(param_type: &TypeExpr, depth: usize) -> String {
+    match param_type {
+        TypeExpr::Named(name) => match name.as_str() {
+            "string" | "uuid" | "datetime" | "email" | "url" => "string".to_string(),
+            "int" | "int64" | "float" => "number".to_string(),
+            "bool" => "boolean".to_string(),
+            "decimal" => "number | string".to_string(),
+            _ => pascal_case(name),
+        },
+        TypeExpr::List(item) => format!("Array<{}>", ts_type(item, depth)),
+        TypeExpr::Map(_, value) => format!("Record<string, {}>", ts_type(value, depth)),
+        TypeExpr::Object(params) => ts_object(params, depth),
+        TypeExpr::Optional(inner) => format!("{} | null", ts_type(inner, depth)),
+        TypeExpr::Enum(variants) => variants
+            .iter()
+            .map(|variant| serde_json::to_string(variant).unwrap())
+            .collect::<Vec<String>>()
+            .join(" | "),
+        TypeExpr::Constrained(inner, _) => ts_type(inner, depth),
+        TypeExpr::Union(variants) => {
+            let indent = "    ".repeat(depth + 1);
+            variants
+                .iter()
+                .map(|variant| {
+                    format!(
+                        "\n{}{}| {{ {}: {} }}",
+                        ts_doc(&variant.doc, &indent),
+                        indent,
+                        ts_field_name(&variant.param_name),
+                        ts_type(&variant.param_type, depth + 1)
+                    )
+                })
+                .collect()
+        }
+    }
+}
+
+fn ts_declaration(name: &str, doc: &Option<String>, declared: &TypeExpr) -> String {
+    let declared = ts_type(declared, 0);
+    let separator = if declared.starts_with('\n') { "" } else { " " };
+    format!("{}export type {} ={}{};\n", ts_doc(doc, ""), name, separator, declared)
+}
+
+fn typescript(policy: &Policy) -> String {
+    let declarations: Vec<String> = declarations(policy)
+        .iter()
+        .map(|declaration| ts_declaration(&declaration.name, declaration.doc, &declaration.declared))
+        .collect();
+    format!("// Generated by `bus generate-types`, do not edit.\n\n{}", declarations.join("\n"))
+}
+
+/// Bounds are written as they read in the policy, so large or long ones keep every digit.
+fn json_number(value: &Decimal) -> Value {
+    serde_json::from_str(&value.to_string()).unwrap_or(Value::Null)
+}
+
+fn json_object(types: &Types, params: &[MsgParam]) -> Value {
+    let mut properties = Map::new();
+    let mut required = Vec::new();
+    for param in params {
+        let mut schema = json_type(types, &param.param_type);
+        if let Some(doc) = &param.doc {
+            schema["description"] = json!(doc);
+        }
+        properties.insert(param.param_name.clone(), schema);
+        if !is_optional(types, &param.param_type) {
+            required.push(json!(param.param_name));
+        }
+    }
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+/// Adds the JSON Schema keywords of a range, e.g. `minLength` for strings or `exclusiveMinimum` for numbers.
+fn json_range(schema: &mut Value, target: RangeTarget, min: &Option<Bound>, max: &Option<Bound>) {
+    let (min_keyword, max_keyword) = match target {
+        RangeTarget::Characters => ("minLength", "maxLength"),
+        RangeTarget::Items => ("minItems", "maxItems"),
+        RangeTarget::Entries => ("minProperties", "maxProperties"),
+        RangeTarget::Number => {
+            if let Some(min) = min {
+                let keyword = if min.inclusive { "minimum" } else { "exclusiveMinimum" };
+                schema[keyword] = json_number(&min.value);
+            }
+            if let Some(max) = max {
+                let keyword = if max.inclusive { "maximum" } else { "exclusiveMaximum" };
+                schema[keyword] = json_number(&max.value);
+            }
+            return;
+        }
+    };
+    // Lengths are whole numbers, so exclusive bounds become the next count in.
+    if let Some(min) = min {
+        let value = if min.inclusive { min.value.ceil() } else { min.value.floor().map(|floor| floor + 1) };
+        schema[min_keyword] = json!(value);
+    }
+    if let Some(max) = max {
+        let value = if max.inclusive { max.value.floor() } else { max.value.ceil().map(|ceil| ceil - 1) };
+        schema[max_keyword] = json!(value);
+    }
+}
+
+fn json_type(types: &Types, param_type: &TypeExpr) -> Value {
+    match param_type {
+        TypeExpr::Named(name) => match name.as_str() {
+            "string" => json!({ "type": "string" }),
+            "uuid" => json!({ "type": "string", "format": "uuid" }),
+            "datetime" => json!({ "type": "string", "format": "date-time" }),
+            "email" => json!({ "type": "string", "format": "email" }),
+            "url" => json!({ "type": "string", "format": "uri" }),
+            "int" => json!({ "type": "integer", "minimum": i32::MIN, "maximum": i32::MAX }),
+            "int64" => json!({ "type": "integer" }),
+            "float" => json!({ "type": "number" }),
+            "bool" => json!({ "type": "boolean" }),
+            "decimal" => json!({ "type": ["number", "string"], "pattern": "^-?[0-9]+(\\.[0-9]+)?$" }),
+            _ => json!({ "$ref": format!("#/$defs/{}", pascal_case(name)) }),
+        },
+        TypeExpr::List(item) => json!({ "type": "array", "items": json_type(types, item) }),
+        TypeExpr::Map(key, value) => json!({
+            "type": "object",
+            "propertyNames": json_type(types, key),
+            "additionalProperties": json_type(types, value),
+        }),
+        TypeExpr::Object(params) => json_object(types, params),
+        TypeExpr::Optional(inner) => json!({ "anyOf": [json_type(types, inner), { "type": "null" }] }),
+        TypeExpr::Enum(variants) => json!({ "type": "string", "enum": variants }),
+        TypeExpr::Union(variants) => {
+            let variants: Vec<Value> = variants
+                .iter()
+                .map(|variant| json_object(types, std::slice::from_ref(variant)))
+                .collect();
+            json!({ "oneOf": variants })
+        }
+        TypeExpr::Constrained(inner, constraints) => {
+            let mut schema = json_type(types, inner);
+            if schema.get("$ref").is_some() {
+                schema = json!({ "allOf": [schema] });
+            }
+            for constraint in constraints {
+                match constraint {
+                    Constraint::Range { min, max } => {
+                        if let Some(target) = range_target(types, inner) {
+                            json_range(&mut schema, target, min, max);
+                        }
+                    }
+                    Constraint::Pattern(regex) => schema["pattern"] = json!(regex.as_str()),
+                }
+            }
+            schema
+        }
+    }
+}
+
+fn json_schema(policy: &Policy) -> String {
+    let mut defs = Map::new();
+    for declaration in declarations(policy) {
+        let mut schema = json_type(&policy.types, &declaration.declared);
+        if let Some(doc) = declaration.doc {
+            schema["description"] = json!(doc);
+        }
+        defs.insert(declaration.name, schema);
+    }
+    let schema = json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$comment": "Generated by `bus generate-types`, do not edit.",
+        "$defs": defs,
+    });
+    serde_json::to_string_pretty(&schema).unwrap() + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, Language};
+    use crate::resolver::load;
+
+    #[test]
+    fn test_typescript() {
+        let policy = load("
+            /// Where orders are shipped to.
+            type Address {
+                street: string
+                zip-code: string?
+            }
+            alias OrderId = uuid
+            enum OrderStatus { pending paid }
+            union Payment {
+                card: { last4: string }
+                pix: Pix
+            }
+            type Pix {
+                key: string
+            }
+            request place-order {
+                id: OrderId
+                to: Address
+                tags: map<string, list<int>>
+            }
+            allow backend broadcast order-updated {
+                status: OrderStatus
+                payment: Payment
+            }
+        ").unwrap();
+        assert_eq!(generate(&policy, Language::TypeScript), r#"// Generated by `bus generate-types`, do not edit.
+
+/** Where orders are shipped to. */
+export type Address = {
+    street: string;
+    "zip-code"?: string | null;
+};
+
+export type OrderId = string;
+
+export type OrderStatus = "pending" | "paid";
+
+export type Payment =
+    | { card: {
+        last4: string;
+    } }
+    | { pix: Pix };
+
+export type Pix = {
+    key: string;
+};
+
+export type PlaceOrderRequest = {
+    id: OrderId;
+    to: Address;
+    tags: Record<string, Array<number>>;
+};
+
+export type OrderUpdated = {
+    status: OrderStatus;
+    payment: Payment;
+};
+"#);
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let policy = load("
+            alias Sku = string /^[A-Z0-9-]+$/
+            /// Places an order.
+            request place-order {
+                name: string(1..120)
+                qty: int(>=1)
+                skus: list<Sku>(1..)
+                price: decimal(>0)
+                note: string(<10)?
+                id: int64(<=9007199254740993)
+            }
+        ").unwrap();
+        let schema: serde_json::Value = serde_json::from_str(&generate(&policy, Language::JsonSchema)).unwrap();
+        let defs = &schema["$defs"];
+        assert_eq!(defs["Sku"], serde_json::json!({ "type": "string", "pattern": "^[A-Z0-9-]+$" }));
+        let request = &defs["PlaceOrderRequest"];
+        assert_eq!(request["description"], "Places an order.");
+        assert_eq!(request["required"], serde_json::json!(["name", "qty", "skus", "price", "id"]));
+        let properties = &request["properties"];
+        assert_eq!(properties["name"], serde_json::json!({ "type": "string", "minLength": 1, "maxLength": 120 }));
+        assert_eq!(properties["qty"]["minimum"], 1);
+        assert_eq!(properties["skus"]["items"], serde_json::json!({ "$ref": "#/$defs/Sku" }));
+        assert_eq!(properties["skus"]["minItems"], 1);
+        assert_eq!(properties["price"]["exclusiveMinimum"], 0);
+        assert_eq!(properties["note"]["anyOf"][0]["maxLength"], 9);
+        assert_eq!(properties["id"]["maximum"].to_string(), "9007199254740993");
+    }
+}
@@ -1,43 +1,27 @@
-use std::collections::HashMap as Map;
 use std::error::Error;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::fmt;
 use serde::de::{self, MapAccess, Visitor};
+use serde::ser::SerializeMap;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use crate::policy::{BroadcastStmt, MsgStmt, RequestStmt, ResponseStmt, Statement, MsgParam};
+use serde_json::{Map, Value};
+use crate::channel;
+use crate::scalars::{scalar_type, Decimal};
+use crate::policy::{Bound, BroadcastStmt, Constraint, MsgStmt, MsgType, RequestStmt, ResponseStmt, Statement, MsgParam, TypeExpr};
+use crate::resolver::{broadcast_stmts, is_optional, range_target, Policy, RangeTarget, Types};
 
-#[derive(Debug, Deserialize, Clone)]
-#[serde(untagged)]
-pub enum ParamType {
-    String(String),
-    Int(i32),
-    Float(f32),
-    Bool(bool),
-}
-
-impl Serialize for ParamType {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        match self {
-            ParamType::String(s) => serializer.serialize_str(s),
-            ParamType::Int(i) => serializer.serialize_i32(*i),
-            ParamType::Float(f) => serializer.serialize_f32(*f),
-            ParamType::Bool(b) => serializer.serialize_bool(*b),
-        }
-    }
-}
-
-type Params = Map<String, ParamType>;
+pub type Params = Map<String, Value>;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct RequestMessage {
+    pub id: Option<String>,
     pub payload: Params,
     pub channel: String,
+    pub timeout_ms: Option<u64>,
 }
 #[derive(Debug, Deserialize, Clone)]
 pub struct ResponseMessage {
+    pub id: Option<String>,
     pub payload: Params,
     pub channel: String,
 }
@@ -53,12 +37,55 @@ pub struct ListenMessage {
     pub channel: String,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct HandleMessage {
+    pub channel: String,
+}
+
 #[derive(Debug, Clone)]
 pub enum Message {
     Request(RequestMessage),
     Response(ResponseMessage),
     Broadcast(BroadcastMessage),
     Listen(ListenMessage),
+    Handle(HandleMessage),
+}
+
+impl Serialize for Message {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+        match self {
+            Message::Request(request) => {
+                map.serialize_entry("type", "request")?;
+                map.serialize_entry("id", &request.id)?;
+                map.serialize_entry("channel", &request.channel)?;
+                map.serialize_entry("payload", &request.payload)?;
+            }
+            Message::Response(response) => {
+                map.serialize_entry("type", "response")?;
+                map.serialize_entry("id", &response.id)?;
+                map.serialize_entry("channel", &response.channel)?;
+                map.serialize_entry("payload", &response.payload)?;
+            }
+            Message::Broadcast(event) => {
+                map.serialize_entry("type", "broadcast")?;
+                map.serialize_entry("channel", &event.channel)?;
+                map.serialize_entry("payload", &event.payload)?;
+            }
+            Message::Listen(event) => {
+                map.serialize_entry("type", "listen")?;
+                map.serialize_entry("channel", &event.channel)?;
+            }
+            Message::Handle(event) => {
+                map.serialize_entry("type", "handle")?;
+                map.serialize_entry("channel", &event.channel)?;
+            }
+        }
+        map.end()
+    }
 }
 
 impl<'de> Deserialize<'de> for Message {
@@ -80,6 +107,8 @@ impl<'de> Deserialize<'de> for Message {
                 A: MapAccess<'de>,
             {
                 let mut message_type: Option<String> = None;
+                let mut id: Option<String> = None;
+                let mut timeout_ms: Option<u64> = None;
                 let mut payload: Option<Params> = None;
                 let mut channel: Option<String> = None; // Add this line
 
@@ -88,6 +117,12 @@ impl<'de> Deserialize<'de> for Message {
                         "type" => {
                             message_type = Some(map.next_value()?);
                         }
+                        "id" => {
+                            id = map.next_value()?;
+                        }
+                        "timeout_ms" => {
+                            timeout_ms = map.next_value()?;
+                        }
                         "payload" => {
                             payload = Some(map.next_value()?);
                         }
@@ -100,16 +135,17 @@ impl<'de> Deserialize<'de> for Message {
                 }
 
                 let message_type = message_type.ok_or_else(|| de::Error::missing_field("type"))?;
-                let payload = payload.ok_or_else(|| de::Error::missing_field("payload"))?;
+                let payload = || payload.ok_or_else(|| de::Error::missing_field("payload"));
                 let channel = channel.ok_or_else(|| de::Error::missing_field("channel"))?;
                 match message_type.as_str() {
-                    "request" => Ok(Message::Request(RequestMessage { payload, channel })),
-                    "response" => Ok(Message::Response(ResponseMessage { payload, channel })),
-                    "broadcast" => Ok(Message::Broadcast(BroadcastMessage { payload, channel })),
+                    "request" => Ok(Message::Request(RequestMessage { id, payload: payload()?, channel, timeout_ms })),
+                    "response" => Ok(Message::Response(ResponseMessage { id, payload: payload()?, channel })),
+                    "broadcast" => Ok(Message::Broadcast(BroadcastMessage { payload: payload()?, channel })),
                     "listen" => Ok(Message::Listen(ListenMessage { channel })),
+                    "handle" => Ok(Message::Handle(HandleMessage { channel })),
                     _ => Err(de::Error::unknown_variant(
                         &message_type,
-                        &["request", "response", "broadcast", "listen"],
+                        &["request", "response", "broadcast", "listen", "handle"],
                     )),
                 }
             }
@@ -119,6 +155,48 @@ impl<'de> Deserialize<'de> for Message {
     }
 }
 
+impl Message {
+    /// Registering as a handler is checked against the `response` rules. It carries no payload, so
+    /// `when` clauses are left to each response sent afterwards.
+    pub fn msg_type(&self) -> MsgType {
+        match self {
+            Message::Request(_) => MsgType::Request,
+            Message::Response(_) => MsgType::Response,
+            Message::Broadcast(_) => MsgType::Broadcast,
+            Message::Listen(_) => MsgType::Listen,
+            Message::Handle(_) => MsgType::Response,
+        }
+    }
+
+    /// Listen and handle frames carry no payload.
+    pub fn payload(&self) -> Option<&Params> {
+        match self {
+            Message::Request(request) => Some(&request.payload),
+            Message::Response(response) => Some(&response.payload),
+            Message::Broadcast(broadcast) => Some(&broadcast.payload),
+            Message::Listen(_) | Message::Handle(_) => None,
+        }
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Message::Request(request) => request.id.as_deref(),
+            Message::Response(response) => response.id.as_deref(),
+            _ => None,
+        }
+    }
+
+    pub fn channel(&self) -> &str {
+        match self {
+            Message::Request(request) => &request.channel,
+            Message::Response(response) => &response.channel,
+            Message::Broadcast(event) => &event.channel,
+            Message::Listen(event) => &event.channel,
+            Message::Handle(event) => &event.channel,
+        }
+    }
+}
+
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -126,160 +204,466 @@ impl fmt::Display for Message {
             Message::Response(response) => write!(f, "Response: {:?}", response),
             Message::Broadcast(event) => write!(f, "Broadcast: {:?}", event),
             Message::Listen(event) => write!(f, "Listen: {:?}", event),
+            Message::Handle(event) => write!(f, "Handle: {:?}", event),
         }
     }
 }
 
-#[derive(Debug)]
-pub struct InvalidChannelError;
-impl Error for InvalidChannelError {}
-impl Display for InvalidChannelError {
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub reason: String,
+}
+impl Error for ParseError {}
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "Could not parse message: {}", self.reason)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnknownChannelError {
+    pub msg_type: MsgType,
+    pub channel: String,
+}
+impl Error for UnknownChannelError {}
+impl Display for UnknownChannelError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "No {} channel named {}", self.msg_type, self.channel)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnauthorizedError {
+    pub role_name: String,
+    pub msg_type: MsgType,
+    pub channel: String,
+}
+impl Error for UnauthorizedError {}
+impl Display for UnauthorizedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "Role {} is not allowed to {} on {}", self.role_name, self.msg_type, self.channel)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SchemaViolationError {
+    pub path: String,
+    pub reason: String,
+}
+impl Error for SchemaViolationError {}
+impl Display for SchemaViolationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "Invalid payload at {}: {}", self.path, self.reason)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitedError {
+    pub limit: u32,
+}
+impl Error for RateLimitedError {}
+impl Display for RateLimitedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "Rate limit of {} messages per second exceeded", self.limit)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TimeoutError;
+impl Error for TimeoutError {}
+impl Display for TimeoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "Request timed out")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnavailableError {
+    pub channel: String,
+}
+impl Error for UnavailableError {}
+impl Display for UnavailableError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "No responder available for {}", self.channel)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnknownRequestError {
+    pub id: Option<String>,
+}
+impl Error for UnknownRequestError {}
+impl Display for UnknownRequestError {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        write!(f, "Invalid channel")
+        match &self.id {
+            Some(id) => write!(f, "No pending request with id {}", id),
+            None => write!(f, "Response is missing the request id"),
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct InvalidMessageTypeError {
-    given_type: String
+#[derive(Debug, Clone)]
+pub struct ChannelMismatchError {
+    pub channel: String,
+    pub expected: String,
 }
-impl Error for InvalidMessageTypeError {}
-impl Display for InvalidMessageTypeError {
+impl Error for ChannelMismatchError {}
+impl Display for ChannelMismatchError {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        write!(f, "Invalid message type")
+        write!(f, "Response on {} answers a request on {}", self.channel, self.expected)
     }
 }
 
-#[derive(Debug)]
+/// Every error a client can receive. `code` is part of the wire protocol and must stay stable.
+#[derive(Debug, Clone)]
 pub enum MessageError {
-    InvalidChannelError(InvalidChannelError),
-    InvalidMessageTypeError(InvalidMessageTypeError),
-    InvalidMessageError(Message),
-    InvalidParameterError(Params),
+    Parse(ParseError),
+    UnknownChannel(UnknownChannelError),
+    Unauthorized(UnauthorizedError),
+    SchemaViolation(SchemaViolationError),
+    RateLimited(RateLimitedError),
+    Timeout(TimeoutError),
+    Unavailable(UnavailableError),
+    UnknownRequest(UnknownRequestError),
+    ChannelMismatch(ChannelMismatchError),
+}
+
+impl MessageError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            MessageError::Parse(_) => "parse_error",
+            MessageError::UnknownChannel(_) => "unknown_channel",
+            MessageError::Unauthorized(_) => "unauthorized",
+            MessageError::SchemaViolation(_) => "schema_violation",
+            MessageError::RateLimited(_) => "rate_limited",
+            MessageError::Timeout(_) => "timeout",
+            MessageError::Unavailable(_) => "unavailable",
+            MessageError::UnknownRequest(_) => "unknown_request",
+            MessageError::ChannelMismatch(_) => "channel_mismatch",
+        }
+    }
+}
+
+impl Error for MessageError {}
+impl Display for MessageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            MessageError::Parse(err) => write!(f, "{}", err),
+            MessageError::UnknownChannel(err) => write!(f, "{}", err),
+            MessageError::Unauthorized(err) => write!(f, "{}", err),
+            MessageError::SchemaViolation(err) => write!(f, "{}", err),
+            MessageError::RateLimited(err) => write!(f, "{}", err),
+            MessageError::Timeout(err) => write!(f, "{}", err),
+            MessageError::Unavailable(err) => write!(f, "{}", err),
+            MessageError::UnknownRequest(err) => write!(f, "{}", err),
+            MessageError::ChannelMismatch(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// The JSON frame sent back to the client, e.g.
+/// `{"type": "error", "id": "1", "code": "schema_violation", "message": "...", "path": "/name"}`.
+pub struct ErrorFrame<'a> {
+    pub id: Option<&'a str>,
+    pub error: &'a MessageError,
 }
 
-fn validate_parameters(stmt_params: &Vec<MsgParam>, message_params: &Params) -> Option<MessageError> {
+impl Serialize for ErrorFrame<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("type", "error")?;
+        if let Some(id) = self.id {
+            map.serialize_entry("id", id)?;
+        }
+        map.serialize_entry("code", self.error.code())?;
+        map.serialize_entry("message", &self.error.to_string())?;
+        match self.error {
+            MessageError::UnknownChannel(err) => map.serialize_entry("channel", &err.channel)?,
+            MessageError::Unauthorized(err) => map.serialize_entry("channel", &err.channel)?,
+            MessageError::Unavailable(err) => map.serialize_entry("channel", &err.channel)?,
+            MessageError::ChannelMismatch(err) => map.serialize_entry("channel", &err.channel)?,
+            MessageError::SchemaViolation(err) => map.serialize_entry("path", &err.path)?,
+            _ => {}
+        }
+        map.end()
+    }
+}
+
+fn schema_violation(path: &str, reason: &str) -> Option<MessageError> {
+    Some(MessageError::SchemaViolation(SchemaViolationError {
+        path: path.to_string(),
+        reason: reason.to_string(),
+    }))
+}
+
+/// Escapes a field name for use as a JSON pointer segment (RFC 6901).
+fn pointer(path: &str, segment: &str) -> String {
+    format!("{}/{}", path, segment.replace('~', "~0").replace('/', "~1"))
+}
+
+fn validate_value(types: &Types, param_type: &TypeExpr, value: &Value, path: &str) -> Option<MessageError> {
+    match (param_type, value) {
+        (TypeExpr::Optional(_), Value::Null) => None,
+        (TypeExpr::Optional(inner), _) => validate_value(types, inner, value, path),
+        (TypeExpr::Named(name), _) => match (scalar_type(name), types.get(name)) {
+            (Some(scalar), _) if scalar.check(value) => None,
+            (Some(scalar), _) => schema_violation(path, &format!("expected {}", scalar.description)),
+            (None, Some(declared)) => validate_value(types, declared, value, path),
+            (None, None) => schema_violation(path, &format!("unknown type {}", name)),
+        },
+        (TypeExpr::List(item), Value::Array(items)) => items
+            .iter()
+            .enumerate()
+            .find_map(|(index, value)| validate_value(types, item, value, &pointer(path, &index.to_string()))),
+        (TypeExpr::Map(key, item), Value::Object(entries)) => entries.iter().find_map(|(name, value)| {
+            let path = pointer(path, name);
+            validate_value(types, key, &Value::String(name.clone()), &path)
+                .or_else(|| validate_value(types, item, value, &path))
+        }),
+        (TypeExpr::Object(params), Value::Object(fields)) => validate_fields(types, params, fields, path),
+        (TypeExpr::Enum(variants), Value::String(variant)) if variants.contains(variant) => None,
+        (TypeExpr::Enum(variants), _) => schema_violation(path, &format!("expected one of {}", variants.join(", "))),
+        (TypeExpr::Union(variants), Value::Object(fields)) if fields.len() == 1 => {
+            let (tag, value) = fields.iter().next().unwrap();
+            match variants.iter().find(|variant| variant.param_name == *tag) {
+                Some(variant) => validate_value(types, &variant.param_type, value, &pointer(path, tag)),
+                None => schema_violation(&pointer(path, tag), &format!("expected one of {}", variant_names(variants))),
+            }
+        }
+        (TypeExpr::Union(variants), _) => schema_violation(
+            path,
+            &format!("expected an object with exactly one of {}", variant_names(variants)),
+        ),
+        (TypeExpr::Constrained(inner, constraints), _) => validate_value(types, inner, value, path).or_else(|| {
+            constraints
+                .iter()
+                .find_map(|constraint| validate_constraint(types, inner, constraint, value, path))
+        }),
+        (TypeExpr::List(_), _) => schema_violation(path, "expected list"),
+        (TypeExpr::Map(_, _), _) => schema_violation(path, "expected map"),
+        (TypeExpr::Object(_), _) => schema_violation(path, "expected object"),
+    }
+}
+
+fn describe_range(target: RangeTarget, min: &Option<Bound>, max: &Option<Bound>) -> String {
+    let bounds = match (min, max) {
+        (Some(min), Some(max)) if min.inclusive && max.inclusive && min.value == max.value => {
+            format!("exactly {}", min.value)
+        }
+        (Some(min), Some(max)) if min.inclusive && max.inclusive => format!("between {} and {}", min.value, max.value),
+        _ => {
+            let min = min.as_ref().map(|min| format!("{} {}", if min.inclusive { "at least" } else { "more than" }, min.value));
+            let max = max.as_ref().map(|max| format!("{} {}", if max.inclusive { "at most" } else { "less than" }, max.value));
+            min.into_iter().chain(max).collect::<Vec<String>>().join(" and ")
+        }
+    };
+    match target {
+        RangeTarget::Characters => format!("{} characters", bounds),
+        RangeTarget::Items => format!("{} items", bounds),
+        RangeTarget::Entries => format!("{} entries", bounds),
+        RangeTarget::Number => bounds,
+    }
+}
+
+fn in_range(measured: &Decimal, min: &Option<Bound>, max: &Option<Bound>) -> bool {
+    let above_min = match min {
+        Some(min) if min.inclusive => *measured >= min.value,
+        Some(min) => *measured > min.value,
+        None => true,
+    };
+    let below_max = match max {
+        Some(max) if max.inclusive => *measured <= max.value,
+        Some(max) => *measured < max.value,
+        None => true,
+    };
+    above_min && below_max
+}
+
+/// Runs after the value matched its type, so mismatched kinds (e.g. `null` for an optional) are skipped.
+fn validate_constraint(
+    types: &Types,
+    param_type: &TypeExpr,
+    constraint: &Constraint,
+    value: &Value,
+    path: &str,
+) -> Option<MessageError> {
+    match constraint {
+        Constraint::Pattern(regex) => match value.as_str() {
+            Some(text) if !regex.is_match(text) => schema_violation(path, &format!("expected to match {}", constraint)),
+            _ => None,
+        },
+        Constraint::Range { min, max } => {
+            let target = range_target(types, param_type)?;
+            let measured = match (target, value) {
+                (RangeTarget::Characters, Value::String(text)) => Decimal::from(text.chars().count()),
+                (RangeTarget::Items, Value::Array(items)) => Decimal::from(items.len()),
+                (RangeTarget::Entries, Value::Object(entries)) => Decimal::from(entries.len()),
+                (RangeTarget::Number, Value::Number(number)) => Decimal::parse(&number.to_string())?,
+                (RangeTarget::Number, Value::String(decimal)) => Decimal::parse(decimal)?,
+                _ => return None,
+            };
+            if in_range(&measured, min, max) {
+                None
+            } else {
+                schema_violation(path, &format!("expected {}", describe_range(target, min, max)))
+            }
+        }
+    }
+}
+
+fn variant_names(variants: &[MsgParam]) -> String {
+    variants.iter().map(|variant| variant.param_name.as_str()).collect::<Vec<&str>>().join(", ")
+}
+
+fn validate_fields(types: &Types, stmt_params: &[MsgParam], fields: &Params, path: &str) -> Option<MessageError> {
     for stmt_param in stmt_params {
-        match message_params.get(&stmt_param.param_name) {
+        let path = pointer(path, &stmt_param.param_name);
+        match fields.get(&stmt_param.param_name) {
             Some(value) => {
-                match (stmt_param.param_type.as_str(), value) {
-                    ("string", ParamType::String(_)) => {}
-                    ("int", ParamType::Int(_)) => {}
-                    ("float", ParamType::Float(_)) => {}
-                    ("bool", ParamType::Bool(_)) => {}
-                    _ => {
-                        return Some(MessageError::InvalidParameterError(message_params.clone()));
-                    }
+                if let Some(err) = validate_value(types, &stmt_param.param_type, value, &path) {
+                    return Some(err);
                 }
             }
+            None if is_optional(types, &stmt_param.param_type) => {}
             None => {
-                return Some(MessageError::InvalidParameterError(message_params.clone()));
+                return schema_violation(&path, "missing required field");
             }
         }
     }
 
-    for key in message_params.keys() {
-        if stmt_params.iter().find(|&x| x.param_name == *key).is_none() {
-            return Some(MessageError::InvalidParameterError(message_params.clone()));
+    for key in fields.keys() {
+        if !stmt_params.iter().any(|x| x.param_name == *key) {
+            return schema_violation(&pointer(path, key), "unexpected field");
         }
     }
 
     None
 }
 
-fn validate_request<'a>(stmt: &'a RequestStmt, message: &'a RequestMessage) -> Option<MessageError> {
-    println!("stmt.msg_name: {}, message.channel: {}", stmt.msg_name, message.channel);
-    println!("stmt.msg_name == message.channel: {}", stmt.msg_name == message.channel);
+fn validate_parameters(types: &Types, stmt_params: &[MsgParam], message_params: &Params) -> Option<MessageError> {
+    validate_fields(types, stmt_params, message_params, "")
+}
+
+fn unknown_channel(msg_type: MsgType, channel: &str) -> Option<MessageError> {
+    Some(MessageError::UnknownChannel(UnknownChannelError {
+        msg_type,
+        channel: channel.to_string(),
+    }))
+}
+
+fn validate_request<'a>(types: &Types, stmt: &'a RequestStmt, message: &'a RequestMessage) -> Option<MessageError> {
     if stmt.msg_name == message.channel {
-        return validate_parameters(&stmt.msg_params, &message.payload);
+        validate_parameters(types, &stmt.msg_params, &message.payload)
     } else {
-        Some(MessageError::InvalidChannelError(InvalidChannelError))
+        unknown_channel(MsgType::Request, &message.channel)
     }
 }
 
-fn validate_response<'a>(stmt: &'a ResponseStmt, message: &'a ResponseMessage) -> Option<MessageError> {
+fn validate_response<'a>(types: &Types, stmt: &'a ResponseStmt, message: &'a ResponseMessage) -> Option<MessageError> {
     if stmt.msg_name == message.channel {
-        return validate_parameters(&stmt.msg_params, &message.payload);
+        validate_parameters(types, &stmt.msg_params, &message.payload)
     } else {
-        Some(MessageError::InvalidChannelError(InvalidChannelError))
+        unknown_channel(MsgType::Response, &message.channel)
     }
 }
 
-fn validate_broadcast<'a>(stmt: &'a BroadcastStmt, message: &'a BroadcastMessage) -> Option<MessageError> {
+fn validate_broadcast<'a>(types: &Types, stmt: &'a BroadcastStmt, message: &'a BroadcastMessage) -> Option<MessageError> {
     if stmt.msg_name == message.channel {
-        return validate_parameters(&stmt.msg_params, &message.payload);
+        validate_parameters(types, &stmt.msg_params, &message.payload)
     } else {
-        Some(MessageError::InvalidChannelError(InvalidChannelError))
+        unknown_channel(MsgType::Broadcast, &message.channel)
+    }
+}
+
+fn find_broadcast_stmt<'a>(policy: &'a [Statement], channel: &str) -> Option<&'a BroadcastStmt> {
+    broadcast_stmts(policy).find(|broadcast_stmt| broadcast_stmt.msg_name == channel)
+}
+
+/// Routes a broadcast to the instance of its channel named by the bound fields, e.g. `user-updated/42`.
+fn bind_channel(policy: &[Statement], mut message: BroadcastMessage) -> BroadcastMessage {
+    if let Some(broadcast_stmt) = find_broadcast_stmt(policy, &message.channel) {
+        for (field, _) in &broadcast_stmt.bound_fields {
+            let value = message.payload.get(field).unwrap_or(&Value::Null);
+            message.channel = format!("{}/{}", message.channel, channel::encode(value));
+        }
     }
+    message
 }
 
-fn validate_message(policy: Vec<Statement>, message: &Message) -> Option<MessageError> {
-    let mut matched = false;
+fn validate_message(policy: &Policy, message: &Message) -> Option<MessageError> {
+    let Policy { statements, types, .. } = policy;
+    match message {
+        Message::Broadcast(broadcast_message) => {
+            return match find_broadcast_stmt(statements, &broadcast_message.channel) {
+                Some(broadcast_stmt) => validate_broadcast(types, broadcast_stmt, broadcast_message),
+                None => unknown_channel(MsgType::Broadcast, &broadcast_message.channel),
+            };
+        }
+        Message::Listen(listen_message) => {
+            let (name, values) = channel::split_instance(&listen_message.channel);
+            if broadcast_stmts(statements).any(|broadcast_stmt| {
+                channel::matches(name, &broadcast_stmt.msg_name) && values.len() <= broadcast_stmt.bound_fields.len()
+            }) {
+                return None;
+            }
+            return unknown_channel(MsgType::Listen, &listen_message.channel);
+        }
+        _ => {}
+    }
 
-    for stmt in &policy {
+    for stmt in statements {
         match (stmt, message) {
             (
-                Statement::Msg(MsgStmt::Request(ref req_stmt)),
-                &Message::Request(ref request_message),
-            ) => {
-                if let Some(err) = validate_request(req_stmt, request_message) {
-                    return Some(err);
-                } else {
-                    matched = true;
-                }
+                Statement::Msg(MsgStmt::Request(req_stmt)),
+                Message::Request(request_message),
+            ) if req_stmt.msg_name == request_message.channel => {
+                return validate_request(types, req_stmt, request_message);
             }
-
             (
-                Statement::Msg(MsgStmt::Response(ref res_stmt)),
-                &Message::Response(ref response_message),
-            ) => {
-                if let Some(err) = validate_response(res_stmt, response_message) {
-                    return Some(err);
-                } else {
-                    matched = true;
-                }
+                Statement::Msg(MsgStmt::Response(res_stmt)),
+                Message::Response(response_message),
+            ) if res_stmt.msg_name == response_message.channel => {
+                return validate_response(types, res_stmt, response_message);
             }
-
             (
-                Statement::Msg(MsgStmt::Broadcast(ref broadcast_stmt)),
-                &Message::Broadcast(ref broadcast_message),
-            ) => {
-                if let Some(err) = validate_broadcast(broadcast_stmt, broadcast_message) {
-                    return Some(err);
-                } else {
-                    matched = true;
-                }
+                Statement::Msg(MsgStmt::Request(req_stmt)),
+                Message::Handle(handle_message),
+            ) if req_stmt.msg_name == handle_message.channel => {
+                return None;
             }
-            (_, &Message::Listen(_)) => (),
             _ => {}
         }
-
-        if matched {
-            break;
-        }
     }
 
-    if matched {
-        None
-    } else {
-        Some(MessageError::InvalidMessageError(message.clone()))
-    }
+    unknown_channel(message.msg_type(), message.channel())
 }
 
 pub fn message_from_str(
-    policy: Vec<Statement>,
+    policy: &Policy,
     message: &str,
 ) -> Result<Message, MessageError> {
-    let message: Message = serde_json::from_str(message).unwrap();
-    match validate_message(policy, &message) {
-        Some(err) => Err(err),
-        None => Ok(message),
+    let message: Message = serde_json::from_str(message).map_err(|err| {
+        MessageError::Parse(ParseError {
+            reason: err.to_string(),
+        })
+    })?;
+    match (validate_message(policy, &message), message) {
+        (Some(err), _) => Err(err),
+        (None, Message::Broadcast(broadcast_message)) => {
+            Ok(Message::Broadcast(bind_channel(&policy.statements, broadcast_message)))
+        }
+        (None, message) => Ok(message),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::policy::{Statement, MsgStmt, RequestStmt};
+    use crate::policy::{Statement, MsgStmt, RequestStmt, Span, TypeExpr};
+    use crate::resolver::{Policy, Types};
+    use std::collections::HashMap;
 
     fn validate_request_io(stmt: RequestStmt, message: &str, expected_error: bool) {
         let message = serde_json::from_str::<crate::validator::Message>(message).unwrap();
@@ -287,7 +671,7 @@ mod tests {
             crate::validator::Message::Request(req) => req,
             _ => panic!("Invalid message type")
         };
-        let result = crate::validator::validate_request(&stmt, &req_message);
+        let result = crate::validator::validate_request(&Types::new(), &stmt, &req_message);
         assert_eq!(!expected_error, result.is_none());
     }
 
@@ -299,13 +683,20 @@ mod tests {
                 msg_params: vec![
                     crate::policy::MsgParam {
                         param_name: "a".to_string(),
-                        param_type: "string".to_string(),
+                        param_type: TypeExpr::Named("string".to_string()),
+                        type_span: Span::default(),
+                        doc: None,
                     },
                     crate::policy::MsgParam {
                         param_name: "b".to_string(),
-                        param_type: "int".to_string(),
+                        param_type: TypeExpr::Named("int".to_string()),
+                        type_span: Span::default(),
+                        doc: None,
                     }
                 ],
+                timeout: None,
+                doc: None,
+                span: Span::default(),
             }, 
             r#"{
                 "type": "request",
@@ -327,13 +718,20 @@ mod tests {
                 msg_params: vec![
                     crate::policy::MsgParam {
                         param_name: "a".to_string(),
-                        param_type: "string".to_string(),
+                        param_type: TypeExpr::Named("string".to_string()),
+                        type_span: Span::default(),
+                        doc: None,
                     },
                     crate::policy::MsgParam {
                         param_name: "b".to_string(),
-                        param_type: "int".to_string(),
+                        param_type: TypeExpr::Named("int".to_string()),
+                        type_span: Span::default(),
+                        doc: None,
                     }
                 ],
+                timeout: None,
+                doc: None,
+                span: Span::default(),
             }, 
             r#"{
                 "type": "request",
@@ -354,9 +752,14 @@ mod tests {
                 msg_params: vec![
                     crate::policy::MsgParam {
                         param_name: "a".to_string(),
-                        param_type: "string".to_string(),
+                        param_type: TypeExpr::Named("string".to_string()),
+                        type_span: Span::default(),
+                        doc: None,
                     },
                 ],
+                timeout: None,
+                doc: None,
+                span: Span::default(),
             }, 
             r#"{
                 "type": "request",
@@ -378,13 +781,20 @@ mod tests {
                 msg_params: vec![
                     crate::policy::MsgParam {
                         param_name: "a".to_string(),
-                        param_type: "string".to_string(),
+                        param_type: TypeExpr::Named("string".to_string()),
+                        type_span: Span::default(),
+                        doc: None,
                     },
                     crate::policy::MsgParam {
                         param_name: "b".to_string(),
-                        param_type: "int".to_string(),
+                        param_type: TypeExpr::Named("int".to_string()),
+                        type_span: Span::default(),
+                        doc: None,
                     }
                 ],
+                timeout: None,
+                doc: None,
+                span: Span::default(),
             }, 
             r#"{
                 "type": "request",
@@ -400,19 +810,297 @@ mod tests {
 
     #[test]
     fn test_validate_message() {
-        let policy = vec![
-            Statement::Msg(MsgStmt::Request(RequestStmt {
-                msg_name: "test".to_string(),
-                msg_params: vec![],
-            }))
-        ];
+        let policy = Policy {
+            statements: vec![
+                Statement::Msg(MsgStmt::Request(RequestStmt {
+                    msg_name: "test".to_string(),
+                    msg_params: vec![],
+                    timeout: None,
+                    doc: None,
+                    span: Span::default(),
+                }))
+            ],
+            types: Types::new(),
+            roles: HashMap::new(),
+        };
         let message = r#"{
             "type": "request",
             "payload": {},
             "channel": "test"
         }"#;
         let message = serde_json::from_str::<crate::validator::Message>(message).unwrap();
-        let result = crate::validator::validate_message(policy, &message);
+        let result = crate::validator::validate_message(&policy, &message);
         assert!(result.is_none());
     }
-}
\ No newline at end of file
+
+    fn message_error(policy: &str, message: &str) -> Option<crate::validator::MessageError> {
+        let policy = crate::resolver::load(policy).unwrap();
+        crate::validator::message_from_str(&policy, message).err()
+    }
+
+    const POLICY: &str = "
+        request first {
+            a: string
+        }
+        request second {
+            b: int
+        }
+    ";
+
+    #[test]
+    fn test_validate_message_second_declaration() {
+        let err = message_error(POLICY, r#"{"type": "request", "payload": {"b": 1}, "channel": "second"}"#);
+        assert!(err.is_none());
+    }
+
+    #[test]
+    fn test_validate_message_unknown_channel() {
+        let err = message_error(POLICY, r#"{"type": "request", "payload": {}, "channel": "third"}"#);
+        assert_eq!(err.unwrap().code(), "unknown_channel");
+    }
+
+    #[test]
+    fn test_validate_message_parse_error() {
+        let err = message_error(POLICY, r#"{"type": "request", "channel": "#);
+        assert_eq!(err.unwrap().code(), "parse_error");
+    }
+
+    #[test]
+    fn test_error_frame() {
+        let err = message_error(POLICY, r#"{"type": "request", "payload": {"b": "1"}, "channel": "second"}"#).unwrap();
+        let frame = serde_json::to_value(crate::validator::ErrorFrame { id: Some("1"), error: &err }).unwrap();
+        assert_eq!(frame["type"], "error");
+        assert_eq!(frame["id"], "1");
+        assert_eq!(frame["code"], "schema_violation");
+        assert_eq!(frame["path"], "/b");
+    }
+
+    #[test]
+    fn test_validate_inline_broadcast_schema() {
+        let policy = "
+            role backend
+            allow backend broadcast user-created {
+                id: string
+            }
+        ";
+        let valid = r#"{"type": "broadcast", "payload": {"id": "1"}, "channel": "user-created"}"#;
+        assert!(message_error(policy, valid).is_none());
+        let invalid = r#"{"type": "broadcast", "payload": {"id": 1}, "channel": "user-created"}"#;
+        assert_eq!(message_error(policy, invalid).unwrap().code(), "schema_violation");
+        let listen = r#"{"type": "listen", "channel": "user-created"}"#;
+        assert!(message_error(policy, listen).is_none());
+    }
+
+    #[test]
+    fn test_validate_semantic_types() {
+        let policy = include_str!("../../examples/example1.bus");
+        let valid = r#"{"type": "broadcast", "channel": "user-created", "payload": {
+            "id": "e1375bc9-0708-4eb9-b3d6-2c46398d2da9",
+            "created": "2023-04-01T12:30:00Z"
+        }}"#;
+        assert!(message_error(policy, valid).is_none());
+        let invalid = r#"{"type": "broadcast", "channel": "user-created", "payload": {
+            "id": "e1375bc9-0708-4eb9-b3d6-2c46398d2da9",
+            "created": "yesterday"
+        }}"#;
+        assert_eq!(
+            message_error(policy, invalid).unwrap().to_string(),
+            "Invalid payload at /created: expected datetime (RFC 3339 timestamp)"
+        );
+    }
+
+    const ORDER_POLICY: &str = "
+        request place-order {
+            items: list<{
+                sku: string
+                qty: int
+            }>
+            notes: string?
+            tags: map<string, list<string>>?
+        }
+    ";
+
+    #[test]
+    fn test_validate_composite_types() {
+        let valid = r#"{"type": "request", "channel": "place-order", "payload": {
+            "items": [{"sku": "A-1", "qty": 2}],
+            "tags": {"gift": ["yes"]}
+        }}"#;
+        assert!(message_error(ORDER_POLICY, valid).is_none());
+        let null_optional = r#"{"type": "request", "channel": "place-order", "payload": {
+            "items": [], "notes": null
+        }}"#;
+        assert!(message_error(ORDER_POLICY, null_optional).is_none());
+    }
+
+    #[test]
+    fn test_validate_composite_types_path() {
+        let cases = [
+            (r#"{"items": [{"sku": "A-1", "qty": 2}, {"sku": "A-2", "qty": "3"}]}"#, "/items/1/qty"),
+            (r#"{"items": [{"sku": "A-1"}]}"#, "/items/0/qty"),
+            (r#"{"items": [{"sku": "A-1", "qty": 2, "price": 1}]}"#, "/items/0/price"),
+            (r#"{"items": {}}"#, "/items"),
+            (r#"{"items": [], "tags": {"a/b": [1]}}"#, "/tags/a~1b/0"),
+        ];
+        for (payload, path) in cases {
+            let message = format!(r#"{{"type": "request", "channel": "place-order", "payload": {}}}"#, payload);
+            match message_error(ORDER_POLICY, &message) {
+                Some(crate::validator::MessageError::SchemaViolation(err)) => assert_eq!(err.path, path),
+                other => panic!("Expected schema violation at {}, got {:?}", path, other),
+            }
+        }
+    }
+
+    const SHARED_TYPES_POLICY: &str = "
+        alias OrderId = uuid
+        alias Note = string?
+        type Address {
+            street: string
+            zip: string?
+        }
+        type Category {
+            name: string
+            children: list<Category>
+        }
+        request ship-order {
+            id: OrderId
+            to: Address
+            category: Category
+            note: Note
+        }
+    ";
+
+    #[test]
+    fn test_validate_named_types() {
+        let valid = r#"{"type": "request", "channel": "ship-order", "payload": {
+            "id": "e1375bc9-0708-4eb9-b3d6-2c46398d2da9",
+            "to": {"street": "Rua A"},
+            "category": {"name": "books", "children": [{"name": "poetry", "children": []}]}
+        }}"#;
+        assert!(message_error(SHARED_TYPES_POLICY, valid).is_none());
+        let cases = [
+            (r#"{"id": "1", "to": {"street": "Rua A"}, "category": {"name": "a", "children": []}}"#, "/id"),
+            (r#"{"id": "e1375bc9-0708-4eb9-b3d6-2c46398d2da9", "to": {}, "category": {"name": "a", "children": []}}"#, "/to/street"),
+            (
+                r#"{"id": "e1375bc9-0708-4eb9-b3d6-2c46398d2da9", "to": {"street": "Rua A"},
+                    "category": {"name": "a", "children": [{"name": 1, "children": []}]}}"#,
+                "/category/children/0/name",
+            ),
+        ];
+        for (payload, path) in cases {
+            let message = format!(r#"{{"type": "request", "channel": "ship-order", "payload": {}}}"#, payload);
+            match message_error(SHARED_TYPES_POLICY, &message) {
+                Some(crate::validator::MessageError::SchemaViolation(err)) => assert_eq!(err.path, path),
+                other => panic!("Expected schema violation at {}, got {:?}", path, other),
+            }
+        }
+    }
+
+    const ORDER_STATUS_POLICY: &str = "
+        enum OrderStatus { pending paid shipped }
+        union Payment {
+            card: { last4: string }
+            pix: { key: string }
+        }
+        broadcast order-updated {
+            status: OrderStatus
+            payment: Payment
+        }
+    ";
+
+    #[test]
+    fn test_validate_enum_and_union() {
+        let valid = r#"{"type": "broadcast", "channel": "order-updated", "payload": {
+            "status": "paid", "payment": {"pix": {"key": "help@abstra.app"}}
+        }}"#;
+        assert!(message_error(ORDER_STATUS_POLICY, valid).is_none());
+        let cases = [
+            (r#"{"status": "lost", "payment": {"pix": {"key": "k"}}}"#, "/status", "expected one of pending, paid, shipped"),
+            (r#"{"status": "paid", "payment": {"boleto": {}}}"#, "/payment/boleto", "expected one of card, pix"),
+            (
+                r#"{"status": "paid", "payment": {"pix": {"key": "k"}, "card": {"last4": "1234"}}}"#,
+                "/payment",
+                "expected an object with exactly one of card, pix",
+            ),
+            (r#"{"status": "paid", "payment": {"card": {"last4": 1234}}}"#, "/payment/card/last4", "expected string"),
+        ];
+        for (payload, path, reason) in cases {
+            let message = format!(r#"{{"type": "broadcast", "channel": "order-updated", "payload": {}}}"#, payload);
+            match message_error(ORDER_STATUS_POLICY, &message) {
+                Some(crate::validator::MessageError::SchemaViolation(err)) => {
+                    assert_eq!((err.path.as_str(), err.reason.as_str()), (path, reason))
+                }
+                other => panic!("Expected schema violation at {}, got {:?}", path, other),
+            }
+        }
+    }
+
+    const CONSTRAINTS_POLICY: &str = "
+        alias Sku = string /^[A-Z0-9-]+$/
+        request place-order {
+            name: string(1..120)
+            qty: int(>=1)
+            skus: list<Sku>(1..3)
+            price: decimal(>0, <=1000)
+            code: string(4)?
+        }
+    ";
+
+    #[test]
+    fn test_validate_constraints() {
+        let valid = r#"{"type": "request", "channel": "place-order", "payload": {
+            "name": "Ana", "qty": 1, "skus": ["A-1"], "price": "999.99", "code": null
+        }}"#;
+        assert!(message_error(CONSTRAINTS_POLICY, valid).is_none());
+        let cases = [
+            (r#""name": """#, "/name", "expected between 1 and 120 characters"),
+            (r#""qty": 0"#, "/qty", "expected at least 1"),
+            (r#""skus": []"#, "/skus", "expected between 1 and 3 items"),
+            (r#""skus": ["a-1"]"#, "/skus/0", "expected to match /^[A-Z0-9-]+$/"),
+            (r#""price": "0""#, "/price", "expected more than 0 and at most 1000"),
+            (r#""code": "12345""#, "/code", "expected exactly 4 characters"),
+        ];
+        for (field, path, reason) in cases {
+            let mut payload: serde_json::Value = serde_json::from_str(
+                r#"{"name": "Ana", "qty": 1, "skus": ["A-1"], "price": 10}"#,
+            ).unwrap();
+            let field: serde_json::Value = serde_json::from_str(&format!("{{{}}}", field)).unwrap();
+            for (key, value) in field.as_object().unwrap() {
+                payload[key] = value.clone();
+            }
+            let message = format!(r#"{{"type": "request", "channel": "place-order", "payload": {}}}"#, payload);
+            match message_error(CONSTRAINTS_POLICY, &message) {
+                Some(crate::validator::MessageError::SchemaViolation(err)) => {
+                    assert_eq!((err.path.as_str(), err.reason.as_str()), (path, reason))
+                }
+                other => panic!("Expected schema violation at {}, got {:?}", path, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate_ranges_exactly() {
+        let policy = "
+            request transfer {
+                id: int64(<=9007199254740992)
+                amount: decimal(<=0.30000000000000000001)
+            }
+        ";
+        let message = |id: &str, amount: &str| {
+            format!(r#"{{"type": "request", "channel": "transfer", "payload": {{"id": {}, "amount": "{}"}}}}"#, id, amount)
+        };
+        assert!(message_error(policy, &message("9007199254740992", "0.30000000000000000001")).is_none());
+        let cases = [
+            (message("9007199254740993", "0.3"), "/id", "expected at most 9007199254740992"),
+            (message("1", "0.30000000000000000002"), "/amount", "expected at most 0.30000000000000000001"),
+        ];
+        for (message, path, reason) in cases {
+            match message_error(policy, &message) {
+                Some(crate::validator::MessageError::SchemaViolation(err)) => {
+                    assert_eq!((err.path.as_str(), err.reason.as_str()), (path, reason))
+                }
+                other => panic!("Expected schema violation at {}, got {:?}", path, other),
+            }
+        }
+    }
+}